@@ -1,4 +1,5 @@
 use crate::errors::Result;
+use failure::format_err;
 use std::time::SystemTime;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
@@ -7,100 +8,385 @@ use serde::{Deserialize, Serialize};
 use crate::transaction::Transaction;
 use merkle_cbt::merkle_tree::{Merge, CBMT};
 
-const TARGET_HEXT: usize = 4;
+/// Compact encoding of the easiest target allowed, equivalent to the old
+/// requirement of four leading zero hex characters in the block hash
+pub const POW_LIMIT_BITS: u32 = 0x1f00ffff;
 
+/// Version of the blocks this node mines
+pub const BLOCK_VERSION: u32 = 1;
+
+/// BlockHeader is the part of a block covered by its proof of work
+///
+/// The transactions are committed through the merkle root, so a header chain
+/// can be checked before any block body is downloaded. The hash and the
+/// height are kept alongside but are not hashed.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BlockHeader {
+  pub version: u32,
+  pub prev_block_hash: String,
+  pub merkle_root: Vec<u8>,
+  pub timestamp: u128,
+  pub bits: u32,
+  pub nonce: u32,
+  pub hash: String,
+  pub height: i32,
+}
+
+impl BlockHeader {
+  /// CalculateHash hashes the header fields, ignoring the stored hash
+  pub fn calculate_hash(&self) -> Result<String> {
+    let content = (
+      self.version,
+      self.prev_block_hash.clone(),
+      self.merkle_root.clone(),
+      self.timestamp,
+      self.bits,
+      self.nonce
+    );
+    let data = bincode::serialize(&content)?;
+    let mut hasher = Sha256::new();
+    hasher.input(&data[..]);
+    Ok(hasher.result_str())
+  }
+
+  /// GetWork returns the expected number of hashes needed to mine this header
+  pub fn get_work(&self) -> Result<u128> {
+    block_work(self.bits)
+  }
+}
+
+/// MerkleProof shows that a transaction is committed to by the merkle root
+/// of a block, without the rest of the block
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MerkleProof {
+  pub txid: String,
+  pub block_hash: String,
+  /// number of transactions in the block, committed to by the merkle root
+  pub tx_count: u32,
+  /// index of the transaction's leaf among all the nodes of the tree
+  pub index: u32,
+  /// hashes of the nodes next to the path from the leaf to the root
+  pub lemmas: Vec<Vec<u8>>,
+}
+
+impl MerkleProof {
+  /// Verify checks the proof against the header of the block it names
+  ///
+  /// The header must hash to the proof's block hash and the path from the
+  /// transaction must lead to the header's merkle root. The root commits to
+  /// the transaction count, so the index must point at one of the leaves of
+  /// a tree of that size. Whether the header belongs to the best chain is up
+  /// to the caller.
+  pub fn verify(&self, header: &BlockHeader) -> Result<bool> {
+    if header.hash != self.block_hash || header.calculate_hash()? != header.hash {
+      return Ok(false);
+    }
+    // the leaves of a tree of n transactions are nodes n-1..=2n-2
+    let leaves = self.tx_count as u64;
+    let index = self.index as u64;
+    if leaves == 0 || index < leaves - 1 || index > 2 * leaves - 2 {
+      return Ok(false);
+    }
+    let proof = merkle_cbt::merkle_tree::MerkleProof::<Vec<u8>, MergeTX>::new(
+      vec![self.index],
+      self.lemmas.clone(),
+    );
+    Ok(match proof.root(&[leaf_hash(self.txid.as_bytes())]) {
+      Some(root) => commit_count(self.tx_count, &root) == header.merkle_root,
+      None => false,
+    })
+  }
+}
+
+/// Block is a header and the transactions, its body, committed to by the
+/// header's merkle root
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
-  timestamp: u128,
+  header: BlockHeader,
   transactions: Vec<Transaction>,
-  prev_block_hash: String,
-  hash: String,
-  height: i32,
-  nonce: u32,
 }
 
 
 
 impl Block {
+  /// FromParts joins a header and a body stored apart
+  pub fn from_parts(header: BlockHeader, transactions: Vec<Transaction>) -> Block {
+    Block { header, transactions }
+  }
+
   pub fn get_transaction(&self) -> &Vec<Transaction> {
     &self.transactions
   }
 
   pub fn get_height(&self) -> i32 {
-      self.height
+      self.header.height
   }
   
   pub(crate) fn get_prev_hash(&self) -> String {
-    self.prev_block_hash.clone()
+    self.header.prev_block_hash.clone()
+  }
+
+  pub fn get_hash(&self) -> String { self.header.hash.clone() }
+
+  /// GetWork returns the expected number of hashes needed to mine this block
+  pub fn get_work(&self) -> Result<u128> {
+    self.header.get_work()
   }
 
-  pub fn get_hash(&self) -> String { self.hash.clone() }
+  /// GetHeader returns the header of the block
+  pub fn get_header(&self) -> &BlockHeader {
+    &self.header
+  }
+
+  /// NewGenesisBlock rebuilds the first block of a chain from its fixed fields
+  ///
+  /// Every node builds the same block from the same inputs, so the result is
+  /// only accepted if it hashes to the `hash` the network expects and the hash
+  /// satisfies `bits`.
+  pub fn new_genesis_block(
+    coinbase: Transaction,
+    timestamp: u128,
+    bits: u32,
+    nonce: u32,
+    hash: &str,
+  ) -> Result<Block> {
+    let mut block = Block::unmined(vec![coinbase], String::new(), 0, timestamp, bits)?;
+    block.header.nonce = nonce;
+    block.header.hash = block.calculate_hash()?;
+    if block.header.hash != hash || !block.validate()? {
+      return Err(format_err!(
+        "genesis block hashes to {} with nonce {}, expected {}",
+        block.header.hash, nonce, hash
+      ));
+    }
+    Ok(block)
+  }
+
+  /// MineGenesisBlock mines the first block of a new network at `timestamp`
+  pub fn mine_genesis_block(coinbase: Transaction, timestamp: u128, bits: u32) -> Result<Block> {
+    let mut block = Block::unmined(vec![coinbase], String::new(), 0, timestamp, bits)?;
+    block.run_proof_of_work()?;
+    Ok(block)
+  }
+
+  pub fn get_timestamp(&self) -> u128 {
+    self.header.timestamp
+  }
 
-  /// newGenesisBlock
-  pub fn new_genesis_block(coinbase: Transaction) -> Block {
-    Block::new_block(vec![coinbase], String::new(), 0).unwrap()
+  pub fn get_nonce(&self) -> u32 {
+    self.header.nonce
   }
 
-  pub fn new_block(data: Vec<Transaction>, prev_block_hash: String, height: i32) -> Result<Block> {
+  pub fn new_block(
+    data: Vec<Transaction>,
+    prev_block_hash: String,
+    height: i32,
+    bits: u32,
+  ) -> Result<Block> {
     let timestamp = SystemTime::now()
      .duration_since(SystemTime::UNIX_EPOCH)?
      .as_millis();
-    let mut block = Block {
-      timestamp: timestamp,
-      transactions: data,
+    let mut block = Block::unmined(data, prev_block_hash, height, timestamp, bits)?;
+    block.run_proof_of_work()?;
+    Ok(block)
+  }
+
+  /// Unmined builds a block committing to `transactions` with a zero nonce
+  /// and no hash yet
+  fn unmined(
+    transactions: Vec<Transaction>,
+    prev_block_hash: String,
+    height: i32,
+    timestamp: u128,
+    bits: u32,
+  ) -> Result<Block> {
+    let header = BlockHeader {
+      version: BLOCK_VERSION,
       prev_block_hash,
+      merkle_root: merkle_root(&transactions)?,
+      timestamp,
+      bits,
+      nonce: 0,
       hash: String::new(),
       height,
-      nonce: 0,
     };
-    block.run_proof_of_work()?;
-    Ok(block)
+    Ok(Block { header, transactions })
   }
 
   fn run_proof_of_work(&mut self) -> Result<()> {
     info!("Mining the block");
     while !self.validate()? {
-      self.nonce += 1;
+      self.header.nonce += 1;
     }
-    let data = self.prepare_hash_data()?;
-    let mut hasher = Sha256::new();
-    hasher.input(&data[..]);
-    self.hash = hasher.result_str();
+    self.header.hash = self.calculate_hash()?;
     Ok(())
   }
 
-  fn validate(&self) -> Result<bool> {
-    let data = self.prepare_hash_data()?;
-    let mut hasher = Sha256::new();
-    hasher.input(&data[..]);
-    let mut vec1: Vec<u8> = Vec::new();
-    vec1.resize(TARGET_HEXT, '0' as u8);
-    // println!("{:?}", vec1);
-    Ok(hasher.result_str()[0..TARGET_HEXT] == String::from_utf8(vec1)?)
+  /// Validate checks whether the block hash satisfies the block's own target
+  pub fn validate(&self) -> Result<bool> {
+    hash_meets_target(&self.calculate_hash()?, self.header.bits)
+  }
+
+  /// CalculateHash hashes the block header fields, ignoring the stored hash
+  pub fn calculate_hash(&self) -> Result<String> {
+    self.header.calculate_hash()
+  }
+
+  /// HashTransactions returns the merkle root of the transactions in the
+  /// block, which matches the header's for a well formed block
+  pub fn hash_transactions(&self) -> Result<Vec<u8>> {
+    merkle_root(&self.transactions)
+  }
+
+  /// MerkleProof proves that the transaction `txid` is in the block, or
+  /// returns None when it is not
+  pub fn merkle_proof(&self, txid: &str) -> Result<Option<MerkleProof>> {
+    let position = match self.transactions.iter().position(|tx| tx.id == txid) {
+      Some(position) => position as u32,
+      None => return Ok(None),
+    };
+    let proof = CBMT::<Vec<u8>, MergeTX>::build_merkle_proof(&merkle_leaves(&self.transactions)?, &[position])
+      .ok_or_else(|| format_err!("no merkle proof for transaction {} of block {}", txid, self.get_hash()))?;
+    Ok(Some(MerkleProof {
+      txid: txid.to_string(),
+      block_hash: self.get_hash(),
+      tx_count: self.transactions.len() as u32,
+      index: proof.indices()[0],
+      lemmas: proof.lemmas().to_vec(),
+    }))
   }
+}
+
+/// Prefixes keeping the hashes of leaves, inner nodes and the committed root
+/// apart, so that no node can pass for another kind
+const MERKLE_LEAF: u8 = 0;
+const MERKLE_NODE: u8 = 1;
+const MERKLE_ROOT: u8 = 2;
+
+/// MerkleRoot returns the root of the merkle tree over the transaction hashes,
+/// committing to the number of transactions
+fn merkle_root(transactions: &[Transaction]) -> Result<Vec<u8>> {
+    let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&merkle_leaves(transactions)?);
+
+    Ok(commit_count(transactions.len() as u32, &tree.root()))
+}
+
+/// MerkleLeaves returns the leaves of the merkle tree, the hashes of the hex
+/// encoded transaction hashes
+fn merkle_leaves(transactions: &[Transaction]) -> Result<Vec<Vec<u8>>> {
+    let mut hashes = Vec::new();
+    for tx in transactions {
+        hashes.push(leaf_hash(tx.hash()?.as_bytes()));
+    }
+    Ok(hashes)
+}
+
+fn leaf_hash(txid: &[u8]) -> Vec<u8> {
+    tagged_hash(MERKLE_LEAF, &[txid])
+}
+
+/// CommitCount binds the transaction count to the root of the tree
+fn commit_count(tx_count: u32, tree_root: &[u8]) -> Vec<u8> {
+    tagged_hash(MERKLE_ROOT, &[&tx_count.to_be_bytes(), tree_root])
+}
 
-  /// HashTransactions returns a hash of the transactions in the block
-  fn hash_transactions(&self) -> Result<Vec<u8>> {
-      let mut transactions = Vec::new();
-      for tx in &self.transactions {
-          transactions.push(tx.hash()?.as_bytes().to_owned());
+fn tagged_hash(tag: u8, parts: &[&[u8]]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(&[tag]);
+    for part in parts {
+        hasher.input(part);
+    }
+    let mut re: [u8; 32] = [0; 32];
+    hasher.result(&mut re);
+    re.to_vec()
+}
+
+/// CompactToTarget expands a compact `bits` value into a big-endian 256-bit target
+pub fn compact_to_target(bits: u32) -> Result<[u8; 32]> {
+  let exponent = (bits >> 24) as usize;
+  let mantissa = bits & 0x007fffff;
+  let mut target = [0u8; 32];
+  if exponent > 32 {
+    return Err(format_err!("Compact target {:#010x} overflows 256 bits", bits));
+  }
+  if exponent <= 3 {
+    let value = mantissa >> (8 * (3 - exponent));
+    target[29..].copy_from_slice(&value.to_be_bytes()[1..]);
+  } else {
+    let start = 32 - exponent;
+    for (i, b) in mantissa.to_be_bytes()[1..].iter().enumerate() {
+      if start + i < 32 {
+        target[start + i] = *b;
       }
-      let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&transactions);
+    }
+  }
+  Ok(target)
+}
 
-      Ok(tree.root())
+/// TargetToCompact packs a big-endian 256-bit target into its compact form
+pub fn target_to_compact(target: &[u8; 32]) -> u32 {
+  let first = match target.iter().position(|b| *b != 0) {
+    Some(i) => i,
+    None => return 0,
+  };
+  let mut size = (32 - first) as u32;
+  let mut mantissa: u32 = 0;
+  for i in 0..3 {
+    mantissa <<= 8;
+    if first + i < 32 {
+      mantissa |= target[first + i] as u32;
+    }
   }
+  if mantissa & 0x00800000 != 0 {
+    mantissa >>= 8;
+    size += 1;
+  }
+  (size << 24) | mantissa
+}
 
-  fn prepare_hash_data(&self) -> Result<Vec<u8>> {
-    let content = (
-      self.prev_block_hash.clone(),
-      self.hash_transactions()?,
-      self.timestamp,
-      TARGET_HEXT,
-      self.nonce
-    );
-    let bytes = bincode::serialize(&content)?;
-    Ok(bytes)
+/// BlockWork approximates 2^256 / target, the expected hashes to meet `bits`
+///
+/// Only the top 64 significant bits of the target are used, which is plenty to
+/// order chains by work while staying in u128 arithmetic.
+pub fn block_work(bits: u32) -> Result<u128> {
+  let target = compact_to_target(bits)?;
+  let zeros = match target.iter().position(|b| *b != 0) {
+    Some(i) => i as u32 * 8 + target[i].leading_zeros(),
+    None => return Err(format_err!("Compact target {:#010x} is zero", bits)),
+  };
+  // top 64 bits of the target, starting at its most significant set bit
+  let mut top: u64 = 0;
+  for bit in zeros..zeros + 64 {
+    top <<= 1;
+    if bit < 256 && target[(bit / 8) as usize] & (0x80 >> (bit % 8)) != 0 {
+      top |= 1;
+    }
+  }
+  // work = 2^(64 + zeros) / top = (2^127 / top) * 2^(zeros - 63)
+  let base = (1u128 << 127) / top as u128;
+  if zeros >= 63 {
+    Ok(base.checked_shl(zeros - 63).unwrap_or(u128::MAX))
+  } else {
+    Ok(base >> (63 - zeros))
+  }
+}
+
+/// HashMeetsTarget checks that a hex encoded hash is not above the compact target
+pub fn hash_meets_target(hash: &str, bits: u32) -> Result<bool> {
+  let target = compact_to_target(bits)?;
+  Ok(decode_hash(hash)? <= target)
+}
+
+/// DecodeHash turns a hex encoded sha256 hash into its raw bytes
+pub fn decode_hash(hash: &str) -> Result<[u8; 32]> {
+  if hash.len() != 64 {
+    return Err(format_err!("Invalid hash length: {}", hash.len()));
   }
+  let mut bytes = [0u8; 32];
+  for (i, b) in bytes.iter_mut().enumerate() {
+    *b = u8::from_str_radix(&hash[2 * i..2 * i + 2], 16)?;
+  }
+  Ok(bytes)
 }
 
 struct MergeTX {}
@@ -108,26 +394,95 @@ struct MergeTX {}
 impl Merge for MergeTX {
   type Item = Vec<u8>;
   fn merge(left: &Self::Item, right: &Self::Item) -> Self::Item {
-      let mut hasher = Sha256::new();
-      let mut data: Vec<u8> = left.clone();
-      data.append(&mut right.clone());
-      hasher.input(&data);
-      let mut re: [u8; 32] = [0; 32];
-      hasher.result(&mut re);
-      re.to_vec()
-  }
-}
-
-// #[cfg(test)]
-// mod tests {
-//   use super::*;
-
-//   #[test]
-//   fn test_blockchain() {
-//     let mut b = Blockchain::new();
-//     b.add_block("data".to_String());
-//     b.add_block("data2".to_String());
-//     b.add_block("data3".to_String());
-//     dbg!(b);
-//   }
-// }
\ No newline at end of file
+      tagged_hash(MERKLE_NODE, &[left, right])
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_compact_roundtrip() {
+    let target = compact_to_target(POW_LIMIT_BITS).unwrap();
+    assert_eq!(&target[..4], &[0, 0, 0xff, 0xff]);
+    assert!(target[4..].iter().all(|b| *b == 0));
+    assert_eq!(target_to_compact(&target), POW_LIMIT_BITS);
+
+    let small = compact_to_target(0x03123456).unwrap();
+    assert_eq!(&small[29..], &[0x12, 0x34, 0x56]);
+    assert_eq!(target_to_compact(&small), 0x03123456);
+  }
+
+  #[test]
+  fn test_block_work() {
+    assert_eq!(block_work(POW_LIMIT_BITS).unwrap(), 65537);
+    // halving the target doubles the work
+    assert_eq!(block_work(0x1e7fff80).unwrap(), 131074);
+  }
+
+  #[test]
+  fn test_merkle_proof() {
+    let miner = crate::wallets::Wallet::new().get_address();
+    let txs: Vec<Transaction> = (0..5)
+      .map(|i| Transaction::new_coinbase(miner.clone(), format!("tx {}", i), 1, 50).unwrap())
+      .collect();
+    let mut block = Block::unmined(txs.clone(), String::new(), 1, 0, POW_LIMIT_BITS).unwrap();
+    block.header.hash = block.calculate_hash().unwrap();
+
+    for tx in &txs {
+      let proof = block.merkle_proof(&tx.id).unwrap().unwrap();
+      assert!(proof.verify(block.get_header()).unwrap());
+    }
+    assert!(block.merkle_proof("missing").unwrap().is_none());
+
+    let proof = block.merkle_proof(&txs[3].id).unwrap().unwrap();
+    let mut forged = proof.clone();
+    forged.txid = txs[2].id.clone();
+    assert!(!forged.verify(block.get_header()).unwrap());
+    let mut forged = proof.clone();
+    forged.lemmas[0][0] ^= 1;
+    assert!(!forged.verify(block.get_header()).unwrap());
+    // a header whose fields were changed no longer hashes to the proof's block
+    let mut header = block.get_header().clone();
+    header.merkle_root = forged.lemmas[0].clone();
+    assert!(!proof.verify(&header).unwrap());
+
+    // the count is committed to by the root
+    let mut forged = proof.clone();
+    forged.tx_count = 4;
+    assert!(!forged.verify(block.get_header()).unwrap());
+    // indices below the leaves name inner nodes, and the path from one of
+    // those is the leaf's path less its first lemma
+    let mut forged = proof.clone();
+    forged.index = (proof.index - 1) / 2;
+    forged.lemmas.remove(0);
+    assert!(forged.index < proof.tx_count - 1);
+    assert!(!forged.verify(block.get_header()).unwrap());
+    forged.index = proof.tx_count;
+    assert!(!forged.verify(block.get_header()).unwrap());
+    let mut forged = proof.clone();
+    forged.lemmas.push(proof.lemmas[0].clone());
+    assert!(!forged.verify(block.get_header()).unwrap());
+    // an inner node can't stand in for a transaction
+    let inner = block.merkle_proof(&txs[4].id).unwrap().unwrap();
+    let mut forged = proof.clone();
+    forged.txid = String::from_utf8_lossy(&inner.lemmas[1]).into_owned();
+    forged.lemmas = inner.lemmas[2..].to_vec();
+    assert!(!forged.verify(block.get_header()).unwrap());
+
+    let mut single = Block::unmined(txs[..1].to_vec(), String::new(), 1, 0, POW_LIMIT_BITS).unwrap();
+    single.header.hash = single.calculate_hash().unwrap();
+    let proof = single.merkle_proof(&txs[0].id).unwrap().unwrap();
+    assert!(proof.lemmas.is_empty());
+    assert!(proof.verify(single.get_header()).unwrap());
+  }
+
+  #[test]
+  fn test_hash_meets_target() {
+    let easy = format!("0000ffff{}", "0".repeat(56));
+    let hard = format!("00010000{}", "0".repeat(56));
+    assert!(hash_meets_target(&easy, POW_LIMIT_BITS).unwrap());
+    assert!(!hash_meets_target(&hard, POW_LIMIT_BITS).unwrap());
+  }
+}
@@ -1,25 +1,122 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use bincode::{deserialize, serialize};
 use failure::format_err;
-use log::info;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
 
-use crate::block::Block;
+use crate::config::GenesisParams;
+use crate::block::{compact_to_target, target_to_compact, Block, BlockHeader, MerkleProof, POW_LIMIT_BITS};
 use crate::errors::Result;
-use crate::transaction::Transaction;
-use crate::tx::{TXOutput, TXOutputs};
+use crate::transaction::{SubsidySchedule, Transaction, DEFAULT_SUBSIDY};
+use crate::tx::OutPoint;
+use crate::utxoset::{BlockUndo, UTXOEntry};
+use crate::validation::{check_header, check_maturity, validate_block};
 // use sled;
 
-const GENESIS_COINBASE_DATA: &str =
+pub const GENESIS_COINBASE_DATA: &str =
     "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
 
-const TARGET_HEXT: usize = 4;
+/// Number of blocks between two difficulty adjustments
+pub const RETARGET_INTERVAL: i32 = 10;
+/// Block interval the retarget rule aims for, in milliseconds
+pub const TARGET_BLOCK_TIME: u128 = 10_000;
+/// Depth a coinbase output needs before it can be spent, used by chains created without one
+pub const DEFAULT_COINBASE_MATURITY: i32 = 10;
+/// Number of most recent blocks listed one by one in a block locator
+const LOCATOR_DENSE_SPAN: usize = 10;
 
 
 #[derive(Debug)]
 pub struct Blockchain {
   current_hash: String,
-  db: sled::Db
+  /// height of the block at current_hash
+  best_height: i32,
+  db: sled::Db,
+  /// directory the chain is stored in, shared with the UTXO set
+  data_dir: PathBuf,
+  subsidy: SubsidySchedule,
+  coinbase_maturity: i32,
+  /// easiest target blocks may be mined against
+  pow_limit_bits: u32,
+  /// whether the target adjusts every RETARGET_INTERVAL blocks
+  retarget: bool,
+  /// whether the txid and address indexes are kept, see rebuild_tx_index
+  tx_index: bool,
+}
+
+/// HeightRangeIter walks the active chain by height, forward from its start
+/// or backward from its end, yielding blocks or only their headers
+///
+/// A block that cannot be read is yielded as an error, which ends the walk.
+pub struct HeightRangeIter<'a, T> {
+  bc: &'a Blockchain,
+  fetch: fn(&Blockchain, i32) -> Result<T>,
+  /// next height yielded from the front
+  front: i32,
+  /// height after the last one yielded from the back
+  back: i32,
+}
+
+/// TxLocation is where a transaction of the active chain is stored
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TxLocation {
+  pub block_hash: String,
+  pub height: i32,
+  /// position of the transaction in its block
+  pub position: u32,
+}
+
+/// BlockIndex is the per-block metadata kept for every stored block, on any branch
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockIndex {
+  pub hash: String,
+  pub prev_hash: String,
+  pub height: i32,
+  /// total work of the chain ending at this block
+  pub chain_work: u128,
+}
+
+/// HeaderIndex is a validated header, with or without its block body
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HeaderIndex {
+  pub header: BlockHeader,
+  /// total work of the header chain ending at this header
+  pub chain_work: u128,
+}
+
+/// ChainUpdate describes how the active chain changed after accepting a block
+#[derive(Debug, Default)]
+pub struct ChainUpdate {
+  /// blocks removed from the active chain, old tip first
+  pub disconnected: Vec<Block>,
+  /// blocks added to the active chain, oldest first
+  pub connected: Vec<Block>,
+}
+
+impl ChainUpdate {
+  /// IsReorg reports whether any block left the active chain
+  #[cfg(test)]
+  pub fn is_reorg(&self) -> bool {
+    !self.disconnected.is_empty()
+  }
+}
+
+/// ReorgEvent records a switch of the active chain to a branch with more work
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReorgEvent {
+  pub timestamp: u128,
+  pub old_tip: String,
+  pub new_tip: String,
+  pub fork_hash: String,
+  pub fork_height: i32,
+  /// number of blocks disconnected from the old chain
+  pub depth: usize,
+  /// number of blocks connected from the new branch
+  pub connected: usize,
 }
 
 pub struct BlockchainIter<'a> {
@@ -28,38 +125,124 @@ pub struct BlockchainIter<'a> {
 }
 
 impl Blockchain {
-  /// NewBlockchain creates a new Blockchain db
-  pub fn new() -> Result<Blockchain> {
+  /// NewBlockchain opens the Blockchain db kept in `data_dir`
+  ///
+  /// A new db starts with the genesis block of `genesis`, along with the
+  /// subsidy schedule, the coinbase maturity and the difficulty rules, which
+  /// are stored with the chain and apply to every block. An existing db must
+  /// start with that same genesis block.
+  pub fn new(data_dir: &Path, genesis: &GenesisParams) -> Result<Blockchain> {
     info!("open blockchain");
 
-    let db = sled::open("data/blocks")?;
-    let hash = db
-        .get("LAST")?
-        .expect("Must create a new block database first");
-      info!("Found block database");
-      let lasthash = String::from_utf8(hash.to_vec())?;
-      Ok(Blockchain {
-        current_hash: lasthash.clone(),
-        db,
-      })
+    let db = sled::open(data_dir.join("blocks"))?;
+    if db.get("LAST")?.is_none() {
+      info!("Creating new block database");
+      Blockchain::init(&db, genesis)?;
+    }
+    let stored = match db.get("GENESIS")? {
+      Some(hash) => String::from_utf8(hash.to_vec())?,
+      None => String::new(),
+    };
+    if stored != genesis.hash {
+      return Err(format_err!(
+        "block database in {} starts at genesis {:?}, expected {}",
+        data_dir.display(), stored, genesis.hash
+      ));
+    }
 
+    info!("Found block database");
+    let lasthash = String::from_utf8(db.get("LAST")?.unwrap().to_vec())?;
+    let subsidy = match db.get("SUBSIDY")? {
+      Some(data) => deserialize(&data)?,
+      None => DEFAULT_SUBSIDY,
+    };
+    let coinbase_maturity = match db.get("MATURITY")? {
+      Some(data) => deserialize(&data)?,
+      None => DEFAULT_COINBASE_MATURITY,
+    };
+    let pow_limit_bits = match db.get("POWLIMIT")? {
+      Some(data) => deserialize(&data)?,
+      None => POW_LIMIT_BITS,
+    };
+    let retarget = match db.get("RETARGET")? {
+      Some(data) => deserialize(&data)?,
+      None => true,
+    };
+    let tx_index = db.contains_key("TXINDEX")?;
+    let best_height = match db.open_tree("headers")?.get(&lasthash)? {
+      Some(data) => deserialize::<HeaderIndex>(&data)?.header.height,
+      None => return Err(format_err!("Header {} is not found", lasthash)),
+    };
+    let bc = Blockchain {
+      current_hash: lasthash,
+      best_height,
+      db,
+      data_dir: data_dir.to_path_buf(),
+      subsidy,
+      coinbase_maturity,
+      pow_limit_bits,
+      retarget,
+      tx_index,
+    };
+    // chains stored before the height index was kept get it on first open
+    if bc.db.open_tree("heights")?.get(best_height.to_be_bytes())? != Some(bc.current_hash.as_bytes().into()) {
+      bc.rebuild_height_index()?;
+    }
+    if let Some(best) = bc.get_best_header()? {
+      let stored = bc.db.open_tree("headerheights")?.get(best.header.height.to_be_bytes())?;
+      if stored.as_deref() != Some(best.header.hash.as_bytes()) {
+        bc.set_best_header(&best.header)?;
+      }
+    }
+    Ok(bc)
   }
 
-  /// CreateBlockchain creates a new blockchain DB
-  pub fn create_blockchain(address: String) -> Result<Blockchain> {
-    info!("Create new blockchain");
+  /// Init stores the genesis block and the consensus parameters in a new db
+  fn init(db: &sled::Db, genesis: &GenesisParams) -> Result<()> {
+    let block = genesis.block()?;
+    let bc = Blockchain {
+      current_hash: block.get_hash(),
+      best_height: 0,
+      db: db.clone(),
+      data_dir: PathBuf::new(),
+      subsidy: genesis.subsidy(),
+      coinbase_maturity: genesis.coinbase_maturity,
+      pow_limit_bits: genesis.pow_limit_bits,
+      retarget: genesis.retarget,
+      tx_index: false,
+    };
+    bc.store_block(&block, 0)?;
+    db.insert("SUBSIDY", serialize(&bc.subsidy)?)?;
+    db.insert("MATURITY", serialize(&bc.coinbase_maturity)?)?;
+    db.insert("POWLIMIT", serialize(&bc.pow_limit_bits)?)?;
+    db.insert("RETARGET", serialize(&bc.retarget)?)?;
+    db.insert("GENESIS", block.get_hash().as_bytes())?;
+    db.open_tree("heights")?.insert(0i32.to_be_bytes(), block.get_hash().as_bytes())?;
+    db.insert("LAST", block.get_hash().as_bytes())?;
+    db.flush()?;
+    Ok(())
+  }
 
-    let db = sled::open("data/blocks")?;
-    info!("Creating new block databse");
-    let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
-    let genesis: Block = Block::new_genesis_block(cbtx);
-    db.insert(genesis.get_hash(), bincode::serialize(&genesis)?)?;
-    db.insert("LAST", genesis.get_hash().as_bytes())?;
+  /// OpenTemporary creates an in-memory blockchain starting at `genesis`
+  ///
+  /// Coinbase outputs are spendable right away unless a test asks otherwise.
+  #[cfg(test)]
+  pub(crate) fn open_temporary(genesis: &Block) -> Result<Blockchain> {
+    let db = sled::Config::new().temporary(true).open()?;
     let bc = Blockchain {
       current_hash: genesis.get_hash(),
+      best_height: 0,
       db,
+      data_dir: PathBuf::new(),
+      subsidy: DEFAULT_SUBSIDY,
+      coinbase_maturity: 0,
+      pow_limit_bits: POW_LIMIT_BITS,
+      retarget: true,
+      tx_index: false,
     };
-    bc.db.flush()?;
+    bc.store_block(genesis, 0)?;
+    bc.db.open_tree("heights")?.insert(0i32.to_be_bytes(), genesis.get_hash().as_bytes())?;
+    bc.db.insert("LAST", genesis.get_hash().as_bytes())?;
     Ok(bc)
   }
 
@@ -72,45 +255,482 @@ impl Blockchain {
               return Err(format_err!("ERROR: Invalid transaction"));
           }
       }
-
-      let lasthash = self.db.get("LAST")?.unwrap();
+      let lastblock = self.get_block(&self.current_hash)?;
 
       let newblock = Block::new_block(
           transactions,
-          String::from_utf8(lasthash.to_vec())?,
-          self.get_best_height()? + 1,
+          lastblock.get_hash(),
+          lastblock.get_height() + 1,
+          self.get_next_bits(lastblock.get_header())?,
       )?;
-      self.db.insert(newblock.get_hash(), serialize(&newblock)?)?;
-      self.db.insert("LAST", newblock.get_hash().as_bytes())?;
+      validate_block(self, &newblock)?;
+      let parent = self.get_block_index(&lastblock.get_hash())?;
+      self.store_block(&newblock, parent.chain_work)?;
+      let update = ChainUpdate {
+          disconnected: Vec::new(),
+          connected: vec![newblock],
+      };
+      self.set_active_chain(&update)?;
+      Ok(update.connected.into_iter().next().unwrap())
+  }
+
+  /// AddBlock validates and saves the block into the blockchain
+  ///
+  /// The block may extend the active chain, start or extend a side branch, or
+  /// carry a side branch past the active chain's cumulative work, in which case
+  /// the chain is reorganized onto it. The returned ChainUpdate lists the blocks
+  /// that left and joined the active chain so callers can fix up derived state.
+  pub fn add_block(&mut self, block: Block) -> Result<ChainUpdate> {
+      if self.has_block(&block.get_hash())? {
+          return Ok(ChainUpdate::default());
+      }
+      validate_block(self, &block)?;
+
+      let parent = self.get_block_index(&block.get_prev_hash())?;
+      let index = self.store_block(&block, parent.chain_work)?;
+      let tip = self.get_block_index(&self.current_hash)?;
+
+      if index.chain_work <= tip.chain_work {
+          info!(
+              "Block {} at height {} is on a side chain",
+              index.hash, index.height
+          );
+          self.db.open_tree("sidetips")?.remove(&index.prev_hash)?;
+          self.db.open_tree("sidetips")?.insert(&index.hash, serialize(&index)?)?;
+          self.db.flush()?;
+          return Ok(ChainUpdate::default());
+      }
+
+      let update = if index.prev_hash == tip.hash {
+          ChainUpdate {
+              disconnected: Vec::new(),
+              connected: vec![block],
+          }
+      } else {
+          self.reorganize(&tip, &index)?
+      };
+      self.set_active_chain(&update)?;
+      Ok(update)
+  }
+
+  /// SetActiveChain applies an update to the active chain: the height index,
+  /// the transaction indexes when kept, and the tip all move to its last
+  /// connected block
+  fn set_active_chain(&mut self, update: &ChainUpdate) -> Result<()> {
+      let tip = match update.connected.last() {
+          Some(tip) => tip,
+          None => return Ok(()),
+      };
+      let heights = self.db.open_tree("heights")?;
+      for block in &update.disconnected {
+          heights.remove(block.get_height().to_be_bytes())?;
+          if self.tx_index {
+              self.unindex_block(block)?;
+          }
+      }
+      for block in &update.connected {
+          heights.insert(block.get_height().to_be_bytes(), block.get_hash().as_bytes())?;
+          if self.tx_index {
+              self.index_block(block)?;
+          }
+      }
+
+      self.db.insert("LAST", tip.get_hash().as_bytes())?;
       self.db.flush()?;
+      self.current_hash = tip.get_hash();
+      self.best_height = tip.get_height();
+      Ok(())
+  }
 
-      self.current_hash = newblock.get_hash();
-      Ok(newblock)
+  /// RebuildHeightIndex maps every height of the active chain to its block,
+  /// walking back from the tip
+  fn rebuild_height_index(&self) -> Result<()> {
+      info!("Rebuilding the height index");
+      let heights = self.db.open_tree("heights")?;
+      heights.clear()?;
+      for block in self.iter() {
+          let block = block?;
+          heights.insert(block.get_height().to_be_bytes(), block.get_hash().as_bytes())?;
+      }
+      heights.flush()?;
+      Ok(())
   }
 
-  /// AddBlock saves the block into the blockchain
-  pub fn add_block(&mut self, block: Block) -> Result<()> {
-      let data = serialize(&block)?;
-      if let Some(_) = self.db.get(block.get_hash())? {
-          return Ok(());
+  /// Reorganize computes the switch of the active chain from `old_tip` to `new_tip`
+  ///
+  /// The old tip is kept as a side chain tip and the event is recorded so it
+  /// can be reported through get_reorgs.
+  fn reorganize(&self, old_tip: &BlockIndex, new_tip: &BlockIndex) -> Result<ChainUpdate> {
+      let mut old = old_tip.clone();
+      let mut new = new_tip.clone();
+      let mut disconnected = Vec::new();
+      let mut connected = Vec::new();
+
+      while old.height > new.height {
+          disconnected.push(self.get_block(&old.hash)?);
+          old = self.get_block_index(&old.prev_hash)?;
+      }
+      while new.height > old.height {
+          connected.push(self.get_block(&new.hash)?);
+          new = self.get_block_index(&new.prev_hash)?;
       }
-      self.db.insert(block.get_hash(), data)?;
+      while old.hash != new.hash {
+          disconnected.push(self.get_block(&old.hash)?);
+          connected.push(self.get_block(&new.hash)?);
+          old = self.get_block_index(&old.prev_hash)?;
+          new = self.get_block_index(&new.prev_hash)?;
+      }
+      connected.reverse();
 
-      let lastheight = self.get_best_height()?;
-      if block.get_height() > lastheight {
-          self.db.insert("LAST", block.get_hash().as_bytes())?;
-          self.current_hash = block.get_hash();
-          self.db.flush()?;
+      let event = ReorgEvent {
+          timestamp: SystemTime::now()
+              .duration_since(SystemTime::UNIX_EPOCH)?
+              .as_millis(),
+          old_tip: old_tip.hash.clone(),
+          new_tip: new_tip.hash.clone(),
+          fork_hash: old.hash.clone(),
+          fork_height: old.height,
+          depth: disconnected.len(),
+          connected: connected.len(),
+      };
+      warn!(
+          "Chain reorganization at height {}: {} block(s) disconnected, {} connected, new tip {}",
+          event.fork_height, event.depth, event.connected, event.new_tip
+      );
+
+      let sidetips = self.db.open_tree("sidetips")?;
+      sidetips.remove(&new_tip.hash)?;
+      sidetips.remove(&new_tip.prev_hash)?;
+      sidetips.insert(&old_tip.hash, serialize(old_tip)?)?;
+      let reorgs = self.db.open_tree("reorgs")?;
+      reorgs.insert(self.db.generate_id()?.to_be_bytes(), serialize(&event)?)?;
+
+      Ok(ChainUpdate {
+          disconnected,
+          connected,
+      })
+  }
+
+  /// StoreBlock writes a block and its index entry, returning the entry
+  fn store_block(&self, block: &Block, parent_work: u128) -> Result<BlockIndex> {
+      let index = BlockIndex {
+          hash: block.get_hash(),
+          prev_hash: block.get_prev_hash(),
+          height: block.get_height(),
+          chain_work: parent_work.saturating_add(block.get_work()?),
+      };
+      self.db
+          .open_tree("bodies")?
+          .insert(block.get_hash(), serialize(block.get_transaction())?)?;
+      self.db
+          .open_tree("index")?
+          .insert(block.get_hash(), serialize(&index)?)?;
+      self.store_header(&HeaderIndex {
+          header: block.get_header().clone(),
+          chain_work: index.chain_work,
+      })?;
+      Ok(index)
+  }
+
+  fn store_header(&self, index: &HeaderIndex) -> Result<()> {
+      self.db
+          .open_tree("headers")?
+          .insert(&index.header.hash, serialize(index)?)?;
+      let best = self.get_best_header()?;
+      if best.is_none() || index.chain_work > best.unwrap().chain_work {
+          self.set_best_header(&index.header)?;
       }
       Ok(())
   }
 
+  /// SetBestHeader makes `header` the best header and maps the heights of
+  /// its chain to their headers
+  ///
+  /// Only the heights that differ from the previous best header chain are
+  /// written, so following a growing header chain costs one entry per header.
+  fn set_best_header(&self, header: &BlockHeader) -> Result<()> {
+      let heights = self.db.open_tree("headerheights")?;
+      for entry in heights.range((header.height + 1).to_be_bytes()..) {
+          heights.remove(entry?.0)?;
+      }
+      let mut current = header.clone();
+      while heights.get(current.height.to_be_bytes())?.as_deref() != Some(current.hash.as_bytes()) {
+          heights.insert(current.height.to_be_bytes(), current.hash.as_bytes())?;
+          if current.prev_block_hash.is_empty() {
+              break;
+          }
+          current = self.get_header(&current.prev_block_hash)?;
+      }
+      self.db.insert("BESTHEADER", header.hash.as_bytes())?;
+      Ok(())
+  }
+
+  fn find_header_index(&self, hash: &str) -> Result<Option<HeaderIndex>> {
+      match self.db.open_tree("headers")?.get(hash)? {
+          Some(data) => Ok(Some(deserialize(&data)?)),
+          None => Ok(None),
+      }
+  }
+
+  /// GetHeader returns a validated header, whether or not its block is stored
+  pub fn get_header(&self, hash: &str) -> Result<BlockHeader> {
+      match self.find_header_index(hash)? {
+          Some(index) => Ok(index.header),
+          None => Err(format_err!("Header {} is not found", hash)),
+      }
+  }
+
+  /// HasBlock reports whether the body of a block is stored
+  pub fn has_block(&self, hash: &str) -> Result<bool> {
+      Ok(!hash.is_empty() && self.db.open_tree("bodies")?.contains_key(hash)?)
+  }
+
+  /// AddHeader validates a header and adds it to the header tree
+  ///
+  /// Returns false if the header was already known. Consensus failures are
+  /// returned as a BlockError.
+  pub fn add_header(&self, header: &BlockHeader) -> Result<bool> {
+      if self.find_header_index(&header.hash)?.is_some() {
+          return Ok(false);
+      }
+      if self.db.open_tree("invalid")?.contains_key(&header.prev_block_hash)? {
+          self.db.open_tree("invalid")?.insert(&header.hash, vec![])?;
+          return Err(format_err!("Header {} builds on an invalid block", header.hash));
+      }
+      check_header(self, header)?;
+      let parent = self.find_header_index(&header.prev_block_hash)?.unwrap();
+      self.store_header(&HeaderIndex {
+          header: header.clone(),
+          chain_work: parent.chain_work.saturating_add(header.get_work()?),
+      })?;
+      Ok(true)
+  }
+
+  /// InvalidateHeader marks a header whose block failed validation
+  ///
+  /// The stored headers building on it are marked and dropped along with
+  /// it, so headers extending any of them are refused from then on. The
+  /// best header falls back to the active chain's tip.
+  pub fn invalidate_header(&self, hash: &str) -> Result<()> {
+      let headers = self.db.open_tree("headers")?;
+      let mut condemned = vec![hash.to_string()];
+      if let Some(index) = self.find_header_index(hash)? {
+          // lowest first, so every parent is settled before its children
+          let mut above = Vec::new();
+          for item in headers.iter() {
+              let other: HeaderIndex = deserialize(&item?.1)?;
+              if other.header.height > index.header.height {
+                  above.push(other.header);
+              }
+          }
+          above.sort_by_key(|header| header.height);
+          let mut invalid: HashSet<String> = condemned.iter().cloned().collect();
+          for header in above {
+              if invalid.contains(&header.prev_block_hash) {
+                  invalid.insert(header.hash.clone());
+                  condemned.push(header.hash);
+              }
+          }
+      }
+      let invalid = self.db.open_tree("invalid")?;
+      for hash in &condemned {
+          invalid.insert(hash.as_str(), vec![])?;
+          headers.remove(hash.as_str())?;
+      }
+      self.set_best_header(&self.get_header(&self.current_hash)?)
+  }
+
+  /// GetBestHeader returns the header with the most cumulative work
+  pub fn get_best_header(&self) -> Result<Option<HeaderIndex>> {
+      match self.db.get("BESTHEADER")? {
+          Some(hash) => self.find_header_index(&String::from_utf8(hash.to_vec())?),
+          None => Ok(None),
+      }
+  }
+
+  /// GetBestHeaderHash returns the hash of the best header chain's block at
+  /// `height`, or None past its tip
+  pub fn get_best_header_hash(&self, height: i32) -> Result<Option<String>> {
+      match self.db.open_tree("headerheights")?.get(height.to_be_bytes())? {
+          Some(hash) if height >= 0 => Ok(Some(String::from_utf8(hash.to_vec())?)),
+          _ => Ok(None),
+      }
+  }
+
+  /// GetMissingBlocks returns, oldest first, up to `max` blocks of the best
+  /// header chain whose bodies are not stored yet
+  ///
+  /// The best header chain is followed forward from where it leaves the
+  /// active chain, so only the returned blocks are looked at past that point.
+  pub fn get_missing_blocks(&self, max: usize) -> Result<Vec<String>> {
+      let best = match self.get_best_header()? {
+          Some(best) => best.header,
+          None => return Ok(Vec::new()),
+      };
+      let header_heights = self.db.open_tree("headerheights")?;
+      let heights = self.db.open_tree("heights")?;
+      let mut fork = self.best_height.min(best.height);
+      while fork > 0 && header_heights.get(fork.to_be_bytes())? != heights.get(fork.to_be_bytes())? {
+          fork -= 1;
+      }
+
+      let mut missing = Vec::new();
+      for entry in header_heights.range((fork + 1).to_be_bytes()..) {
+          if missing.len() >= max {
+              break;
+          }
+          let hash = String::from_utf8(entry?.1.to_vec())?;
+          if !self.has_block(&hash)? {
+              missing.push(hash);
+          }
+      }
+      Ok(missing)
+  }
+
+  /// GetBlockLocator describes the best header chain to a peer
+  ///
+  /// The most recent headers are listed one by one, then the steps double
+  /// back to the genesis block, which is always the last entry.
+  pub fn get_block_locator(&self) -> Result<Vec<String>> {
+      match self.get_best_header()? {
+          Some(best) => self.build_locator(best.header),
+          None => Ok(Vec::new()),
+      }
+  }
+
+  /// GetChainLocator returns the block locator of the active chain tip
+  pub fn get_chain_locator(&self) -> Result<Vec<String>> {
+      if self.current_hash.is_empty() {
+          return Ok(Vec::new());
+      }
+      self.build_locator(self.get_header(&self.current_hash)?)
+  }
+
+  fn build_locator(&self, mut header: BlockHeader) -> Result<Vec<String>> {
+      let mut locator = Vec::new();
+      let mut step = 1;
+      loop {
+          locator.push(header.hash.clone());
+          if header.prev_block_hash.is_empty() {
+              break;
+          }
+          if locator.len() >= LOCATOR_DENSE_SPAN {
+              step *= 2;
+          }
+          for _ in 0..step {
+              if header.prev_block_hash.is_empty() {
+                  break;
+              }
+              header = self.get_header(&header.prev_block_hash)?;
+          }
+      }
+      Ok(locator)
+  }
+
+  /// GetHeadersAfter returns, oldest first, the active chain headers following
+  /// the most recent locator entry that is on the active chain
+  ///
+  /// At most `max` headers are returned, ending early at `stop_hash`. With no
+  /// shared entry the headers following the genesis block are returned.
+  pub fn get_headers_after(
+      &self,
+      locator: &[String],
+      stop_hash: &str,
+      max: usize,
+  ) -> Result<Vec<BlockHeader>> {
+      // locators list the newest blocks first, so the first entry found on
+      // the active chain is the fork point
+      let mut start = 1;
+      for hash in locator {
+          if let Ok(header) = self.get_header(hash) {
+              if self.get_block_hash(header.height).ok().as_ref() == Some(hash) {
+                  start = header.height + 1;
+                  break;
+              }
+          }
+      }
+      let mut headers = Vec::new();
+      for header in self.iter_header_range(start..) {
+          if headers.len() >= max {
+              break;
+          }
+          let header = header?;
+          let stop = header.hash == stop_hash;
+          headers.push(header);
+          if stop {
+              break;
+          }
+      }
+      Ok(headers)
+  }
+
+  /// GetHashesAfter returns, oldest first, the active chain block hashes
+  /// following the fork point with `locator`, with the same limits as
+  /// get_headers_after
+  pub fn get_hashes_after(&self, locator: &[String], stop_hash: &str, max: usize) -> Result<Vec<String>> {
+      Ok(self
+          .get_headers_after(locator, stop_hash, max)?
+          .into_iter()
+          .map(|header| header.hash)
+          .collect())
+  }
+
+  fn find_block_index(&self, block_hash: &str) -> Result<Option<BlockIndex>> {
+      match self.db.open_tree("index")?.get(block_hash)? {
+          Some(data) => Ok(Some(deserialize(&data)?)),
+          None => Ok(None),
+      }
+  }
+
+  /// GetBlockIndex returns the index entry of a stored block
+  pub fn get_block_index(&self, block_hash: &str) -> Result<BlockIndex> {
+      match self.find_block_index(block_hash)? {
+          Some(index) => Ok(index),
+          None => Err(format_err!("Block index for {} is not found", block_hash)),
+      }
+  }
+
+  /// SaveUndo persists the undo record of a block alongside it
+  pub fn save_undo(&self, block_hash: &str, undo: &BlockUndo) -> Result<()> {
+      self.db.open_tree("undo")?.insert(block_hash, serialize(undo)?)?;
+      Ok(())
+  }
+
+  /// GetUndo returns the undo record saved when the block was connected
+  pub fn get_undo(&self, block_hash: &str) -> Result<BlockUndo> {
+      match self.db.open_tree("undo")?.get(block_hash)? {
+          Some(data) => Ok(deserialize(&data)?),
+          None => Err(format_err!("Undo data for block {} is not found", block_hash)),
+      }
+  }
+
+  /// GetSideTips returns the tips of all known branches other than the active one
+  #[cfg(test)]
+  pub fn get_side_tips(&self) -> Result<Vec<BlockIndex>> {
+      let mut tips = Vec::new();
+      for kv in self.db.open_tree("sidetips")?.iter() {
+          let (_, v) = kv?;
+          tips.push(deserialize(&v)?);
+      }
+      Ok(tips)
+  }
+
+  /// GetReorgs returns every recorded chain reorganization, oldest first
+  pub fn get_reorgs(&self) -> Result<Vec<ReorgEvent>> {
+      let mut events = Vec::new();
+      for kv in self.db.open_tree("reorgs")?.iter() {
+          let (_, v) = kv?;
+          events.push(deserialize(&v)?);
+      }
+      Ok(events)
+  }
+
   /// FindUnspentTransactions returns a list of transactions containing unspent outputs
   fn find_unspent_transactions(&self, address: &[u8]) -> Vec<Transaction> {
     let mut spent_TXOs: HashMap<String, Vec<i32>> = HashMap::new();
     let mut unspend_TXs: Vec<Transaction> = Vec::new();
 
-    for block in self.iter() {
+    for block in self.iter().map_while(|block| block.ok()) {
       for tx in block.get_transaction() {
         for index in 0..tx.vout.len() {
           if let Some(ids) = spent_TXOs.get(&tx.id) {
@@ -145,49 +765,39 @@ impl Blockchain {
   }
 
   /// FindUTXO finds and returns all unspent transaction outputs
-  pub fn find_UTXO(&self) -> HashMap<String, TXOutputs> {
-    let mut utxos: HashMap<String, TXOutputs> = HashMap::new();
-    let mut spend_txos: HashMap<String, Vec<i32>> = HashMap::new();
+  ///
+  /// Blocks are walked from the tip and their transactions in reverse, so every
+  /// spend is seen before the output it consumes.
+  pub fn find_UTXO(&self) -> Result<HashMap<OutPoint, UTXOEntry>> {
+    let mut utxos: HashMap<OutPoint, UTXOEntry> = HashMap::new();
+    let mut spend_txos: HashSet<OutPoint> = HashSet::new();
     for block in self.iter() {
-        for tx in block.get_transaction() {
-            for index in 0..tx.vout.len() {
-                if let Some(ids) = spend_txos.get(&tx.id) {
-                    if ids.contains(&(index as i32)) {
-                        continue;
-                    }
-                }
-
-                match utxos.get_mut(&tx.id) {
-                    Some(v) => {
-                        v.outputs.push(tx.vout[index].clone());
-                    }
-                    None => {
-                        utxos.insert(
-                            tx.id.clone(),
-                            TXOutputs {
-                                outputs: vec![tx.vout[index].clone()],
-                            },
-                        );
-                    }
+        let block = block?;
+        for tx in block.get_transaction().iter().rev() {
+            if !tx.is_coinbase() {
+                for i in &tx.vin {
+                    spend_txos.insert(OutPoint::new(&i.txid, i.vout));
                 }
             }
 
-            if !tx.is_coinbase() {
-                for i in &tx.vin {
-                    match spend_txos.get_mut(&i.txid) {
-                        Some(v) => {
-                            v.push(i.vout);
-                        }
-                        None => {
-                            spend_txos.insert(i.txid.clone(), vec![i.vout]);
-                        }
-                    }
+            for index in 0..tx.vout.len() {
+                let outpoint = OutPoint::new(&tx.id, index as i32);
+                if spend_txos.contains(&outpoint) {
+                    continue;
                 }
+                utxos.insert(
+                    outpoint,
+                    UTXOEntry {
+                        output: tx.vout[index].clone(),
+                        height: block.get_height(),
+                        is_coinbase: tx.is_coinbase(),
+                    },
+                );
             }
         }
     }
 
-    utxos
+    Ok(utxos)
   }
 
   pub fn iter(&self) -> BlockchainIter {
@@ -199,14 +809,153 @@ impl Blockchain {
 
   /// FindTransaction finds a transaction by its ID
   pub fn find_transacton(&self, id: &str) -> Result<Transaction> {
+    match self.locate_transaction(id)? {
+      Some((tx, _)) => Ok(tx),
+      None => Err(format_err!("Transaction is not found")),
+    }
+  }
+
+  /// LocateTransaction finds a transaction of the active chain along with
+  /// where it is stored, through the txid index when it is kept
+  pub fn locate_transaction(&self, id: &str) -> Result<Option<(Transaction, TxLocation)>> {
+    if self.tx_index {
+      let location: TxLocation = match self.db.open_tree("txindex")?.get(id)? {
+        Some(data) => deserialize(&data)?,
+        None => return Ok(None),
+      };
+      let block = self.get_block(&location.block_hash)?;
+      let tx = block.get_transaction()[location.position as usize].clone();
+      return Ok(Some((tx, location)));
+    }
     for b in self.iter() {
-      for tx in b.get_transaction() {
-          if tx.id == id {
-              return Ok(tx.clone());
-          }
+      let b = b?;
+      if let Some(position) = b.get_transaction().iter().position(|tx| tx.id == id) {
+        let location = TxLocation {
+          block_hash: b.get_hash(),
+          height: b.get_height(),
+          position: position as u32,
+        };
+        return Ok(Some((b.get_transaction()[position].clone(), location)));
       }
     }
-    Err(format_err!("Transaction is not found"))
+    Ok(None)
+  }
+
+  /// GetMerkleProof proves that a transaction of the active chain is in its
+  /// block, or returns None when the transaction is not found
+  pub fn get_merkle_proof(&self, txid: &str) -> Result<Option<MerkleProof>> {
+    match self.locate_transaction(txid)? {
+      Some((_, location)) => self.get_block(&location.block_hash)?.merkle_proof(txid),
+      None => Ok(None),
+    }
+  }
+
+  /// VerifyMerkleProof checks that a proof leads to the merkle root of a block
+  /// of the active chain
+  pub fn verify_merkle_proof(&self, proof: &MerkleProof) -> Result<bool> {
+    let header = match self.find_header_index(&proof.block_hash)? {
+      Some(index) => index.header,
+      None => return Ok(false),
+    };
+    if self.get_block_hash(header.height).ok().as_ref() != Some(&header.hash) {
+      return Ok(false);
+    }
+    proof.verify(&header)
+  }
+
+  /// GetAddressTxids lists the ids of the active chain transactions paying
+  /// or spending from `pub_key_hash`, oldest first, or None when the address
+  /// index is not kept
+  pub fn get_address_txids(&self, pub_key_hash: &[u8]) -> Result<Option<Vec<String>>> {
+    if !self.tx_index {
+      return Ok(None);
+    }
+    let mut prefix = vec![pub_key_hash.len() as u8];
+    prefix.extend_from_slice(pub_key_hash);
+    let mut txids = Vec::new();
+    for entry in self.db.open_tree("addrindex")?.scan_prefix(prefix) {
+      let (_, txid) = entry?;
+      txids.push(String::from_utf8(txid.to_vec())?);
+    }
+    Ok(Some(txids))
+  }
+
+  /// HasTxIndex reports whether the txid and address indexes are kept
+  pub fn has_tx_index(&self) -> bool {
+    self.tx_index
+  }
+
+  /// RebuildTxIndex indexes every transaction of the active chain by id and
+  /// by the addresses it pays and spends from, then keeps both indexes up to
+  /// date as blocks are connected and disconnected. It returns the number of
+  /// transactions indexed.
+  ///
+  /// A rebuild that fails leaves no index behind rather than a partial one.
+  pub fn rebuild_tx_index(&mut self) -> Result<usize> {
+    self.drop_tx_index()?;
+    let mut count = 0;
+    for block in self.iter_range(..) {
+      let block = block?;
+      self.index_block(&block)?;
+      count += block.get_transaction().len();
+    }
+    self.db.insert("TXINDEX", serialize(&true)?)?;
+    self.db.flush()?;
+    self.tx_index = true;
+    Ok(count)
+  }
+
+  /// DropTxIndex removes the txid and address indexes
+  pub fn drop_tx_index(&mut self) -> Result<()> {
+    self.db.remove("TXINDEX")?;
+    self.clear_tx_index()?;
+    self.db.flush()?;
+    self.tx_index = false;
+    Ok(())
+  }
+
+  fn clear_tx_index(&self) -> Result<()> {
+    self.db.open_tree("txindex")?.clear()?;
+    self.db.open_tree("addrindex")?.clear()?;
+    Ok(())
+  }
+
+  /// IndexBlock adds the transactions of a block joining the active chain
+  /// to the indexes
+  fn index_block(&self, block: &Block) -> Result<()> {
+    let txindex = self.db.open_tree("txindex")?;
+    let addrindex = self.db.open_tree("addrindex")?;
+    for (position, tx) in block.get_transaction().iter().enumerate() {
+      let location = TxLocation {
+        block_hash: block.get_hash(),
+        height: block.get_height(),
+        position: position as u32,
+      };
+      txindex.insert(tx.id.as_bytes(), serialize(&location)?)?;
+      for pub_key_hash in tx.get_pub_key_hashes() {
+        addrindex.insert(address_index_key(&pub_key_hash, &location), tx.id.as_bytes())?;
+      }
+    }
+    Ok(())
+  }
+
+  /// UnindexBlock removes the transactions of a block leaving the active
+  /// chain from the indexes
+  fn unindex_block(&self, block: &Block) -> Result<()> {
+    let txindex = self.db.open_tree("txindex")?;
+    let addrindex = self.db.open_tree("addrindex")?;
+    for (position, tx) in block.get_transaction().iter().enumerate() {
+      let location = TxLocation {
+        block_hash: block.get_hash(),
+        height: block.get_height(),
+        position: position as u32,
+      };
+      txindex.remove(tx.id.as_bytes())?;
+      for pub_key_hash in tx.get_pub_key_hashes() {
+        addrindex.remove(address_index_key(&pub_key_hash, &location))?;
+      }
+    }
+    Ok(())
   }
 
   fn get_prev_TXs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
@@ -226,71 +975,473 @@ impl Blockchain {
   }
 
   /// VerifyTransaction verifies transaction input signatures
+  ///
+  /// The transaction is checked as if it went into the block after the tip,
+  /// so spending a coinbase that is not yet mature there is an error.
   pub fn verify_transacton(&self, tx: &Transaction) -> Result<bool> {
     if tx.is_coinbase() {
         return Ok(true);
     }
     let prev_TXs = self.get_prev_TXs(tx)?;
+    check_maturity(self, tx, &prev_TXs, self.get_best_height()? + 1)?;
     tx.verify(prev_TXs)
   }
 
-  // GetBlock finds a block by its hash and returns it
+  // GetBlock finds a block by its hash and returns it, joining its header
+  // and its body
   pub fn get_block(&self, block_hash: &str) -> Result<Block> {
-      let data = self.db.get(block_hash)?.unwrap();
-      let block = deserialize(&data.to_vec())?;
-      Ok(block)
+      let body = match self.db.open_tree("bodies")?.get(block_hash)? {
+          Some(data) => data,
+          None => return Err(format_err!("Block {} is not found", block_hash)),
+      };
+      let header = self.get_header(block_hash)?;
+      Ok(Block::from_parts(header, deserialize(&body)?))
+  }
+
+  /// GetSubsidySchedule returns the reward schedule of the chain
+  pub fn get_subsidy_schedule(&self) -> SubsidySchedule {
+      self.subsidy
+  }
+
+  /// GetBlockSubsidy returns the reward a block at `height` may create
+  pub fn get_block_subsidy(&self, height: i32) -> i32 {
+      self.subsidy.subsidy(height)
+  }
+
+  /// GetIssuedSupply returns the coins created by blocks up to and including `height`
+  pub fn get_issued_supply(&self, height: i32) -> i64 {
+      self.subsidy.supply_at(height)
+  }
+
+  /// GetDataDir returns the directory the chain is stored in
+  pub fn get_data_dir(&self) -> &Path {
+    &self.data_dir
+  }
+
+  /// GetCoinbaseMaturity returns how many blocks deep a coinbase output must be to be spent
+  pub fn get_coinbase_maturity(&self) -> i32 {
+      self.coinbase_maturity
+  }
+
+  /// IsMature reports whether a coinbase created at `height` may be spent in a block at `spend_height`
+  pub fn is_mature(&self, height: i32, spend_height: i32) -> bool {
+      spend_height - height >= self.coinbase_maturity
+  }
+
+  /// GetPowLimit returns the easiest target, in compact form, of the chain
+  pub fn get_pow_limit(&self) -> u32 {
+      self.pow_limit_bits
+  }
+
+  #[cfg(test)]
+  pub(crate) fn set_coinbase_maturity(&mut self, coinbase_maturity: i32) {
+      self.coinbase_maturity = coinbase_maturity;
+  }
+
+  /// GetNextBits returns the target the block following `prev` must be mined against
+  ///
+  /// The target is kept for RETARGET_INTERVAL blocks and then scaled by how long
+  /// the last window actually took compared to TARGET_BLOCK_TIME per block.
+  /// Chains created without retargeting keep the genesis target forever.
+  pub fn get_next_bits(&self, prev: &BlockHeader) -> Result<u32> {
+      let height = prev.height + 1;
+      if !self.retarget || height % RETARGET_INTERVAL != 0 {
+          return Ok(prev.bits);
+      }
+
+      let mut first = prev.clone();
+      for _ in 0..RETARGET_INTERVAL - 1 {
+          first = self.get_header(&first.prev_block_hash)?;
+      }
+      let actual = prev.timestamp.saturating_sub(first.timestamp);
+      let expected = TARGET_BLOCK_TIME * (RETARGET_INTERVAL - 1) as u128;
+      let bits = calculate_next_bits(prev.bits, actual, expected, self.pow_limit_bits)?;
+      info!(
+          "retarget at height {}: {} ms for {} ms expected, bits {:#010x} -> {:#010x}",
+          height, actual, expected, prev.bits, bits
+      );
+      Ok(bits)
+  }
+
+  /// GetTipHash returns the hash of the last block of the active chain
+  pub fn get_tip_hash(&self) -> String {
+      self.current_hash.clone()
+  }
+
+  /// GetBlockHash returns the hash of the active chain block at `height`
+  pub fn get_block_hash(&self, height: i32) -> Result<String> {
+      match self.db.open_tree("heights")?.get(height.to_be_bytes())? {
+          Some(hash) if height >= 0 => Ok(String::from_utf8(hash.to_vec())?),
+          _ => Err(format_err!("No block at height {}", height)),
+      }
+  }
+
+  /// GetBlockByHeight returns the active chain block at `height`
+  pub fn get_block_by_height(&self, height: i32) -> Result<Block> {
+      self.get_block(&self.get_block_hash(height)?)
+  }
+
+  /// GetHeaderByHeight returns the header of the active chain block at
+  /// `height`, without reading its transactions
+  pub fn get_header_by_height(&self, height: i32) -> Result<BlockHeader> {
+      self.get_header(&self.get_block_hash(height)?)
+  }
+
+  /// IterRange walks the active chain blocks with heights in `range`, from
+  /// the lowest height up, or from the highest down once reversed
+  pub fn iter_range(&self, range: impl RangeBounds<i32>) -> HeightRangeIter<'_, Block> {
+      self.height_range(range, Blockchain::get_block_by_height)
+  }
+
+  /// IterHeaderRange walks the headers of the active chain like iter_range,
+  /// without deserializing any transaction
+  pub fn iter_header_range(&self, range: impl RangeBounds<i32>) -> HeightRangeIter<'_, BlockHeader> {
+      self.height_range(range, Blockchain::get_header_by_height)
+  }
+
+  fn height_range<T>(
+      &self,
+      range: impl RangeBounds<i32>,
+      fetch: fn(&Blockchain, i32) -> Result<T>,
+  ) -> HeightRangeIter<'_, T> {
+      let front = match range.start_bound() {
+          Bound::Included(h) => *h,
+          Bound::Excluded(h) => h + 1,
+          Bound::Unbounded => 0,
+      };
+      let back = match range.end_bound() {
+          Bound::Included(h) => h + 1,
+          Bound::Excluded(h) => *h,
+          Bound::Unbounded => self.best_height + 1,
+      };
+      HeightRangeIter {
+          bc: self,
+          fetch,
+          front: front.max(0),
+          back: back.min(self.best_height + 1),
+      }
   }
 
   /// GetBestHeight returns the height of the latest block
   pub fn get_best_height(&self) -> Result<i32> {
-      let lasthash = if let Some(h) = self.db.get("LAST")? {
-          h
-      } else {
-          return Ok(-1);
-      };
-      let last_data = self.db.get(lasthash)?.unwrap();
-      let last_block: Block = deserialize(&last_data.to_vec())?;
-      Ok(last_block.get_height())
+      Ok(self.best_height)
   }
+}
 
-  /// GetBlockHashes returns a list of hashes of all the blocks in the chain
-  pub fn get_block_hashs(&self) -> Vec<String> {
-      let mut list = Vec::new();
-      for b in self.iter() {
-          list.push(b.get_hash());
-      }
-      list
+/// AddressIndexKey orders the address index entries of a public key hash by
+/// height and position, so a prefix scan lists them in chain order
+fn address_index_key(pub_key_hash: &[u8], location: &TxLocation) -> Vec<u8> {
+  let mut key = vec![pub_key_hash.len() as u8];
+  key.extend_from_slice(pub_key_hash);
+  key.extend_from_slice(&location.height.to_be_bytes());
+  key.extend_from_slice(&location.position.to_be_bytes());
+  key
+}
+
+/// CalculateNextBits scales a compact target by `actual / expected`
+///
+/// The adjustment is clamped to a factor of four in either direction and the
+/// result never gets easier than `pow_limit`.
+pub fn calculate_next_bits(bits: u32, actual: u128, expected: u128, pow_limit: u32) -> Result<u32> {
+  let actual = actual.clamp(expected / 4, expected * 4);
+
+  let mut target = compact_to_target(bits)?;
+  // multiply then divide the big-endian target as base-256 digits
+  let mut carry: u128 = 0;
+  let mut product = [0u128; 33];
+  for i in (0..32).rev() {
+    let v = target[i] as u128 * actual + carry;
+    product[i + 1] = v & 0xff;
+    carry = v >> 8;
+  }
+  product[0] = carry;
+  let mut rem: u128 = 0;
+  let mut quotient = [0u128; 33];
+  for i in 0..33 {
+    let v = (rem << 8) | product[i];
+    quotient[i] = v / expected;
+    rem = v % expected;
+  }
+  // anything that spills past 256 bits is easier than the limit anyway
+  if quotient[0] != 0 || quotient[1..].iter().any(|d| *d > 0xff) {
+    return Ok(pow_limit);
+  }
+  for i in 0..32 {
+    target[i] = quotient[i + 1] as u8;
+  }
+
+  if target > compact_to_target(pow_limit)? {
+    return Ok(pow_limit);
+  }
+  Ok(target_to_compact(&target))
+}
+
+impl<'a, T> Iterator for HeightRangeIter<'a, T> {
+  type Item = Result<T>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.front >= self.back {
+      return None;
+    }
+    let item = (self.fetch)(self.bc, self.front);
+    self.front = if item.is_ok() { self.front + 1 } else { self.back };
+    Some(item)
+  }
+}
+
+impl<'a, T> DoubleEndedIterator for HeightRangeIter<'a, T> {
+  fn next_back(&mut self) -> Option<Self::Item> {
+    if self.front >= self.back {
+      return None;
+    }
+    let item = (self.fetch)(self.bc, self.back - 1);
+    self.back = if item.is_ok() { self.back - 1 } else { self.front };
+    Some(item)
   }
 }
 
 impl<'a> Iterator for BlockchainIter<'a> {
-  type Item = Block;
+  type Item = Result<Block>;
 
   fn next(&mut self) -> Option<Self::Item> {
-    if let Ok(encode_block) = self.bc.db.get(&self.current_hash) {
-      return match encode_block {
-        Some(b) => {
-          if let Ok(block) = bincode::deserialize::<Block>(&b) {
-            self.current_hash = block.get_prev_hash();
-            Some(block)
-            } else {
-              None
-            }
-          }
-        None => None,
-      };
+    if self.current_hash.is_empty() {
+      return None;
     }
-    None
+    let block = self.bc.get_block(&self.current_hash);
+    self.current_hash = match &block {
+      Ok(block) => block.get_prev_hash(),
+      Err(_) => String::new(),
+    };
+    Some(block)
   }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::config::{Network, NetworkParams};
+  use crate::testutil::{TempDir, TestChain};
+
+  /// genesis returns the first block of a test chain
+  fn genesis(chain: &TestChain) -> Block {
+    chain.utxo.blockchain.get_block(&chain.params.genesis.hash).unwrap()
+  }
+
+  fn child(bc: &Blockchain, parent: &Block, miner: &str) -> Block {
+    let height = parent.get_height() + 1;
+    let cbtx = Transaction::new_coinbase(miner.to_string(), String::new(), height, bc.get_block_subsidy(height)).unwrap();
+    Block::new_block(
+      vec![cbtx],
+      parent.get_hash(),
+      height,
+      bc.get_next_bits(parent.get_header()).unwrap(),
+    )
+    .unwrap()
+  }
+
+  #[test]
+  fn test_reorg_to_heavier_branch() {
+    let a = crate::wallets::Wallet::new().get_address();
+    let b = crate::wallets::Wallet::new().get_address();
+    let mut chain = TestChain::new("blockchain-reorg");
+    let genesis = genesis(&chain);
+    let bc = &mut chain.utxo.blockchain;
+
+    let a1 = child(bc, &genesis, &a);
+    let update = bc.add_block(a1.clone()).unwrap();
+    assert_eq!(update.connected.len(), 1);
+    assert!(!update.is_reorg());
+
+    let b1 = child(bc, &genesis, &b);
+    let update = bc.add_block(b1.clone()).unwrap();
+    assert!(update.connected.is_empty());
+    assert_eq!(bc.get_side_tips().unwrap()[0].hash, b1.get_hash());
+
+    let b2 = child(bc, &b1, &b);
+    let update = bc.add_block(b2.clone()).unwrap();
+    assert!(update.is_reorg());
+    assert_eq!(update.disconnected[0].get_hash(), a1.get_hash());
+    let connected: Vec<String> = update.connected.iter().map(|b| b.get_hash()).collect();
+    assert_eq!(connected, vec![b1.get_hash(), b2.get_hash()]);
+    assert_eq!(bc.iter().next().unwrap().unwrap().get_hash(), b2.get_hash());
+    assert_eq!(bc.get_best_height().unwrap(), 2);
+    assert_eq!(bc.get_block_hash(1).unwrap(), b1.get_hash());
+    assert_eq!(bc.get_block_by_height(2).unwrap().get_hash(), b2.get_hash());
+    assert!(bc.get_block_hash(3).is_err());
+    let forward: Vec<String> = bc.iter_range(..).map(|b| b.unwrap().get_hash()).collect();
+    assert_eq!(forward, vec![genesis.get_hash(), b1.get_hash(), b2.get_hash()]);
+    let backward: Vec<String> = bc.iter_range(1..).rev().map(|b| b.unwrap().get_hash()).collect();
+    assert_eq!(backward, vec![b2.get_hash(), b1.get_hash()]);
+    assert_eq!(bc.iter_range(1..2).count(), 1);
+    let heights: Vec<i32> = bc.iter_header_range(..).rev().map(|h| h.unwrap().height).collect();
+    assert_eq!(heights, vec![2, 1, 0]);
+
+    let tips = bc.get_side_tips().unwrap();
+    assert_eq!(tips.len(), 1);
+    assert_eq!(tips[0].hash, a1.get_hash());
+    let reorgs = bc.get_reorgs().unwrap();
+    assert_eq!(reorgs.len(), 1);
+    assert_eq!(reorgs[0].fork_hash, genesis.get_hash());
+    assert_eq!(reorgs[0].depth, 1);
+
+    // a peer still on a1 gets the blocks after the fork point
+    let locator = vec![a1.get_hash(), genesis.get_hash()];
+    assert_eq!(bc.get_hashes_after(&locator, "", 500).unwrap(), connected);
+    assert_eq!(bc.get_hashes_after(&locator, "", 1).unwrap(), vec![b1.get_hash()]);
+    assert_eq!(bc.get_hashes_after(&locator, &b1.get_hash(), 500).unwrap(), vec![b1.get_hash()]);
+    assert!(bc.get_hashes_after(&bc.get_chain_locator().unwrap(), "", 500).unwrap().is_empty());
+
+    // a block that cannot be read ends the walks with an error
+    bc.rebuild_tx_index().unwrap();
+    bc.db.open_tree("bodies").unwrap().remove(b1.get_hash()).unwrap();
+    let items: Vec<bool> = bc.iter_range(..).map(|b| b.is_ok()).collect();
+    assert_eq!(items, vec![true, false]);
+    let items: Vec<bool> = bc.iter().map(|b| b.is_ok()).collect();
+    assert_eq!(items, vec![true, false]);
+    assert!(bc.rebuild_tx_index().is_err());
+    assert!(!bc.has_tx_index());
+  }
+
+  #[test]
+  fn test_tx_index_follows_reorg() {
+    let a = crate::wallets::Wallet::new().get_address();
+    let b = crate::wallets::Wallet::new().get_address();
+    let mut chain = TestChain::new("blockchain-txindex");
+    let genesis = genesis(&chain);
+    let bc = &mut chain.utxo.blockchain;
+    let a_hash = Network::Main.decode_address(&a).unwrap();
+    let b_hash = Network::Main.decode_address(&b).unwrap();
+    assert!(bc.get_address_txids(&a_hash).unwrap().is_none());
+    assert_eq!(bc.rebuild_tx_index().unwrap(), 1);
+
+    let a1 = child(bc, &genesis, &a);
+    bc.add_block(a1.clone()).unwrap();
+    let a1_coinbase = a1.get_transaction()[0].id.clone();
+    let (_, location) = bc.locate_transaction(&a1_coinbase).unwrap().unwrap();
+    assert_eq!((location.block_hash, location.height, location.position), (a1.get_hash(), 1, 0));
+    assert_eq!(bc.get_address_txids(&a_hash).unwrap().unwrap(), vec![a1_coinbase.clone()]);
+
+    let b1 = child(bc, &genesis, &b);
+    bc.add_block(b1.clone()).unwrap();
+    let b2 = child(bc, &b1, &b);
+    assert!(bc.add_block(b2.clone()).unwrap().is_reorg());
+    assert!(bc.locate_transaction(&a1_coinbase).unwrap().is_none());
+    assert!(bc.get_address_txids(&a_hash).unwrap().unwrap().is_empty());
+    let b_txids = vec![b1.get_transaction()[0].id.clone(), b2.get_transaction()[0].id.clone()];
+    assert_eq!(bc.get_address_txids(&b_hash).unwrap().unwrap(), b_txids);
+
+    // rebuilding from the active chain gives the same indexes
+    assert_eq!(bc.rebuild_tx_index().unwrap(), 3);
+    assert_eq!(bc.get_address_txids(&b_hash).unwrap().unwrap(), b_txids);
+    bc.drop_tx_index().unwrap();
+    assert!(bc.get_address_txids(&b_hash).unwrap().is_none());
+    assert_eq!(bc.find_transacton(&b_txids[1]).unwrap().id, b_txids[1]);
+  }
+
+  #[test]
+  fn test_headers_first() {
+    let a = crate::wallets::Wallet::new().get_address();
+    let mut chain1 = TestChain::new("blockchain-headers-1");
+    let mut chain2 = TestChain::new("blockchain-headers-2");
+    let genesis = genesis(&chain1);
+    let bc1 = &mut chain1.utxo.blockchain;
+    let bc2 = &mut chain2.utxo.blockchain;
+    let b1 = child(bc1, &genesis, &a);
+    bc1.add_block(b1.clone()).unwrap();
+    let b2 = child(bc1, &b1, &a);
+    bc1.add_block(b2.clone()).unwrap();
+    let b3 = child(bc1, &b2, &a);
+    bc1.add_block(b3.clone()).unwrap();
+
+    let locator = bc1.get_block_locator().unwrap();
+    assert_eq!(locator, vec![b3.get_hash(), b2.get_hash(), b1.get_hash(), genesis.get_hash()]);
+    let headers = bc1.get_headers_after(&bc2.get_block_locator().unwrap(), "", 2000).unwrap();
+    assert_eq!(headers.len(), 3);
+    assert_eq!(bc1.get_headers_after(&[genesis.get_hash()], &b2.get_hash(), 2000).unwrap().len(), 2);
+
+    let mut forged = headers[0].clone();
+    forged.nonce += 1;
+    assert!(bc2.add_header(&forged).is_err());
+    for header in &headers {
+      assert!(bc2.add_header(header).unwrap());
+    }
+    assert!(!bc2.add_header(&headers[2]).unwrap());
+    assert_eq!(bc2.get_best_header().unwrap().unwrap().header.hash, b3.get_hash());
+
+    let missing = vec![b1.get_hash(), b2.get_hash(), b3.get_hash()];
+    assert_eq!(bc2.get_missing_blocks(10).unwrap(), missing);
+    assert_eq!(bc2.get_missing_blocks(2).unwrap(), missing[..2].to_vec());
+    bc2.add_block(b1.clone()).unwrap();
+    assert_eq!(bc2.get_missing_blocks(10).unwrap(), missing[1..].to_vec());
+
+    // a longer header branch from b1 takes over the downloads
+    let c = crate::wallets::Wallet::new().get_address();
+    let mut branch = vec![b1];
+    for _ in 0..3 {
+      let next = child(bc2, branch.last().unwrap(), &c);
+      assert!(bc2.add_header(next.get_header()).unwrap());
+      branch.push(next);
+    }
+    let late = child(bc2, branch.last().unwrap(), &c);
+    let branch: Vec<String> = branch[1..].iter().map(|b| b.get_hash()).collect();
+    assert_eq!(bc2.get_missing_blocks(10).unwrap(), branch);
+    bc2.invalidate_header(&branch[0]).unwrap();
+    assert!(bc2.get_missing_blocks(10).unwrap().is_empty());
+    // the descendants go with it, so a header extending them later is refused
+    assert!(branch.iter().all(|hash| bc2.get_header(hash).is_err()));
+    assert!(bc2.add_header(late.get_header()).is_err());
+    assert_eq!(bc2.get_best_header().unwrap().unwrap().header.hash, bc2.get_tip_hash());
+  }
+
+  #[test]
+  fn test_calculate_next_bits() {
+    let expected = TARGET_BLOCK_TIME * (RETARGET_INTERVAL - 1) as u128;
+    assert_eq!(calculate_next_bits(POW_LIMIT_BITS, expected, expected, POW_LIMIT_BITS).unwrap(), POW_LIMIT_BITS);
+    // blocks came too slowly, but the target can't get easier than the limit
+    assert_eq!(calculate_next_bits(POW_LIMIT_BITS, expected * 2, expected, POW_LIMIT_BITS).unwrap(), POW_LIMIT_BITS);
+    // blocks came twice as fast, so the target halves
+    assert_eq!(calculate_next_bits(POW_LIMIT_BITS, expected / 2, expected, POW_LIMIT_BITS).unwrap(), 0x1e7fff80);
+    // the adjustment is clamped to a factor of four
+    assert_eq!(
+      calculate_next_bits(POW_LIMIT_BITS, 1, expected, POW_LIMIT_BITS).unwrap(),
+      calculate_next_bits(POW_LIMIT_BITS, expected / 4, expected, POW_LIMIT_BITS).unwrap()
+    );
+  }
+
+  /// reopen opens the chain in `dir` again, waiting for sled to release the
+  /// lock it holds for a moment after the previous handle is dropped
+  fn reopen(dir: &Path, genesis: &GenesisParams) -> Result<Blockchain> {
+    for _ in 0..50 {
+      match Blockchain::new(dir, genesis) {
+        Err(e) if e.to_string().contains("could not acquire lock") => {
+          std::thread::sleep(std::time::Duration::from_millis(20))
+        }
+        result => return result,
+      }
+    }
+    Blockchain::new(dir, genesis)
+  }
 
   #[test]
   fn test_blockchain() {
-    let mut b = Blockchain::new().unwrap();
+    let temp = TempDir::new("blockchain");
+    let dir = temp.path();
+    let params = GenesisParams::default();
+    let b = Blockchain::new(dir, &params).unwrap();
+    assert_eq!(b.get_best_height().unwrap(), 0);
+    assert_eq!(b.get_block(&params.hash).unwrap().get_timestamp(), params.timestamp as u128);
+    drop(b);
+    // the same store opens again, but not as another network's chain
+    drop(reopen(dir, &params).unwrap());
+    let regtest = NetworkParams::for_network(Network::Regtest).genesis;
+    let err = reopen(dir, &regtest).err().unwrap();
+    assert!(err.to_string().contains("expected"), "{}", err);
+    let b = reopen(dir, &params).unwrap();
+    // a store without the height index gets it rebuilt when opened
+    b.db.drop_tree("heights").unwrap();
+    drop(b);
+    let b = reopen(dir, &params).unwrap();
+    assert_eq!(b.get_block_hash(0).unwrap(), params.hash);
     // b.add_block("data".to_string());
     // b.add_block("data2".to_string());
     // b.add_block("data3".to_string());
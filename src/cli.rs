@@ -1,11 +1,19 @@
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use bitcoincash_addr::Address;
-use clap::{arg, Command};
+use std::time::SystemTime;
+use failure::format_err;
+use clap::{arg, ArgAction, ArgMatches, Command};
+use serde_json::{json, Value};
+use crate::block::{Block, MerkleProof};
 use crate::blockchain::Blockchain;
+use crate::config::{parse_magic, GenesisParams, Network, NetworkParams, DEFAULT_CONFIG_FILE};
 use crate::errors::Result;
+use crate::explorer::{self, HistoryEntry, TxDetails};
+use crate::light::LightClient;
+use crate::rpc::{block_json, merkle_proof_from_json, merkle_proof_json, Client};
 use crate::server::Server;
 // use crate::server::Server;
-use crate::transaction::Transaction;
+use crate::transaction::{Fee, Transaction};
 use crate::utxoset::UTXOSet;
 // use crate::utxoset::UTXOSet;
 use crate::wallets::{Wallet, Wallets};
@@ -21,19 +29,64 @@ impl Cli {
             .version("0.1")
             .author("behrouz.r.fa@gmail.com")
             .about("blockchain in rust: a simple blockchain for learning")
+            .arg(arg!(--network <NAME> "'main, test or regtest, main by default'").global(true))
+            .arg(arg!(--config <FILE> "'network config file, node.toml when present'").global(true))
+            .arg(arg!(--datadir <DIR> "'directory holding the chain and wallets'").global(true))
+            .arg(arg!(--bind <ADDR> "'address the node listens on'").global(true))
+            .arg(arg!(--advertise <ADDR> "'address other nodes reach this node at'").global(true))
+            .arg(arg!(--seed <ADDR> "'seed node, may be repeated'").action(ArgAction::Append).global(true))
+            .arg(arg!(--magic <HEX> "'network magic bytes in hex'").global(true))
+            .arg(arg!(--rpcbind <ADDR> "'loopback address of the JSON-RPC endpoint, empty to turn it off'").global(true))
+            .arg(arg!(--format <FORMAT> "'output format, text or json, text by default'").global(true))
             .subcommand(Command::new("printchain").about("print all the chain blocks"))
+            .subcommand(Command::new("getblock")
+                .about("print a block of the chain")
+                .arg(arg!(<BLOCK>"'block hash or height'"))
+            )
+            .subcommand(Command::new("gettx")
+                .about("print a transaction with the addresses and values it spends")
+                .arg(arg!(<TXID>"'transaction id'"))
+            )
+            .subcommand(Command::new("getmerkleproof")
+                .about("print a proof that a transaction is in its block")
+                .arg(arg!(<TXID>"'transaction id'"))
+            )
+            .subcommand(Command::new("verifymerkleproof")
+                .about("check a proof printed by getmerkleproof against the active chain")
+                .arg(arg!(<PROOF>"'the proof as JSON'"))
+            )
+            .subcommand(Command::new("getaddresshistory")
+                .about("list the transactions of an address with the running balance")
+                .arg(arg!(<ADDRESS>"'The address to list the history of'"))
+            )
             .subcommand(Command::new("createwallet").about("create a wallet"))
             .subcommand(Command::new("listaddresses").about("list all addresses"))
-            .subcommand(Command::new("reindex").about("reindex UTXO"))
+            .subcommand(Command::new("reindex").about("reindex UTXO")
+                .arg(arg!(--txindex "'also build the transaction and address indexes and keep them from now on'"))
+                .arg(arg!(--"drop-txindex" "'remove the transaction and address indexes'").conflicts_with("txindex"))
+            )
+            .subcommand(Command::new("listreorgs").about("list recorded chain reorganizations"))
             .subcommand(Command::new("getbalance")
                 .about("get balance in the blochain")
                 .arg(arg!(<ADDRESS>"'The Address it get balance for'"))
             ).subcommand(Command::new("startnode")
             .about("start the node server")
-            .arg(arg!(<PORT>"'the port server bind to locally'"))
+            .arg(arg!([PORT]"'the port server bind to locally'"))
         )
-            .subcommand(Command::new("create").about("Create new blochain")
-                .arg(arg!(<ADDRESS>"'The address to send gensis block reqward to' "))
+            .subcommand(Command::new("startlight")
+                .about("follow the wallet balances from the --seed nodes without storing the chain")
+            )
+            .subcommand(Command::new("creategenesis").about("mine the genesis block of a new network")
+                .arg(arg!(--timestamp <MS> "'genesis time in unix milliseconds, now by default'"))
+                .arg(arg!(--reward <REWARD> "'initial block reward'"))
+                .arg(arg!(--halving <BLOCKS> "'blocks between reward halvings'"))
+                .arg(arg!(--maturity <BLOCKS> "'blocks before a coinbase can be spent'"))
+            )
+            .subcommand(Command::new("emission").about("print the coin emission curve of the chain"))
+            .subcommand(Command::new("generate")
+                .about("mine blocks right away, regtest only")
+                .arg(arg!(<COUNT>"'number of blocks to mine'"))
+                .arg(arg!(<ADDRESS>"'The address to send the block rewards to'"))
             )
 
             .subcommand(
@@ -42,7 +95,9 @@ impl Cli {
                     .arg(arg!(<FROM>" 'Source wallet address'"))
                     .arg(arg!(<TO>" 'Destination wallet address'"))
                     .arg(arg!(<AMOUNT>" 'Destination wallet address'"))
-                    .arg(arg!(-m --mine " 'the from address mine immediately'")),
+                    .arg(arg!(-m --mine " 'the from address mine immediately'"))
+                    .arg(arg!(--fee <FEE> " 'absolute fee to pay'"))
+                    .arg(arg!(--feerate <FEERATE> " 'fee to pay per byte'")),
             )
             .subcommand(
                 Command::new("startminer")
@@ -53,6 +108,12 @@ impl Cli {
             )
             .get_matches();
 
+        let params = load_params(&matches)?;
+        let format = match matches.get_one::<String>("format") {
+            Some(name) => Format::parse(name)?,
+            None => Format::Text,
+        };
+
         if let Some(ref matches) = matches.subcommand_matches("startminer") {
             let port = if let Some(port) = matches.get_one::<String>("PORT") {
                 port
@@ -67,46 +128,113 @@ impl Cli {
                 println!("ADDRESS not supply!: usage");
                 exit(1)
             };
-            let bc = Blockchain::new()?;
-            let utxo_set = UTXOSet { blockchain: bc };
-            let server = Server::new(port, address, utxo_set)?;
+            params.network.decode_address(address)?;
+            let mut params = params.clone();
+            params.set_port(port)?;
+            let utxo_set = open_utxo_set(&params)?;
+            let server = Server::new(&params, address, utxo_set)?;
             server.start_server()?;
         }
 
 
         if let Some(ref matches) = matches.subcommand_matches("startnode") {
+            let mut params = params.clone();
             if let Some(port) = matches.get_one::<String>("PORT") {
-                let bc = Blockchain::new()?;
-                let utxo_set = UTXOSet { blockchain: bc };
-                let server = Server::new(port, "", utxo_set)?;
-                server.start_server()?;
+                params.set_port(port)?;
             }
+            let utxo_set = open_utxo_set(&params)?;
+            let server = Server::new(&params, "", utxo_set)?;
+            server.start_server()?;
+        }
+
+        if matches.subcommand_matches("startlight").is_some() {
+            cmd_start_light(&params, format)?;
         }
 
         if let Some(_) = matches.subcommand_matches("createwallet") {
-            println!("address: {}", cmd_create_wallet()?);
+            let address = cmd_create_wallet(&params)?;
+            match format {
+                Format::Text => println!("address: {}", address),
+                Format::Json => print_json(json!({"address": address}))?,
+            }
+        }
+        if let Some(matches) = matches.subcommand_matches("reindex") {
+            let tx_index = if matches.get_flag("txindex") {
+                Some(true)
+            } else if matches.get_flag("drop-txindex") {
+                Some(false)
+            } else {
+                None
+            };
+            let (count, indexed) = cmd_reindex(&params, tx_index)?;
+            match format {
+                Format::Text => {
+                    println!("Done! There are {} transactions in the UTXO set.", count);
+                    if let Some(indexed) = indexed {
+                        println!("Indexed {} transactions by id and address.", indexed);
+                    }
+                }
+                Format::Json => print_json(json!({"transactions": count, "indexed": indexed}))?,
+            }
         }
-        if let Some(_) = matches.subcommand_matches("reindex") {
-            let count = cmd_reindex()?;
-            println!("Done! There are {} transactions in the UTXO set.", count);
+
+        if let Some(_) = matches.subcommand_matches("listreorgs") {
+            cmd_list_reorgs(&params, format)?;
         }
 
         if let Some(_) = matches.subcommand_matches("listaddresses") {
-            cmd_list_address()?;
+            cmd_list_address(&params.data_dir, format)?;
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("create") {
-            if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                cmd_create_blockchain(address)?;
+        if let Some(ref matches) = matches.subcommand_matches("creategenesis") {
+            let mut genesis = params.genesis.clone();
+            if let Some(reward) = matches.get_one::<String>("reward") {
+                genesis.reward = reward.parse()?;
+            }
+            if let Some(halving) = matches.get_one::<String>("halving") {
+                genesis.halving_interval = halving.parse()?;
             }
+            if let Some(blocks) = matches.get_one::<String>("maturity") {
+                genesis.coinbase_maturity = blocks.parse()?;
+            }
+            if let Err(e) = genesis.validate() {
+                println!("{}", e);
+                exit(1)
+            }
+            let timestamp = match matches.get_one::<String>("timestamp") {
+                Some(ms) => ms.parse()?,
+                None => SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis(),
+            };
+            cmd_create_genesis(&mut genesis, timestamp, format)?;
+        }
 
+        if let Some(_) = matches.subcommand_matches("emission") {
+            cmd_emission(&params, format)?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("generate") {
+            let count: i32 = matches.get_one::<String>("COUNT").unwrap().parse()?;
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            let hashes = cmd_generate(&params, count, address)?;
+            match format {
+                Format::Text => hashes.iter().for_each(|hash| println!("{}", hash)),
+                Format::Json => print_json(json!(hashes))?,
+            }
         }
 
 
         if let Some(ref matches) = matches.subcommand_matches("getbalance") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                let balance = cmd_get_balance(address)?;
-                println!("Balance: {}\n", balance);
+                let (mature, immature) = cmd_get_balance(&params, address)?;
+                match format {
+                    Format::Text => {
+                        println!("Balance: {}", mature);
+                        println!("Immature: {}\n", immature);
+                    }
+                    Format::Json => {
+                        print_json(json!({"address": address, "balance": mature, "immature": immature}))?
+                    }
+                }
             }
         }
 
@@ -132,10 +260,19 @@ impl Cli {
                 exit(1)
             };
 
-            if matches.contains_id("mine") {
-                cmd_send(from, to, amount, true)?;
+            let fee = if let Some(rate) = matches.get_one::<String>("feerate") {
+                Fee::PerByte(rate.parse()?)
+            } else if let Some(fee) = matches.get_one::<String>("fee") {
+                Fee::Absolute(fee.parse()?)
             } else {
-                cmd_send(from, to, amount, false)?;
+                Fee::Absolute(0)
+            };
+
+            let mine_now = matches.get_flag("mine");
+            let txid = cmd_send(&params, from, to, amount, fee, mine_now)?;
+            match format {
+                Format::Text => println!("success!"),
+                Format::Json => print_json(json!({"txid": txid, "mined": mine_now}))?,
             }
 
 
@@ -144,84 +281,484 @@ impl Cli {
             }*/
         }
 
+        if let Some(matches) = matches.subcommand_matches("getblock") {
+            let block = cmd_get_block(&params, matches.get_one::<String>("BLOCK").unwrap())?;
+            match format {
+                Format::Text => println!("{:#?}", block),
+                Format::Json => print_json(block_json(&block, params.network, true)?)?,
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("gettx") {
+            let tx = cmd_get_tx(&params, matches.get_one::<String>("TXID").unwrap())?;
+            match format {
+                Format::Text => print_tx_details(&tx),
+                Format::Json => print_json(serde_json::to_value(tx)?)?,
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getmerkleproof") {
+            let proof = cmd_get_merkle_proof(&params, matches.get_one::<String>("TXID").unwrap())?;
+            match format {
+                Format::Text => println!("{}", merkle_proof_json(&proof)),
+                Format::Json => print_json(merkle_proof_json(&proof))?,
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("verifymerkleproof") {
+            let proof: Value = serde_json::from_str(matches.get_one::<String>("PROOF").unwrap())?;
+            let proof = merkle_proof_from_json(&proof)?;
+            let valid = cmd_verify_merkle_proof(&params, &proof)?;
+            match format {
+                Format::Text => println!(
+                    "{}: {} in block {}",
+                    if valid { "valid" } else { "invalid" },
+                    proof.txid,
+                    proof.block_hash
+                ),
+                Format::Json => print_json(json!({"txid": proof.txid, "block_hash": proof.block_hash, "valid": valid}))?,
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getaddresshistory") {
+            let history = cmd_get_address_history(&params, matches.get_one::<String>("ADDRESS").unwrap())?;
+            match format {
+                Format::Text => {
+                    for e in history {
+                        println!(
+                            "height: {:<8} {} received: {:<10} sent: {:<10} balance: {}",
+                            e.height, e.txid, e.received, e.sent, e.balance
+                        );
+                    }
+                }
+                Format::Json => print_json(serde_json::to_value(history)?)?,
+            }
+        }
+
         if let Some(_) = matches.subcommand_matches("printchain") {
-            cmd_print_chain()?;
+            cmd_print_chain(&params, format)?;
         }
 
         Ok(())
     }
 }
 
-fn cmd_send(from: &str, to: &str, amount: i32, mine_now: bool) -> Result<()> {
-    let bc = Blockchain::new()?;
-    let mut utxo_set = UTXOSet { blockchain: bc };
-    let wallets = Wallets::new()?;
+/// Format selects how commands print their results
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Text,
+    /// a single JSON document per command, for scripts
+    Json,
+}
+
+impl Format {
+    fn parse(name: &str) -> Result<Format> {
+        match name {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format_err!("unknown output format {}, expected text or json", name)),
+        }
+    }
+}
+
+/// print_json writes `value` to stdout as pretty printed JSON
+fn print_json(value: Value) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(&value)?);
+    Ok(())
+}
+
+/// load_params reads the network config and applies the command line overrides
+fn load_params(matches: &ArgMatches) -> Result<NetworkParams> {
+    let network = match matches.get_one::<String>("network") {
+        Some(name) => Some(Network::parse(name)?),
+        None => None,
+    };
+    let mut params = match matches.get_one::<String>("config") {
+        Some(path) => NetworkParams::load(Path::new(path), network)?,
+        None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+            NetworkParams::load(Path::new(DEFAULT_CONFIG_FILE), network)?
+        }
+        None => NetworkParams::for_network(network.unwrap_or_default()),
+    };
+    if let Some(dir) = matches.get_one::<String>("datadir") {
+        params.data_dir = PathBuf::from(dir);
+    }
+    if let Some(addr) = matches.get_one::<String>("bind") {
+        params.bind_addr = addr.clone();
+    }
+    if let Some(addr) = matches.get_one::<String>("advertise") {
+        params.advertised_addr = addr.clone();
+    }
+    if let Some(seeds) = matches.get_many::<String>("seed") {
+        params.seeds = seeds.cloned().collect();
+    }
+    if let Some(magic) = matches.get_one::<String>("magic") {
+        params.magic = parse_magic(magic)?;
+    }
+    if let Some(addr) = matches.get_one::<String>("rpcbind") {
+        params.rpc.bind_addr = addr.clone();
+    }
+    Ok(params)
+}
+
+/// open_utxo_set opens the chain of the network, creating it on first use, and
+/// rebuilds the UTXO set when it is missing
+fn open_utxo_set(params: &NetworkParams) -> Result<UTXOSet> {
+    let bc = Blockchain::new(&params.data_dir, &params.genesis)?;
+    let utxo_set = UTXOSet { blockchain: bc };
+    if utxo_set.count_transactions()? == 0 {
+        utxo_set.reindex()?;
+    }
+    Ok(utxo_set)
+}
+
+/// cmd_start_light follows the balance of every wallet of the network,
+/// printing it whenever it changes
+fn cmd_start_light(params: &NetworkParams, format: Format) -> Result<()> {
+    let wallets = Wallets::new(&params.data_dir)?;
+    let pub_key_hashes = wallets
+        .get_all_addresses()
+        .iter()
+        .filter_map(|address| params.network.decode_address(address).ok())
+        .collect();
+    let client = LightClient::open(params, pub_key_hashes)?;
+    client.start(|status| match format {
+        Format::Text => println!(
+            "height: {} scanned: {} balance: {} immature: {}",
+            status.height, status.scanned_height, status.balance, status.immature
+        ),
+        Format::Json => println!("{}", json!(status)),
+    })
+}
+
+/// cmd_send pays `amount` from `from` to `to`, returning the transaction id
+fn cmd_send(params: &NetworkParams, from: &str, to: &str, amount: i32, fee: Fee, mine_now: bool) -> Result<String> {
+    params.network.decode_address(from)?;
+    params.network.decode_address(to)?;
+    let wallets = Wallets::new(&params.data_dir)?;
     let wallet = wallets.get_wallet(from).unwrap();
-    let tx = Transaction::new_UTXO(wallet, to, amount, &utxo_set)?;
+    if let Some(node) = Client::connect(params)? {
+        if mine_now && params.network != Network::Regtest {
+            return Err(format_err!(
+                "a node is running at {}, send --mine is only available on regtest",
+                node.get_addr()
+            ));
+        }
+        let tx = Transaction::new_UTXO(wallet, to, amount, fee, &node)?;
+        node.send_raw_transaction(&tx)?;
+        if mine_now {
+            node.call("generate", vec![json!(1), json!(from)])?;
+        }
+        return Ok(tx.id);
+    }
+
+    let mut utxo_set = open_utxo_set(params)?;
+    let tx = Transaction::new_UTXO(wallet, to, amount, fee, &utxo_set)?;
+    let txid = tx.id.clone();
     if mine_now {
-        let cbtx = Transaction::new_coinbase(from.to_string(), String::from("reward!"))?;
+        let height = utxo_set.blockchain.get_best_height()? + 1;
+        let reward = utxo_set
+            .blockchain
+            .get_block_subsidy(height)
+            .checked_add(utxo_set.tx_fee(&tx)?)
+            .ok_or_else(|| format_err!("Block reward overflows"))?;
+        let cbtx =
+            Transaction::new_coinbase(from.to_string(), String::from("reward!"), height, reward)?;
         let new_block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
 
         utxo_set.update(&new_block)?;
     } else {
-        Server::send_transaction(&tx, utxo_set)?;
+        Server::send_transaction(params, &tx, utxo_set)?;
     }
-
-    println!("success!");
-    Ok(())
+    Ok(txid)
 }
 
-fn cmd_create_wallet() -> Result<String> {
-    let mut ws = Wallets::new()?;
-    let address = ws.create_wallet();
+fn cmd_create_wallet(params: &NetworkParams) -> Result<String> {
+    let mut ws = Wallets::new(&params.data_dir)?;
+    let address = ws.create_wallet(params.network);
     ws.save_all()?;
     Ok(address)
 }
 
-fn cmd_reindex() -> Result<i32> {
-    let bc = Blockchain::new()?;
+/// check_no_node fails when a node of this network is serving RPC, since it
+/// holds the chain database open
+fn check_no_node(params: &NetworkParams) -> Result<()> {
+    match Client::connect(params)? {
+        Some(node) => Err(format_err!(
+            "a node is running at {}, stop it before using this command",
+            node.get_addr()
+        )),
+        None => Ok(()),
+    }
+}
+
+/// cmd_reindex rebuilds the UTXO set and the transaction and address indexes
+/// when they are kept; `tx_index` turns the indexes on or off first. It
+/// returns the size of the UTXO set and the number of transactions indexed.
+fn cmd_reindex(params: &NetworkParams, tx_index: Option<bool>) -> Result<(i32, Option<usize>)> {
+    check_no_node(params)?;
+    let mut bc = Blockchain::new(&params.data_dir, &params.genesis)?;
+    if tx_index == Some(false) {
+        bc.drop_tx_index()?;
+    }
+    let indexed = if tx_index == Some(true) || bc.has_tx_index() {
+        Some(bc.rebuild_tx_index()?)
+    } else {
+        None
+    };
     let utxo_set = UTXOSet { blockchain: bc };
     utxo_set.reindex()?;
-    utxo_set.count_transactions()
+    Ok((utxo_set.count_transactions()?, indexed))
 }
 
-fn cmd_create_blockchain(address: &str) -> Result<()> {
-    let address = String::from(address);
-    let bc = Blockchain::create_blockchain(address)?;
+/// cmd_create_genesis mines a genesis block for `genesis` at `timestamp` and
+/// prints the parameters of the new network
+fn cmd_create_genesis(genesis: &mut GenesisParams, timestamp: u128, format: Format) -> Result<()> {
+    let block = Block::mine_genesis_block(genesis.coinbase()?, timestamp, genesis.pow_limit_bits)?;
+    genesis.timestamp = block.get_timestamp() as u64;
+    genesis.nonce = block.get_nonce();
+    genesis.hash = block.get_hash();
+    match format {
+        Format::Text => println!("[genesis]\n{}", toml::to_string(genesis)?),
+        Format::Json => print_json(json!({"genesis": genesis}))?,
+    }
+    Ok(())
+}
 
-    let utxo_set = UTXOSet { blockchain: bc };
-    utxo_set.reindex()?;
-    println!("create blockchain");
+fn cmd_emission(params: &NetworkParams, format: Format) -> Result<()> {
+    check_no_node(params)?;
+    let bc = Blockchain::new(&params.data_dir, &params.genesis)?;
+    let schedule = bc.get_subsidy_schedule();
+    let best_height = bc.get_best_height()?;
+    let mut eras = Vec::new();
+    let mut start = 0;
+    loop {
+        let reward = schedule.subsidy(start);
+        let end = start.saturating_add(schedule.halving_interval - 1);
+        eras.push((start, end, reward, schedule.supply_at(end)));
+        if reward == 0 {
+            break;
+        }
+        match start.checked_add(schedule.halving_interval) {
+            Some(next) => start = next,
+            None => break,
+        }
+    }
+
+    if format == Format::Json {
+        let eras: Vec<Value> = eras
+            .iter()
+            .map(|(start, end, reward, supply)| {
+                json!({"start_height": start, "end_height": end, "reward": reward, "supply": supply})
+            })
+            .collect();
+        return print_json(json!({
+            "initial_reward": schedule.initial,
+            "halving_interval": schedule.halving_interval,
+            "max_supply": schedule.max_supply(),
+            "coinbase_maturity": bc.get_coinbase_maturity(),
+            "pow_limit": format!("{:08x}", bc.get_pow_limit()),
+            "height": best_height,
+            "issued": bc.get_issued_supply(best_height),
+            "eras": eras,
+        }));
+    }
+    println!(
+        "initial reward: {} halving interval: {} max supply: {}",
+        schedule.initial,
+        schedule.halving_interval,
+        schedule.max_supply()
+    );
+    println!("coinbase maturity: {} blocks", bc.get_coinbase_maturity());
+    println!("pow limit: {:#010x}", bc.get_pow_limit());
+    println!("issued at height {}: {}", best_height, bc.get_issued_supply(best_height));
+    for (start, end, reward, supply) in eras {
+        println!("heights {:>8} - {:<8} reward: {:<10} supply: {}", start, end, reward, supply);
+    }
     Ok(())
 }
 
-fn cmd_get_balance(address: &str) -> Result<i32> {
-    let pub_key_hash = Address::decode(address).unwrap().body;
-    let bc = Blockchain::new()?;
-    let utxo_set = UTXOSet { blockchain: bc };
-    let utxos = utxo_set.find_UTXO(&pub_key_hash)?;
+fn cmd_get_balance(params: &NetworkParams, address: &str) -> Result<(i32, i32)> {
+    let pub_key_hash = params.network.decode_address(address)?;
+    if let Some(node) = Client::connect(params)? {
+        let balance = node.call("getbalance", vec![json!(address)])?;
+        let get = |key: &str| balance[key].as_i64().unwrap_or_default() as i32;
+        return Ok((get("balance"), get("immature")));
+    }
+    let utxo_set = open_utxo_set(params)?;
+    utxo_set.get_balance(&pub_key_hash)
+}
 
-    let mut balance = 0;
-    for out in utxos.outputs {
-        balance += out.value;
+/// cmd_generate mines `count` blocks paying `address` on a regtest chain,
+/// returning their hashes
+fn cmd_generate(params: &NetworkParams, count: i32, address: &str) -> Result<Vec<String>> {
+    if params.network != Network::Regtest {
+        return Err(format_err!("generate is only available on regtest"));
     }
-    Ok(balance)
+    params.network.decode_address(address)?;
+    if let Some(node) = Client::connect(params)? {
+        let hashes = node.call("generate", vec![json!(count), json!(address)])?;
+        return Ok(serde_json::from_value(hashes)?);
+    }
+    let mut utxo_set = open_utxo_set(params)?;
+    let mut hashes = Vec::new();
+    for _ in 0..count {
+        let height = utxo_set.blockchain.get_best_height()? + 1;
+        let reward = utxo_set.blockchain.get_block_subsidy(height);
+        let cbtx = Transaction::new_coinbase(address.to_string(), String::new(), height, reward)?;
+        let block = utxo_set.blockchain.mine_block(vec![cbtx])?;
+        utxo_set.update(&block)?;
+        hashes.push(block.get_hash());
+    }
+    Ok(hashes)
 }
 
-fn cmd_print_chain() -> Result<()> {
-    let bc = Blockchain::new()?;
-    for b in bc.iter() {
-        println!("{:#?}", b);
+/// cmd_print_chain prints every block from the tip back to the genesis block
+fn cmd_print_chain(params: &NetworkParams, format: Format) -> Result<()> {
+    let mut blocks = Vec::new();
+    let mut show = |block: Block| -> Result<()> {
+        match format {
+            Format::Text => println!("{:#?}", block),
+            Format::Json => blocks.push(block_json(&block, params.network, true)?),
+        }
+        Ok(())
+    };
+    if let Some(node) = Client::connect(params)? {
+        let best = node.call("getbestblockhash", Vec::new())?;
+        let mut hash = best.as_str().unwrap_or_default().to_string();
+        while !hash.is_empty() {
+            let block = node.get_block(&hash)?;
+            hash = block.get_prev_hash();
+            show(block)?;
+        }
+    } else {
+        let bc = Blockchain::new(&params.data_dir, &params.genesis)?;
+        for b in bc.iter() {
+            show(b?)?;
+        }
+    }
+    if format == Format::Json {
+        print_json(Value::Array(blocks))?;
     }
     Ok(())
 }
 
-fn cmd_list_address() -> Result<()> {
-    let ws = Wallets::new()?;
+/// cmd_get_block finds a block of the active chain by hash, or by height
+/// when given a number
+fn cmd_get_block(params: &NetworkParams, block: &str) -> Result<Block> {
+    // a 64 digit hash would parse as a number too
+    let height = block.parse::<i32>().ok().filter(|_| block.len() < 64);
+    if let Some(node) = Client::connect(params)? {
+        let hash = match height {
+            Some(height) => serde_json::from_value(node.call("getblockhash", vec![json!(height)])?)?,
+            None => block.to_string(),
+        };
+        return node.get_block(&hash);
+    }
+    let bc = Blockchain::new(&params.data_dir, &params.genesis)?;
+    let hash = match height {
+        Some(height) => bc.get_block_hash(height)?,
+        None => block.to_string(),
+    };
+    bc.get_block(&hash)
+}
+
+fn cmd_get_tx(params: &NetworkParams, txid: &str) -> Result<TxDetails> {
+    if let Some(node) = Client::connect(params)? {
+        return Ok(serde_json::from_value(node.call("gettransaction", vec![json!(txid)])?)?);
+    }
+    let bc = Blockchain::new(&params.data_dir, &params.genesis)?;
+    explorer::get_transaction(&bc, params.network, txid)?
+        .ok_or_else(|| format_err!("Transaction {} is not found", txid))
+}
+
+fn cmd_get_merkle_proof(params: &NetworkParams, txid: &str) -> Result<MerkleProof> {
+    if let Some(node) = Client::connect(params)? {
+        return node.get_merkle_proof(txid);
+    }
+    let bc = Blockchain::new(&params.data_dir, &params.genesis)?;
+    bc.get_merkle_proof(txid)?
+        .ok_or_else(|| format_err!("Transaction {} is not found", txid))
+}
+
+fn cmd_verify_merkle_proof(params: &NetworkParams, proof: &MerkleProof) -> Result<bool> {
+    if let Some(node) = Client::connect(params)? {
+        return node.verify_merkle_proof(proof);
+    }
+    let bc = Blockchain::new(&params.data_dir, &params.genesis)?;
+    bc.verify_merkle_proof(proof)
+}
+
+fn cmd_get_address_history(params: &NetworkParams, address: &str) -> Result<Vec<HistoryEntry>> {
+    let pub_key_hash = params.network.decode_address(address)?;
+    if let Some(node) = Client::connect(params)? {
+        return Ok(serde_json::from_value(node.call("getaddresshistory", vec![json!(address)])?)?);
+    }
+    let bc = Blockchain::new(&params.data_dir, &params.genesis)?;
+    explorer::get_address_history(&bc, &pub_key_hash)
+}
+
+fn print_tx_details(tx: &TxDetails) {
+    println!("txid: {}", tx.txid);
+    match (&tx.block_hash, tx.height) {
+        (Some(hash), Some(height)) => {
+            println!("block: {} height: {} confirmations: {}", hash, height, tx.confirmations)
+        }
+        _ => println!("block: none, in the mempool"),
+    }
+    if tx.coinbase {
+        println!("inputs: coinbase");
+    } else {
+        println!("inputs:");
+        for i in &tx.inputs {
+            println!("  {}:{} {} {}", i.txid, i.vout, i.address, i.value);
+        }
+    }
+    println!("outputs:");
+    for (n, o) in tx.outputs.iter().enumerate() {
+        println!("  {} {} {}", n, o.address, o.value);
+    }
+    println!("fee: {}", tx.fee);
+}
+
+fn cmd_list_address(data_dir: &Path, format: Format) -> Result<()> {
+    let ws = Wallets::new(data_dir)?;
     let addresses = ws.get_all_addresses();
+    if format == Format::Json {
+        return print_json(json!(addresses));
+    }
     println!("addresses: ");
     for ad in addresses {
         println!("{}", ad);
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+fn cmd_list_reorgs(params: &NetworkParams, format: Format) -> Result<()> {
+    check_no_node(params)?;
+    let bc = Blockchain::new(&params.data_dir, &params.genesis)?;
+    let reorgs = bc.get_reorgs()?;
+    if format == Format::Json {
+        return print_json(serde_json::to_value(reorgs)?);
+    }
+    for r in reorgs {
+        println!(
+            "{} fork height: {} depth: {} connected: {} old tip: {} new tip: {}",
+            r.timestamp, r.fork_height, r.depth, r.connected, r.old_tip, r.new_tip
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_parse() {
+        assert_eq!(Format::parse("text").unwrap(), Format::Text);
+        assert_eq!(Format::parse("json").unwrap(), Format::Json);
+        assert!(Format::parse("JSON").is_err());
+        assert!(Format::parse("").is_err());
+    }
+}
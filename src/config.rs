@@ -0,0 +1,508 @@
+//! configuration of the network a node runs on
+//!
+//! Every network (main, test or regtest) comes with its own profile of
+//! defaults. Parameters are read from a TOML file, every key being optional
+//! and overriding the defaults of the network the file names:
+//!
+//! ```toml
+//! network = "test"
+//! bind_addr = "0.0.0.0:4000"
+//! advertised_addr = "node1.example:4000"
+//! seeds = ["node0.example:4000"]
+//! magic = 0x0b110907
+//! data_dir = "data/mynet"
+//!
+//! [genesis]
+//! reward = 50
+//! coinbase_maturity = 100
+//! timestamp = 1700000000000
+//! nonce = 4242
+//! hash = "0000a3..."
+//!
+//! [rpc]
+//! bind_addr = "127.0.0.1:8332"
+//! user = "alice"
+//! password = "secret"
+//! ```
+//!
+//! Changing any genesis parameter makes a new network whose genesis block has
+//! to be mined once with `creategenesis`, which prints the timestamp, nonce and
+//! hash to put in the file.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bitcoincash_addr::{Address, HashType, Scheme};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+
+use crate::block::{Block, POW_LIMIT_BITS};
+use crate::blockchain::{DEFAULT_COINBASE_MATURITY, GENESIS_COINBASE_DATA};
+use crate::errors::Result;
+use crate::net::MAGIC;
+use crate::transaction::{SubsidySchedule, Transaction, DEFAULT_SUBSIDY};
+
+/// Config file read when no other file is given and it exists
+pub const DEFAULT_CONFIG_FILE: &str = "node.toml";
+
+/// Address the genesis reward is paid to
+///
+/// Its public key hash is all zeros, which no key hashes to, so the genesis
+/// output can never be spent.
+pub const GENESIS_ADDRESS: &str = "1111111111111111111114oLvT2";
+
+/// Timestamp, nonce and hash of the genesis block of each network
+const MAIN_GENESIS: (u64, u32, &str) = (
+    1231006505000,
+    197947,
+    "000064352628bf26c14a7c1a5f4b6cabb3b455de8b1e1cde1bd494f3f65a97b1",
+);
+const TEST_GENESIS: (u64, u32, &str) = (
+    1296688602000,
+    34734,
+    "000095134ec315903bba978eeed79771751f674286b9f6a3f5de9ea268d78cca",
+);
+const REGTEST_GENESIS: (u64, u32, &str) = (
+    1296688602000,
+    0,
+    "4008840bc32ef9113fbf624667552c1003d233eada56d05bd0909ade243e3c10",
+);
+
+/// Network names a profile of default parameters
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    #[default]
+    Main,
+    Test,
+    /// local network with trivial difficulty where blocks are mined on demand
+    Regtest,
+}
+
+impl Network {
+    pub fn parse(name: &str) -> Result<Network> {
+        match name {
+            "main" | "mainnet" => Ok(Network::Main),
+            "test" | "testnet" => Ok(Network::Test),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format_err!("unknown network {}", name)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Network::Main => "main",
+            Network::Test => "test",
+            Network::Regtest => "regtest",
+        }
+    }
+
+    /// AddressNetwork returns the network whose prefix marks addresses of
+    /// this network
+    pub fn address_network(&self) -> bitcoincash_addr::Network {
+        match self {
+            Network::Main => bitcoincash_addr::Network::Main,
+            Network::Test => bitcoincash_addr::Network::Test,
+            Network::Regtest => bitcoincash_addr::Network::Regtest,
+        }
+    }
+
+    /// AddressScheme returns how addresses of this network are encoded
+    ///
+    /// Base58 gives test and regtest addresses the same version byte, so both
+    /// use CashAddr, whose `bchtest:` and `bchreg:` prefixes tell them apart.
+    pub fn address_scheme(&self) -> Scheme {
+        match self {
+            Network::Main => Scheme::Base58,
+            Network::Test | Network::Regtest => Scheme::CashAddr,
+        }
+    }
+
+    /// EncodeAddress returns the address of a public key hash on this network
+    ///
+    /// CashAddr only encodes hashes of some lengths, so an output locked to
+    /// a hash of another length has no address on test and regtest.
+    pub fn encode_address(&self, pub_key_hash: Vec<u8>) -> Result<String> {
+        let len = pub_key_hash.len();
+        let address = Address {
+            body: pub_key_hash,
+            scheme: self.address_scheme(),
+            hash_type: HashType::Script,
+            network: self.address_network(),
+        };
+        address
+            .encode()
+            .map_err(|_| format_err!("a {} byte public key hash has no {} address", len, self.name()))
+    }
+
+    /// DecodeAddress returns the public key hash of an address, rejecting
+    /// addresses of other networks
+    pub fn decode_address(&self, address: &str) -> Result<Vec<u8>> {
+        let decoded =
+            Address::decode(address).map_err(|_| format_err!("invalid address {}", address))?;
+        if decoded.network != self.address_network() {
+            return Err(format_err!(
+                "address {} does not belong to the {} network",
+                address,
+                self.name()
+            ));
+        }
+        Ok(decoded.body)
+    }
+}
+
+/// NetworkParams describes the network a node joins and where it keeps its data
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkParams {
+    pub network: Network,
+    /// address the node listens on
+    pub bind_addr: String,
+    /// address other nodes reach this node at
+    pub advertised_addr: String,
+    /// nodes dialed when the address book is empty
+    pub seeds: Vec<String>,
+    /// identifier at the start of every message frame
+    pub magic: u32,
+    /// directory holding the chain, UTXO set, wallets and address book
+    pub data_dir: PathBuf,
+    pub genesis: GenesisParams,
+    pub rpc: RpcParams,
+}
+
+/// RpcParams configures the JSON-RPC endpoint of a node
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcParams {
+    /// loopback address the endpoint listens on, empty to turn it off
+    pub bind_addr: String,
+    /// credentials clients must send with basic auth, none asked when the
+    /// user is empty
+    pub user: String,
+    pub password: String,
+}
+
+impl RpcParams {
+    fn local(port: u16) -> RpcParams {
+        RpcParams {
+            bind_addr: format!("127.0.0.1:{}", port),
+            ..RpcParams::default()
+        }
+    }
+}
+
+/// GenesisParams are the consensus parameters fixed when a chain is created
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GenesisParams {
+    pub coinbase_data: String,
+    pub reward: i32,
+    pub halving_interval: i32,
+    pub coinbase_maturity: i32,
+    /// easiest target, in compact form, a block may be mined against
+    pub pow_limit_bits: u32,
+    /// whether the target follows the block rate
+    pub retarget: bool,
+    /// unix time of the genesis block, in milliseconds
+    pub timestamp: u64,
+    /// nonce the genesis block was mined with
+    pub nonce: u32,
+    /// hash every node checks its genesis block against
+    pub hash: String,
+}
+
+impl Default for NetworkParams {
+    fn default() -> Self {
+        NetworkParams::for_network(Network::Main)
+    }
+}
+
+impl Default for GenesisParams {
+    fn default() -> Self {
+        GenesisParams {
+            coinbase_data: String::from(GENESIS_COINBASE_DATA),
+            reward: DEFAULT_SUBSIDY.initial,
+            halving_interval: DEFAULT_SUBSIDY.halving_interval,
+            coinbase_maturity: DEFAULT_COINBASE_MATURITY,
+            pow_limit_bits: POW_LIMIT_BITS,
+            retarget: true,
+            timestamp: MAIN_GENESIS.0,
+            nonce: MAIN_GENESIS.1,
+            hash: String::from(MAIN_GENESIS.2),
+        }
+    }
+}
+
+impl GenesisParams {
+    pub fn subsidy(&self) -> SubsidySchedule {
+        SubsidySchedule {
+            initial: self.reward,
+            halving_interval: self.halving_interval,
+        }
+    }
+
+    /// Coinbase returns the only transaction of the genesis block
+    pub fn coinbase(&self) -> Result<Transaction> {
+        Transaction::new_coinbase(
+            String::from(GENESIS_ADDRESS),
+            self.coinbase_data.clone(),
+            0,
+            self.subsidy().subsidy(0),
+        )
+    }
+
+    /// Block rebuilds the genesis block, failing if it does not match `hash`
+    pub fn block(&self) -> Result<Block> {
+        Block::new_genesis_block(
+            self.coinbase()?,
+            self.timestamp as u128,
+            self.pow_limit_bits,
+            self.nonce,
+            &self.hash,
+        )
+    }
+
+    /// Validate rejects parameters no chain can be created with
+    pub fn validate(&self) -> Result<()> {
+        if self.reward < 0 || self.halving_interval <= 0 || self.coinbase_maturity < 0 {
+            return Err(format_err!(
+                "reward and maturity must not be negative and halving must be positive"
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl NetworkParams {
+    /// ForNetwork returns the default parameters of a network
+    pub fn for_network(network: Network) -> NetworkParams {
+        let local = |port: u16| format!("localhost:{}", port);
+        let data_dir = Path::new("data").join(network.name());
+        match network {
+            Network::Main => NetworkParams {
+                network,
+                bind_addr: local(3000),
+                advertised_addr: local(3000),
+                seeds: vec![local(3000)],
+                magic: u32::from_be_bytes(MAGIC),
+                data_dir,
+                genesis: GenesisParams::default(),
+                rpc: RpcParams::local(8332),
+            },
+            Network::Test => NetworkParams {
+                network,
+                bind_addr: local(13000),
+                advertised_addr: local(13000),
+                seeds: vec![local(13000)],
+                magic: 0x0b110907,
+                data_dir,
+                genesis: GenesisParams {
+                    coinbase_data: String::from("mini-btc testnet genesis"),
+                    timestamp: TEST_GENESIS.0,
+                    nonce: TEST_GENESIS.1,
+                    hash: String::from(TEST_GENESIS.2),
+                    ..GenesisParams::default()
+                },
+                rpc: RpcParams::local(18332),
+            },
+            Network::Regtest => NetworkParams {
+                network,
+                bind_addr: local(23000),
+                advertised_addr: local(23000),
+                seeds: Vec::new(),
+                magic: 0xdab5bffa,
+                data_dir,
+                genesis: GenesisParams {
+                    coinbase_data: String::from("mini-btc regtest genesis"),
+                    coinbase_maturity: 1,
+                    pow_limit_bits: 0x207fffff,
+                    retarget: false,
+                    timestamp: REGTEST_GENESIS.0,
+                    nonce: REGTEST_GENESIS.1,
+                    hash: String::from(REGTEST_GENESIS.2),
+                    ..GenesisParams::default()
+                },
+                rpc: RpcParams::local(18443),
+            },
+        }
+    }
+
+    /// Load reads parameters from a TOML file
+    ///
+    /// Missing keys take the defaults of `network` when given, else of the
+    /// network the file names, else of the main network.
+    pub fn load(path: &Path, network: Option<Network>) -> Result<NetworkParams> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format_err!("cannot read config {}: {}", path.display(), e))?;
+        NetworkParams::parse(&text, network)
+            .map_err(|e| format_err!("invalid config {}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str, network: Option<Network>) -> Result<NetworkParams> {
+        let mut table: toml::Table = toml::from_str(text)?;
+        let network = match (network, table.get("network")) {
+            (Some(network), _) => network,
+            (None, Some(name)) => Network::parse(name.as_str().unwrap_or_default())?,
+            (None, None) => Network::Main,
+        };
+        table.insert(String::from("network"), network.name().into());
+
+        let mut merged = toml::Table::try_from(NetworkParams::for_network(network))?;
+        for (key, value) in table {
+            match (merged.get_mut(&key), value) {
+                (Some(toml::Value::Table(defaults)), toml::Value::Table(values)) => {
+                    defaults.extend(values)
+                }
+                (_, value) => {
+                    merged.insert(key, value);
+                }
+            }
+        }
+        let params: NetworkParams = merged.try_into()?;
+        params.genesis.validate()?;
+        Ok(params)
+    }
+
+    pub fn magic_bytes(&self) -> [u8; 4] {
+        self.magic.to_be_bytes()
+    }
+
+    /// DataPath returns the location of a store inside the data directory
+    pub fn data_path(&self, name: &str) -> PathBuf {
+        self.data_dir.join(name)
+    }
+
+    /// SetPort replaces the port of the bind and advertised addresses
+    pub fn set_port(&mut self, port: &str) -> Result<()> {
+        if port.parse::<u16>().is_err() {
+            return Err(format_err!("invalid port {}", port));
+        }
+        self.bind_addr = with_port(&self.bind_addr, port);
+        self.advertised_addr = with_port(&self.advertised_addr, port);
+        Ok(())
+    }
+}
+
+/// parse_magic reads network magic written as hex, with or without 0x
+pub fn parse_magic(text: &str) -> Result<u32> {
+    let digits = text.trim_start_matches("0x");
+    u32::from_str_radix(digits, 16).map_err(|_| format_err!("invalid magic {}", text))
+}
+
+fn with_port(addr: &str, port: &str) -> String {
+    let host = match addr.rsplit_once(':') {
+        Some((host, _)) => host,
+        None => addr,
+    };
+    format!("{}:{}", host, port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallets::Wallet;
+
+    #[test]
+    fn test_parse_config() {
+        let params = NetworkParams::parse(
+            r#"
+            advertised_addr = "node1.example:4000"
+            seeds = ["node0.example:4000", "node2.example:4000"]
+            magic = 0x0b110907
+            data_dir = "data/test"
+
+            [genesis]
+            coinbase_maturity = 3
+            "#,
+            None,
+        )
+        .unwrap();
+        assert_eq!(params.advertised_addr, "node1.example:4000");
+        assert_eq!(params.bind_addr, NetworkParams::default().bind_addr);
+        assert_eq!(params.seeds.len(), 2);
+        assert_eq!(params.magic_bytes(), [0x0b, 0x11, 0x09, 0x07]);
+        assert_eq!(params.data_path("blocks"), PathBuf::from("data/test/blocks"));
+        assert_eq!(params.genesis.coinbase_maturity, 3);
+        assert_eq!(params.genesis.subsidy(), DEFAULT_SUBSIDY);
+
+        assert!(NetworkParams::parse("bind = \"localhost:1\"", None).is_err());
+        assert!(NetworkParams::parse("[genesis]\nhalving_interval = 0", None).is_err());
+        assert!(NetworkParams::parse("network = \"moon\"", None).is_err());
+        assert_eq!(NetworkParams::parse("", None).unwrap(), NetworkParams::default());
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut params = NetworkParams {
+            bind_addr: String::from("0.0.0.0:3000"),
+            ..NetworkParams::default()
+        };
+        params.set_port("3005").unwrap();
+        assert_eq!(params.bind_addr, "0.0.0.0:3005");
+        assert_eq!(params.advertised_addr, "localhost:3005");
+        assert!(params.set_port("http").is_err());
+
+        assert_eq!(parse_magic("0xfabfb5da").unwrap(), u32::from_be_bytes(MAGIC));
+        assert_eq!(parse_magic("0b110907").unwrap(), 0x0b110907);
+        assert!(parse_magic("magic").is_err());
+    }
+
+    #[test]
+    fn test_network_profiles() {
+        let regtest = NetworkParams::parse("[genesis]\nreward = 7", Some(Network::Regtest)).unwrap();
+        assert_eq!(regtest.data_dir, PathBuf::from("data/regtest"));
+        assert_eq!(regtest.genesis.reward, 7);
+        assert!(!regtest.genesis.retarget);
+        let rpc = NetworkParams::parse("[rpc]\nuser = \"u\"", Some(Network::Regtest)).unwrap().rpc;
+        assert_eq!((rpc.bind_addr.as_str(), rpc.user.as_str()), ("127.0.0.1:18443", "u"));
+
+        // the network named in the file picks the defaults, the command line wins
+        let text = "network = \"test\"\nbind_addr = \"0.0.0.0:1\"";
+        let test = NetworkParams::parse(text, None).unwrap();
+        assert_eq!(test.network, Network::Test);
+        assert_eq!(test.magic, NetworkParams::for_network(Network::Test).magic);
+        assert_eq!(test.bind_addr, "0.0.0.0:1");
+        let main = NetworkParams::parse(text, Some(Network::Main)).unwrap();
+        assert_eq!(main.data_dir, PathBuf::from("data/main"));
+
+        let networks = [Network::Main, Network::Test, Network::Regtest];
+        for a in networks {
+            for b in networks {
+                let (pa, pb) = (NetworkParams::for_network(a), NetworkParams::for_network(b));
+                assert_eq!(a == b, pa.magic == pb.magic);
+                assert_eq!(a == b, pa.genesis == pb.genesis);
+            }
+        }
+    }
+
+    #[test]
+    fn test_genesis_blocks() {
+        for network in [Network::Main, Network::Test, Network::Regtest] {
+            let genesis = NetworkParams::for_network(network).genesis;
+            let block = genesis.block().unwrap();
+            assert_eq!(block.get_hash(), genesis.hash);
+            assert_eq!(block.get_timestamp(), genesis.timestamp as u128);
+        }
+        assert_eq!(Network::Main.decode_address(GENESIS_ADDRESS).unwrap(), vec![0; 20]);
+
+        // other parameters make another block, which has to be mined anew
+        let genesis = GenesisParams {
+            reward: 1,
+            ..GenesisParams::default()
+        };
+        assert!(genesis.block().is_err());
+    }
+
+    #[test]
+    fn test_address_networks() {
+        let wallet = Wallet::new();
+        let networks = [Network::Main, Network::Test, Network::Regtest];
+        for network in networks {
+            let address = wallet.get_network_address(network);
+            for other in networks {
+                assert_eq!(other == network, other.decode_address(&address).is_ok());
+            }
+        }
+        assert!(Network::Main.decode_address("nonsense").is_err());
+    }
+}
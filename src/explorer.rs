@@ -0,0 +1,253 @@
+//! explorer queries over the active chain: transactions with their inputs
+//! resolved and the history of an address
+
+use std::collections::HashMap;
+
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::{Blockchain, TxLocation};
+use crate::config::Network;
+use crate::errors::Result;
+use crate::transaction::{hash_pub_key, Transaction};
+use crate::tx::OutPoint;
+
+/// InputDetails is a transaction input with the output it spends resolved
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InputDetails {
+    pub txid: String,
+    pub vout: i32,
+    pub address: String,
+    pub value: i32,
+}
+
+/// OutputDetails is a transaction output with its address decoded
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OutputDetails {
+    pub value: i32,
+    pub address: String,
+}
+
+/// TxDetails describes a transaction and where it sits in the chain
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TxDetails {
+    pub txid: String,
+    /// hash of the block holding the transaction, None while in the mempool
+    pub block_hash: Option<String>,
+    pub height: Option<i32>,
+    pub confirmations: i32,
+    pub coinbase: bool,
+    /// empty for a coinbase
+    pub inputs: Vec<InputDetails>,
+    pub outputs: Vec<OutputDetails>,
+    pub fee: i32,
+}
+
+/// HistoryEntry is a confirmed transaction touching an address
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub txid: String,
+    pub block_hash: String,
+    pub height: i32,
+    /// value of the outputs paying the address
+    pub received: i32,
+    /// value of the address's outputs spent by the transaction
+    pub sent: i32,
+    /// balance of the address once the transaction confirmed
+    pub balance: i64,
+}
+
+/// get_transaction finds a transaction of the active chain by id
+pub fn get_transaction(bc: &Blockchain, network: Network, txid: &str) -> Result<Option<TxDetails>> {
+    match bc.locate_transaction(txid)? {
+        Some((tx, location)) => {
+            let location = (location.block_hash, location.height);
+            describe_transaction(bc, network, &tx, Some(location)).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+/// describe_transaction resolves the inputs of `tx` against the chain; the
+/// location is the hash and height of its block, None for a mempool transaction
+pub fn describe_transaction(
+    bc: &Blockchain,
+    network: Network,
+    tx: &Transaction,
+    location: Option<(String, i32)>,
+) -> Result<TxDetails> {
+    let mut inputs = Vec::new();
+    if !tx.is_coinbase() {
+        for vin in &tx.vin {
+            let prev = bc.find_transacton(&vin.txid)?;
+            let output = usize::try_from(vin.vout)
+                .ok()
+                .and_then(|vout| prev.vout.get(vout))
+                .ok_or_else(|| format_err!("output {}:{} does not exist", vin.txid, vin.vout))?;
+            inputs.push(InputDetails {
+                txid: vin.txid.clone(),
+                vout: vin.vout,
+                address: network.encode_address(output.pub_key_hash.clone())?,
+                value: output.value,
+            });
+        }
+    }
+    let outputs = tx
+        .vout
+        .iter()
+        .map(|out| {
+            Ok(OutputDetails {
+                value: out.value,
+                address: network.encode_address(out.pub_key_hash.clone())?,
+            })
+        })
+        .collect::<Result<Vec<OutputDetails>>>()?;
+    let fee = if tx.is_coinbase() {
+        0
+    } else {
+        inputs.iter().map(|i| i.value).sum::<i32>() - outputs.iter().map(|o| o.value).sum::<i32>()
+    };
+    let confirmations = match location {
+        Some((_, height)) => bc.get_best_height()? - height + 1,
+        None => 0,
+    };
+    let (block_hash, height) = location.unzip();
+    Ok(TxDetails {
+        txid: tx.id.clone(),
+        block_hash,
+        height,
+        confirmations,
+        coinbase: tx.is_coinbase(),
+        inputs,
+        outputs,
+        fee,
+    })
+}
+
+/// get_address_history lists the confirmed transactions paying or spending
+/// from `pub_key_hash`, oldest first, with the running balance
+///
+/// Only the transactions of the address are read when the address index is
+/// kept, otherwise the whole chain is.
+pub fn get_address_history(bc: &Blockchain, pub_key_hash: &[u8]) -> Result<Vec<HistoryEntry>> {
+    let mut txs: Vec<(Transaction, TxLocation)> = Vec::new();
+    match bc.get_address_txids(pub_key_hash)? {
+        Some(txids) => {
+            for txid in txids {
+                let found = bc.locate_transaction(&txid)?;
+                txs.push(found.ok_or_else(|| format_err!("indexed transaction {} is not found", txid))?);
+            }
+        }
+        None => {
+            for block in bc.iter_range(..) {
+                let block = block?;
+                for (position, tx) in block.get_transaction().iter().enumerate() {
+                    let location = TxLocation {
+                        block_hash: block.get_hash(),
+                        height: block.get_height(),
+                        position: position as u32,
+                    };
+                    txs.push((tx.clone(), location));
+                }
+            }
+        }
+    }
+
+    let mut owned: HashMap<OutPoint, i32> = HashMap::new();
+    let mut balance = 0i64;
+    let mut history = Vec::new();
+    for (tx, location) in txs {
+        let mut sent = 0;
+        if !tx.is_coinbase() {
+            for vin in &tx.vin {
+                let mut spender = vin.pub_key.clone();
+                hash_pub_key(&mut spender);
+                if spender != pub_key_hash {
+                    continue;
+                }
+                if let Some(value) = owned.remove(&OutPoint::new(&vin.txid, vin.vout)) {
+                    sent += value;
+                }
+            }
+        }
+        let mut received = 0;
+        for (index, out) in tx.vout.iter().enumerate() {
+            if out.is_locked_with_key(pub_key_hash) {
+                owned.insert(OutPoint::new(&tx.id, index as i32), out.value);
+                received += out.value;
+            }
+        }
+        if sent == 0 && received == 0 {
+            continue;
+        }
+        balance += received as i64 - sent as i64;
+        history.push(HistoryEntry {
+            txid: tx.id.clone(),
+            block_hash: location.block_hash,
+            height: location.height,
+            received,
+            sent,
+            balance,
+        });
+    }
+    Ok(history)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TestChain;
+    use crate::transaction::Fee;
+    use crate::wallets::Wallet;
+
+    #[test]
+    fn test_explorer_queries() {
+        let chain = TestChain::new("explorer");
+        let mut utxo = chain.utxo;
+        let alice = Wallet::new();
+        let bob = Wallet::new().get_network_address(Network::Regtest);
+        let subsidy = utxo.blockchain.get_block_subsidy(1);
+
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::new(), 1, subsidy).unwrap();
+        let block = utxo.blockchain.mine_block(vec![cbtx]).unwrap();
+        utxo.update(&block).unwrap();
+        let tx = Transaction::new_UTXO(&alice, &bob, 30, Fee::Absolute(2), &utxo).unwrap();
+        let reward = utxo.blockchain.get_block_subsidy(2) + 2;
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::new(), 2, reward).unwrap();
+        let block = utxo.blockchain.mine_block(vec![cbtx, tx.clone()]).unwrap();
+        utxo.update(&block).unwrap();
+
+        let details = get_transaction(&utxo.blockchain, Network::Regtest, &tx.id).unwrap().unwrap();
+        assert_eq!(details.block_hash, Some(block.get_hash()));
+        assert_eq!((details.height, details.confirmations, details.fee), (Some(2), 1, 2));
+        assert_eq!(details.inputs[0].value, subsidy);
+        assert_eq!(details.inputs[0].address, alice.get_network_address(Network::Regtest));
+        assert_eq!(details.outputs[0], OutputDetails { value: 30, address: bob });
+        assert!(get_transaction(&utxo.blockchain, Network::Regtest, "missing").unwrap().is_none());
+        let mut spend = tx.clone();
+        spend.vin[0].vout = 5;
+        assert!(describe_transaction(&utxo.blockchain, Network::Regtest, &spend, None).is_err());
+        spend.vin[0].vout = -1;
+        assert!(describe_transaction(&utxo.blockchain, Network::Regtest, &spend, None).is_err());
+
+        let mut pub_key_hash = alice.public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        let history = get_address_history(&utxo.blockchain, &pub_key_hash).unwrap();
+        let balances: Vec<(i32, i32, i64)> = history.iter().map(|e| (e.received, e.sent, e.balance)).collect();
+        let change = subsidy - 32;
+        assert_eq!(
+            balances,
+            vec![
+                (subsidy, 0, subsidy as i64),
+                (reward, 0, (subsidy + reward) as i64),
+                (change, subsidy, (change + reward) as i64),
+            ]
+        );
+
+        // the address index gives the same history
+        utxo.blockchain.rebuild_tx_index().unwrap();
+        assert_eq!(get_address_history(&utxo.blockchain, &pub_key_hash).unwrap(), history);
+        let indexed = get_transaction(&utxo.blockchain, Network::Regtest, &tx.id).unwrap().unwrap();
+        assert_eq!(indexed, details);
+    }
+}
@@ -0,0 +1,601 @@
+//! light client: follows the best header chain and only the transactions of
+//! its wallet, each proven against its header by a merkle proof
+//!
+//! No block body or UTXO set is kept. The client registers the public key
+//! hashes of its wallet with a full node through a filterload message and asks
+//! for the blocks of the best header chain as merkleblock messages, which
+//! carry only the matching transactions along with their merkle proofs.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{BufReader, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use bincode::{deserialize, serialize};
+use failure::format_err;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::block::{Block, BlockHeader, MerkleProof};
+use crate::blockchain::Blockchain;
+use crate::config::NetworkParams;
+use crate::errors::Result;
+use crate::net::{encode_frame, read_frame};
+use crate::server::{
+    decode_message, FilterLoadmsg, GetDatamsg, GetHeadersmsg, Message, Versionmsg, MAX_HEADERS_PER_MSG, VERSION,
+};
+use crate::transaction::Transaction;
+use crate::tx::OutPoint;
+
+/// Service bits of a light client, which serves nothing
+const NODE_NONE: u64 = 0;
+/// Number of merkle blocks asked for at a time
+const MAX_BLOCKS_IN_FLIGHT: usize = 500;
+/// How often the peer is asked for new headers when it announces none
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+/// How long to wait before dialing the seeds again once every one failed
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// FilteredBlock is the header of a block with the transactions matching a
+/// light client's filter, each with the merkle proof tying it to the header
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FilteredBlock {
+    pub header: BlockHeader,
+    pub transactions: Vec<Transaction>,
+    /// proof of each transaction, in the same order
+    pub proofs: Vec<MerkleProof>,
+}
+
+impl FilteredBlock {
+    /// new keeps the transactions of `block` paying or spending from one of
+    /// the public key hashes of `filter`
+    pub fn new(block: &Block, filter: &HashSet<Vec<u8>>) -> Result<FilteredBlock> {
+        let mut transactions = Vec::new();
+        let mut proofs = Vec::new();
+        for tx in block.get_transaction() {
+            if !tx.get_pub_key_hashes().iter().any(|pkh| filter.contains(pkh)) {
+                continue;
+            }
+            let proof = block
+                .merkle_proof(&tx.id)?
+                .ok_or_else(|| format_err!("no merkle proof for transaction {}", tx.id))?;
+            transactions.push(tx.clone());
+            proofs.push(proof);
+        }
+        Ok(FilteredBlock {
+            header: block.get_header().clone(),
+            transactions,
+            proofs,
+        })
+    }
+}
+
+/// LightStatus is the balance of the wallet at the best header
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LightStatus {
+    pub height: i32,
+    pub best_hash: String,
+    /// height of the most recent block scanned for the wallet's transactions
+    pub scanned_height: i32,
+    pub balance: i32,
+    /// value of coinbase outputs that cannot be spent yet
+    pub immature: i32,
+}
+
+/// WalletTx is a transaction of the wallet and the block it was proven in
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct WalletTx {
+    tx: Transaction,
+    block_hash: String,
+    height: i32,
+}
+
+pub struct LightClient {
+    params: NetworkParams,
+    /// header chain; past the genesis block no block body is stored
+    headers: Blockchain,
+    /// wallet transactions found so far and the blocks already scanned
+    db: sled::Db,
+    /// public key hashes of the wallet, sorted
+    watched: Vec<Vec<u8>>,
+}
+
+impl LightClient {
+    /// open loads the light client kept in the "light" directory of the data
+    /// directory, following the outputs of `pub_key_hashes`
+    ///
+    /// When the followed keys changed since the last run, the transactions
+    /// found so far are dropped and every block is scanned again.
+    pub fn open(params: &NetworkParams, mut pub_key_hashes: Vec<Vec<u8>>) -> Result<LightClient> {
+        let dir = params.data_path("light");
+        let headers = Blockchain::new(&dir, &params.genesis)?;
+        let db = sled::open(dir.join("wallet"))?;
+        pub_key_hashes.sort();
+        pub_key_hashes.dedup();
+        let watched = serialize(&pub_key_hashes)?;
+        if db.get("WATCHED")?.as_deref() != Some(&watched[..]) {
+            info!("followed keys changed, scanning every block again");
+            db.open_tree("txs")?.clear()?;
+            db.open_tree("scanned")?.clear()?;
+            db.remove("SCANNEDTO")?;
+            db.insert("WATCHED", watched)?;
+        }
+        Ok(LightClient {
+            params: params.clone(),
+            headers,
+            db,
+            watched: pub_key_hashes,
+        })
+    }
+
+    /// get_best_header returns the tip of the header chain with the most work
+    pub fn get_best_header(&self) -> Result<BlockHeader> {
+        match self.headers.get_best_header()? {
+            Some(best) => Ok(best.header),
+            None => self.headers.get_header(&self.headers.get_tip_hash()),
+        }
+    }
+
+    /// add_headers validates headers and adds them to the header chain,
+    /// returning how many were new
+    ///
+    /// Consensus failures are returned as a BlockError.
+    pub fn add_headers(&self, headers: &[BlockHeader]) -> Result<usize> {
+        let mut added = 0;
+        for header in headers {
+            if self.headers.add_header(header)? {
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    /// get_unscanned returns, oldest first, up to `max` blocks of the best
+    /// header chain not yet scanned for the wallet's transactions
+    ///
+    /// The best header chain is followed forward from the scan cursor, so
+    /// only the blocks past it are looked at.
+    pub fn get_unscanned(&self, max: usize) -> Result<Vec<String>> {
+        let scanned = self.db.open_tree("scanned")?;
+        let mut unscanned = Vec::new();
+        let mut height = self.scan_cursor()? + 1;
+        while unscanned.len() < max {
+            let hash = match self.headers.get_best_header_hash(height)? {
+                Some(hash) => hash,
+                None => break,
+            };
+            if !scanned.contains_key(&hash)? {
+                unscanned.push(hash);
+            }
+            height += 1;
+        }
+        Ok(unscanned)
+    }
+
+    /// scan_cursor returns the height up to which every block of the best
+    /// header chain was scanned
+    ///
+    /// The cursor is kept as the last block of a fully scanned chain. It
+    /// falls back to where that chain leaves the best header chain, then
+    /// moves forward over the blocks scanned since, so each call only looks
+    /// at the blocks it moves over.
+    fn scan_cursor(&self) -> Result<i32> {
+        let stored: Option<(i32, String)> = match self.db.get("SCANNEDTO")? {
+            Some(data) => Some(deserialize(&data)?),
+            None => None,
+        };
+        // the genesis block pays no wallet
+        let (mut height, mut hash) = match stored.clone() {
+            Some(cursor) => cursor,
+            None => (0, self.headers.get_header_by_height(0)?.hash),
+        };
+        while self.headers.get_best_header_hash(height)?.as_deref() != Some(&hash) {
+            let header = self.headers.get_header(&hash)?;
+            height = header.height - 1;
+            hash = header.prev_block_hash;
+        }
+        let scanned = self.db.open_tree("scanned")?;
+        while let Some(next) = self.headers.get_best_header_hash(height + 1)? {
+            if !scanned.contains_key(&next)? {
+                break;
+            }
+            height += 1;
+            hash = next;
+        }
+        if stored.as_ref() != Some(&(height, hash.clone())) {
+            self.db.insert("SCANNEDTO", serialize(&(height, hash))?)?;
+        }
+        Ok(height)
+    }
+
+    /// add_filtered_block checks the transactions of a filtered block against
+    /// its header and keeps those of the wallet, returning how many were kept
+    ///
+    /// The header must already be in the header chain. A transaction that
+    /// does not hash to its id or whose merkle proof does not lead to the
+    /// header's merkle root fails the whole block.
+    pub fn add_filtered_block(&self, block: &FilteredBlock) -> Result<usize> {
+        let header = self.headers.get_header(&block.header.hash)?;
+        if header != block.header {
+            return Err(format_err!("filtered block {} does not match its header", header.hash));
+        }
+        if block.transactions.len() != block.proofs.len() {
+            return Err(format_err!("filtered block {} lacks merkle proofs", header.hash));
+        }
+        for (tx, proof) in block.transactions.iter().zip(&block.proofs) {
+            if tx.id != tx.hash()? || proof.txid != tx.id || !proof.verify(&header)? {
+                return Err(format_err!(
+                    "transaction {} is not proven to be in block {}",
+                    tx.id,
+                    header.hash
+                ));
+            }
+        }
+
+        let txs = self.db.open_tree("txs")?;
+        let mut kept = 0;
+        for tx in &block.transactions {
+            if !tx.get_pub_key_hashes().iter().any(|pkh| self.watched.contains(pkh)) {
+                continue;
+            }
+            let entry = WalletTx {
+                tx: tx.clone(),
+                block_hash: header.hash.clone(),
+                height: header.height,
+            };
+            txs.insert(format!("{}{}", header.hash, tx.id), serialize(&entry)?)?;
+            kept += 1;
+        }
+        self.db.open_tree("scanned")?.insert(&header.hash, vec![])?;
+        Ok(kept)
+    }
+
+    /// get_status sums the unspent outputs of the wallet found in the blocks
+    /// of the best header chain
+    pub fn get_status(&self) -> Result<LightStatus> {
+        let best = self.get_best_header()?;
+        let mut wallet_txs = Vec::new();
+        for item in self.db.open_tree("txs")?.iter() {
+            let entry: WalletTx = deserialize(&item?.1)?;
+            // transactions of blocks that left the best chain are ignored
+            if self.headers.get_best_header_hash(entry.height)?.as_deref() == Some(&entry.block_hash) {
+                wallet_txs.push(entry);
+            }
+        }
+
+        let mut spent = HashSet::new();
+        for entry in wallet_txs.iter().filter(|e| !e.tx.is_coinbase()) {
+            for vin in &entry.tx.vin {
+                spent.insert(OutPoint::new(&vin.txid, vin.vout));
+            }
+        }
+        let mut unspent: HashMap<OutPoint, (i32, bool, i32)> = HashMap::new();
+        for entry in &wallet_txs {
+            for (index, out) in entry.tx.vout.iter().enumerate() {
+                let outpoint = OutPoint::new(&entry.tx.id, index as i32);
+                if !spent.contains(&outpoint) && self.watched.iter().any(|pkh| out.is_locked_with_key(pkh)) {
+                    unspent.insert(outpoint, (out.value, entry.tx.is_coinbase(), entry.height));
+                }
+            }
+        }
+
+        let mut balance = 0;
+        let mut immature = 0;
+        for (value, coinbase, height) in unspent.into_values() {
+            if coinbase && !self.headers.is_mature(height, best.height + 1) {
+                immature += value;
+            } else {
+                balance += value;
+            }
+        }
+        Ok(LightStatus {
+            height: best.height,
+            best_hash: best.hash,
+            scanned_height: self.scan_cursor()?,
+            balance,
+            immature,
+        })
+    }
+
+    /// start follows the seed nodes one at a time, calling `on_update` with
+    /// the wallet's status whenever it changes
+    ///
+    /// It only returns when no seed node is configured.
+    pub fn start(&self, mut on_update: impl FnMut(&LightStatus)) -> Result<()> {
+        if self.params.seeds.is_empty() {
+            return Err(format_err!("a light client needs a --seed node to follow"));
+        }
+        let mut last = None;
+        loop {
+            for seed in &self.params.seeds {
+                match TcpStream::connect(seed) {
+                    Ok(stream) => {
+                        info!("following {}", seed);
+                        if let Err(e) = self.follow(stream, &mut last, &mut on_update) {
+                            warn!("connection with {} failed: {}", seed, e);
+                        }
+                    }
+                    Err(e) => info!("connecting to {} failed: {}", seed, e),
+                }
+            }
+            thread::sleep(RECONNECT_DELAY);
+        }
+    }
+
+    /// follow syncs with one full node until the connection closes
+    fn follow(
+        &self,
+        stream: TcpStream,
+        last: &mut Option<LightStatus>,
+        on_update: &mut impl FnMut(&LightStatus),
+    ) -> Result<()> {
+        // the full node answers through this connection, so the local address
+        // is announced; nothing listens there
+        let addr_from = stream.local_addr()?.to_string();
+        let writer = Mutex::new(stream.try_clone()?);
+        let mut reader = BufReader::new(stream);
+
+        self.send(&writer, "version", &Versionmsg {
+            addr_from: addr_from.clone(),
+            version: VERSION,
+            services: NODE_NONE,
+            best_height: self.get_best_header()?.height,
+        })?;
+        self.send(&writer, "filterload", &FilterLoadmsg {
+            addr_from: addr_from.clone(),
+            pub_key_hashes: self.watched.clone(),
+        })?;
+        self.send_get_headers(&writer, &addr_from)?;
+        let mut requested = HashSet::new();
+        self.request_unscanned(&writer, &addr_from, &mut requested)?;
+        self.report(last, on_update)?;
+
+        let done = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let refresh = scope.spawn(|| loop {
+                thread::park_timeout(REFRESH_INTERVAL);
+                if done.load(Ordering::Relaxed) || self.send_get_headers(&writer, &addr_from).is_err() {
+                    break;
+                }
+            });
+            let result = self.read_messages(&mut reader, &writer, &addr_from, &mut requested, last, on_update);
+            done.store(true, Ordering::Relaxed);
+            refresh.thread().unpark();
+            if let Ok(stream) = writer.lock() {
+                stream.shutdown(std::net::Shutdown::Both).ok();
+            }
+            result
+        })
+    }
+
+    fn read_messages(
+        &self,
+        reader: &mut BufReader<TcpStream>,
+        writer: &Mutex<TcpStream>,
+        addr_from: &str,
+        requested: &mut HashSet<String>,
+        last: &mut Option<LightStatus>,
+        on_update: &mut impl FnMut(&LightStatus),
+    ) -> Result<()> {
+        while let Some(frame) = read_frame(reader, self.params.magic_bytes())? {
+            match decode_message(&frame.command, &frame.payload)? {
+                Message::Headers(msg) => {
+                    info!("receive {} headers from {}", msg.headers.len(), msg.addr_from);
+                    let added = self.add_headers(&msg.headers)?;
+                    if msg.headers.len() == MAX_HEADERS_PER_MSG && added > 0 {
+                        self.send_get_headers(writer, addr_from)?;
+                    }
+                    self.request_unscanned(writer, addr_from, requested)?;
+                    self.report(last, on_update)?;
+                }
+                Message::Inv(msg) if msg.kind == "block" => self.send_get_headers(writer, addr_from)?,
+                Message::MerkleBlock(msg) => {
+                    let kept = self.add_filtered_block(&msg.block)?;
+                    info!("block {} has {} wallet transactions", msg.block.header.hash, kept);
+                    requested.remove(&msg.block.header.hash);
+                    if requested.is_empty() {
+                        self.request_unscanned(writer, addr_from, requested)?;
+                    }
+                    self.report(last, on_update)?;
+                }
+                // a light client serves no headers, blocks or transactions
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn send_get_headers(&self, writer: &Mutex<TcpStream>, addr_from: &str) -> Result<()> {
+        self.send(writer, "getheaders", &GetHeadersmsg {
+            addr_from: addr_from.to_string(),
+            locator: self.headers.get_block_locator()?,
+            stop_hash: String::new(),
+        })
+    }
+
+    /// request_unscanned asks for the merkle blocks of the best header chain
+    /// not scanned yet and not already asked for
+    fn request_unscanned(
+        &self,
+        writer: &Mutex<TcpStream>,
+        addr_from: &str,
+        requested: &mut HashSet<String>,
+    ) -> Result<()> {
+        for hash in self.get_unscanned(MAX_BLOCKS_IN_FLIGHT)? {
+            if requested.insert(hash.clone()) {
+                self.send(writer, "getdata", &GetDatamsg {
+                    addr_from: addr_from.to_string(),
+                    kind: String::from("merkleblock"),
+                    id: hash,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn send<T: Serialize>(&self, writer: &Mutex<TcpStream>, command: &str, msg: &T) -> Result<()> {
+        let frame = encode_frame(self.params.magic_bytes(), command, &serialize(msg)?)?;
+        writer.lock().unwrap().write_all(&frame)?;
+        Ok(())
+    }
+
+    /// report calls `on_update` when the status changed since the last call
+    fn report(&self, last: &mut Option<LightStatus>, on_update: &mut impl FnMut(&LightStatus)) -> Result<()> {
+        let status = self.get_status()?;
+        if last.as_ref() != Some(&status) {
+            on_update(&status);
+            *last = Some(status);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Network;
+    use crate::server::Server;
+    use crate::testutil::TestChain;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use crate::transaction::{hash_pub_key, Fee};
+    use crate::wallets::Wallet;
+
+    #[test]
+    fn test_light_client() {
+        let chain = TestChain::new("light");
+        let mut utxo = chain.utxo;
+        let params = &chain.params;
+        let alice = Wallet::new();
+        let bob = Wallet::new();
+        let mut alice_pkh = alice.public_key.clone();
+        hash_pub_key(&mut alice_pkh);
+
+        let subsidy = utxo.blockchain.get_block_subsidy(1);
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::new(), 1, subsidy).unwrap();
+        let block = utxo.blockchain.mine_block(vec![cbtx]).unwrap();
+        utxo.update(&block).unwrap();
+        let tx = Transaction::new_UTXO(&alice, &bob.get_network_address(Network::Regtest), 30, Fee::Absolute(2), &utxo)
+            .unwrap();
+        let reward = utxo.blockchain.get_block_subsidy(2) + 2;
+        let cbtx = Transaction::new_coinbase(bob.get_address(), String::new(), 2, reward).unwrap();
+        let block = utxo.blockchain.mine_block(vec![cbtx, tx]).unwrap();
+        utxo.update(&block).unwrap();
+        let cbtx = Transaction::new_coinbase(bob.get_address(), String::new(), 3, subsidy).unwrap();
+        let block = utxo.blockchain.mine_block(vec![cbtx]).unwrap();
+        utxo.update(&block).unwrap();
+
+        let light = LightClient::open(params, vec![alice_pkh.clone()]).unwrap();
+        let locator = light.headers.get_block_locator().unwrap();
+        let headers = utxo.blockchain.get_headers_after(&locator, "", MAX_HEADERS_PER_MSG).unwrap();
+        assert_eq!(light.add_headers(&headers).unwrap(), 3);
+        assert_eq!(light.get_status().unwrap().scanned_height, 0);
+
+        let filter: HashSet<Vec<u8>> = [alice_pkh.clone()].into_iter().collect();
+        let unscanned = light.get_unscanned(MAX_BLOCKS_IN_FLIGHT).unwrap();
+        assert_eq!(unscanned.len(), 3);
+        let blocks: Vec<FilteredBlock> = unscanned
+            .iter()
+            .map(|hash| FilteredBlock::new(&utxo.blockchain.get_block(hash).unwrap(), &filter).unwrap())
+            .collect();
+        assert_eq!(blocks.iter().map(|b| b.transactions.len()).collect::<Vec<_>>(), vec![1, 1, 0]);
+
+        // a transaction changed after it was proven fails the block
+        let mut forged = blocks[1].clone();
+        forged.transactions[0].vout[0].value += 1;
+        assert!(light.add_filtered_block(&forged).is_err());
+        let mut forged = blocks[1].clone();
+        forged.proofs = blocks[0].proofs.clone();
+        assert!(light.add_filtered_block(&forged).is_err());
+
+        for block in &blocks {
+            light.add_filtered_block(block).unwrap();
+        }
+        assert!(light.get_unscanned(MAX_BLOCKS_IN_FLIGHT).unwrap().is_empty());
+        let status = light.get_status().unwrap();
+        assert_eq!((status.height, status.scanned_height), (3, 3));
+        assert_eq!((status.balance, status.immature), utxo.get_balance(&alice_pkh).unwrap());
+        assert_eq!(status.balance + status.immature, subsidy - 32);
+
+        // a longer branch leaving the chain after block 2 sends the cursor back there
+        let mut miner = Blockchain::new(&params.data_path("miner"), &params.genesis).unwrap();
+        for height in 1..=2 {
+            miner.add_block(utxo.blockchain.get_block_by_height(height).unwrap()).unwrap();
+        }
+        let branch: Vec<BlockHeader> = (3..=5)
+            .map(|height| {
+                let cbtx = Transaction::new_coinbase(bob.get_address(), String::from("branch"), height, subsidy).unwrap();
+                miner.mine_block(vec![cbtx]).unwrap().get_header().clone()
+            })
+            .collect();
+        assert_eq!(light.add_headers(&branch).unwrap(), 3);
+        let status = light.get_status().unwrap();
+        assert_eq!((status.height, status.scanned_height), (5, 2));
+        assert_eq!(status.balance + status.immature, subsidy - 32);
+        let unscanned = light.get_unscanned(MAX_BLOCKS_IN_FLIGHT).unwrap();
+        assert_eq!(unscanned, branch.iter().map(|h| h.hash.clone()).collect::<Vec<_>>());
+
+        // following other keys starts the scan over
+        drop(light);
+        let light = LightClient::open(params, vec![bob.public_key.clone()]).unwrap();
+        assert_eq!(light.get_unscanned(MAX_BLOCKS_IN_FLIGHT).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_follow() {
+        let mut chain = TestChain::new("light-follow");
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        chain.params.set_port(&port.to_string()).unwrap();
+        let mut utxo = chain.utxo;
+        let params = &chain.params;
+        let alice = Wallet::new();
+        let mut alice_pkh = alice.public_key.clone();
+        hash_pub_key(&mut alice_pkh);
+        for height in 1..=3 {
+            let address = if height == 2 { Wallet::new() } else { alice.clone() }.get_address();
+            let subsidy = utxo.blockchain.get_block_subsidy(height);
+            let cbtx = Transaction::new_coinbase(address, String::new(), height, subsidy).unwrap();
+            let block = utxo.blockchain.mine_block(vec![cbtx]).unwrap();
+            utxo.update(&block).unwrap();
+        }
+        let expected = utxo.get_balance(&alice_pkh).unwrap();
+        let server = Server::new(params, "", utxo).unwrap();
+        thread::spawn(move || server.start_server());
+
+        let mut stream = None;
+        for _ in 0..100 {
+            stream = TcpStream::connect(&params.advertised_addr).ok();
+            if stream.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let stream = stream.expect("node did not start");
+        let light = LightClient::open(params, vec![alice_pkh]).unwrap();
+        let (updates, statuses) = mpsc::channel();
+        let closer = stream.try_clone().unwrap();
+        thread::scope(|scope| {
+            let follower = scope.spawn(|| {
+                let mut last = None;
+                light.follow(stream, &mut last, &mut |status: &LightStatus| {
+                    updates.send(status.clone()).ok();
+                })
+            });
+            // the node sends the headers, then a merkle block for each one
+            loop {
+                let status = statuses.recv_timeout(Duration::from_secs(30)).expect("light client stalled");
+                if status.scanned_height == 3 {
+                    assert_eq!(status.height, 3);
+                    assert_eq!((status.balance, status.immature), expected);
+                    break;
+                }
+            }
+            closer.shutdown(std::net::Shutdown::Both).unwrap();
+            follower.join().unwrap().ok();
+        });
+        assert!(light.get_unscanned(MAX_BLOCKS_IN_FLIGHT).unwrap().is_empty());
+    }
+}
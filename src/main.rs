@@ -11,6 +11,17 @@ mod tx;
 mod wallets;
 mod utxoset;
 mod server;
+mod validation;
+mod mempool;
+mod net;
+mod config;
+mod peers;
+mod sync;
+mod rpc;
+mod explorer;
+mod light;
+#[cfg(test)]
+mod testutil;
 
 fn main() -> Result<()>{
     let mut cli = Cli::new()?;
@@ -0,0 +1,343 @@
+//! pool of unconfirmed transactions
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use bincode::serialize;
+
+use crate::block::Block;
+use crate::errors::Result;
+use crate::transaction::Transaction;
+use crate::tx::OutPoint;
+use crate::validation::BlockError;
+
+/// MempoolError is the reason a transaction was not accepted into the mempool
+#[derive(Debug, PartialEq)]
+pub enum MempoolError {
+    AlreadyKnown(String),
+    MissingInput { txid: String, vout: i32 },
+    InvalidSignature(String),
+    OutputsExceedInputs(String),
+    /// the transaction spends an output of a transaction it would replace
+    SpendsConflict(String),
+    InsufficientReplacementFee { txid: String, fee: i32, required: i32 },
+    /// a sum of the transaction's amounts does not fit in an amount
+    ValueOverflow(String),
+    /// the transaction breaks a rule it would break in a block as well
+    Invalid(BlockError),
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MempoolError::AlreadyKnown(txid) => {
+                write!(f, "transaction {} is already in the mempool", txid)
+            }
+            MempoolError::MissingInput { txid, vout } => {
+                write!(f, "output {}:{} is neither unspent nor in the mempool", txid, vout)
+            }
+            MempoolError::InvalidSignature(txid) => {
+                write!(f, "transaction {} has an invalid signature", txid)
+            }
+            MempoolError::OutputsExceedInputs(txid) => {
+                write!(f, "transaction {} spends more than its inputs", txid)
+            }
+            MempoolError::SpendsConflict(txid) => write!(
+                f,
+                "transaction {} spends an output of a transaction it conflicts with",
+                txid
+            ),
+            MempoolError::InsufficientReplacementFee { txid, fee, required } => write!(
+                f,
+                "transaction {} pays {} but replacing its conflicts requires more than {}",
+                txid, fee, required
+            ),
+            MempoolError::ValueOverflow(txid) => write!(f, "amounts of transaction {} overflow", txid),
+            MempoolError::Invalid(reason) => write!(f, "invalid transaction: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for MempoolError {}
+
+/// MempoolEntry is a transaction waiting to be mined
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    pub fee: i32,
+    /// serialized size in bytes
+    pub size: usize,
+}
+
+/// Mempool holds unconfirmed transactions and the outputs they spend
+///
+/// No two transactions in the pool spend the same output. A transaction that
+/// conflicts with pooled ones replaces them, together with everything that
+/// spends their outputs, only if it pays more than all of them combined and
+/// a higher fee rate than each transaction it directly conflicts with.
+#[derive(Debug, Default)]
+pub struct Mempool {
+    entries: HashMap<String, MempoolEntry>,
+    spent: HashMap<OutPoint, String>,
+}
+
+impl Mempool {
+    pub fn new() -> Mempool {
+        Mempool::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, txid: &str) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn get(&self, txid: &str) -> Option<&Transaction> {
+        self.entries.get(txid).map(|entry| &entry.tx)
+    }
+
+    /// Entries returns every pooled transaction with its fee
+    pub fn entries(&self) -> impl Iterator<Item = &MempoolEntry> {
+        self.entries.values()
+    }
+
+    /// Spender returns the id of the pooled transaction spending `outpoint`
+    pub fn spender(&self, outpoint: &OutPoint) -> Option<&str> {
+        self.spent.get(outpoint).map(|txid| txid.as_str())
+    }
+
+    /// Insert adds a transaction paying `fee`, returning the transactions it replaced
+    ///
+    /// The caller is responsible for checking that the inputs exist and the
+    /// signatures are valid; the mempool only enforces that outputs are spent once.
+    pub fn insert(&mut self, tx: Transaction, fee: i32) -> Result<Vec<Transaction>> {
+        if self.contains(&tx.id) {
+            return Err(MempoolError::AlreadyKnown(tx.id).into());
+        }
+        let size = serialize(&tx)?.len();
+
+        let mut conflicts = HashSet::new();
+        for vin in &tx.vin {
+            if let Some(txid) = self.spender(&OutPoint::new(&vin.txid, vin.vout)) {
+                conflicts.insert(txid.to_string());
+            }
+        }
+
+        if !conflicts.is_empty() {
+            let mut evicted = HashSet::new();
+            for txid in &conflicts {
+                self.collect_descendants(txid, &mut evicted);
+            }
+            if tx.vin.iter().any(|vin| evicted.contains(&vin.txid)) {
+                return Err(MempoolError::SpendsConflict(tx.id).into());
+            }
+
+            // fees past the largest amount can't be outbid by any fee
+            let evicted_fees = evicted
+                .iter()
+                .try_fold(0i32, |sum, txid| sum.checked_add(self.entries[txid].fee))
+                .unwrap_or(i32::MAX);
+            if fee <= evicted_fees {
+                return Err(MempoolError::InsufficientReplacementFee {
+                    txid: tx.id,
+                    fee,
+                    required: evicted_fees,
+                }
+                .into());
+            }
+            for txid in &conflicts {
+                let other = &self.entries[txid];
+                // fee / size > other.fee / other.size, compared without division
+                if fee as i64 * other.size as i64 <= other.fee as i64 * size as i64 {
+                    return Err(MempoolError::InsufficientReplacementFee {
+                        txid: tx.id,
+                        fee,
+                        required: (other.fee as i64 * size as i64 / other.size as i64) as i32,
+                    }
+                    .into());
+                }
+            }
+        }
+
+        let mut replaced = Vec::new();
+        for txid in &conflicts {
+            replaced.extend(self.remove_with_descendants(txid));
+        }
+        for vin in &tx.vin {
+            self.spent
+                .insert(OutPoint::new(&vin.txid, vin.vout), tx.id.clone());
+        }
+        self.entries
+            .insert(tx.id.clone(), MempoolEntry { tx, fee, size });
+        Ok(replaced)
+    }
+
+    /// Remove takes a single transaction out of the pool
+    pub fn remove(&mut self, txid: &str) -> Option<Transaction> {
+        let entry = self.entries.remove(txid)?;
+        for vin in &entry.tx.vin {
+            self.spent.remove(&OutPoint::new(&vin.txid, vin.vout));
+        }
+        Some(entry.tx)
+    }
+
+    /// RemoveWithDescendants removes a transaction and everything spending its outputs
+    pub fn remove_with_descendants(&mut self, txid: &str) -> Vec<Transaction> {
+        let mut evicted = HashSet::new();
+        self.collect_descendants(txid, &mut evicted);
+        evicted.iter().filter_map(|txid| self.remove(txid)).collect()
+    }
+
+    /// RemoveBlock drops the transactions a block confirmed
+    ///
+    /// Pooled transactions that spend an output the block spent can no longer
+    /// be mined; they are removed with their descendants and returned.
+    pub fn remove_block(&mut self, block: &Block) -> Vec<Transaction> {
+        let mut conflicting = Vec::new();
+        for tx in block.get_transaction() {
+            self.remove(&tx.id);
+            if tx.is_coinbase() {
+                continue;
+            }
+            for vin in &tx.vin {
+                if let Some(txid) = self.spender(&OutPoint::new(&vin.txid, vin.vout)) {
+                    let txid = txid.to_string();
+                    conflicting.extend(self.remove_with_descendants(&txid));
+                }
+            }
+        }
+        conflicting
+    }
+
+    fn collect_descendants(&self, txid: &str, found: &mut HashSet<String>) {
+        let entry = match self.entries.get(txid) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if !found.insert(txid.to_string()) {
+            return;
+        }
+        for vout in 0..entry.tx.vout.len() as i32 {
+            if let Some(child) = self.spender(&OutPoint::new(txid, vout)) {
+                self.collect_descendants(child, found);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::POW_LIMIT_BITS;
+    use crate::tx::{TXInput, TXOutput};
+    use crate::wallets::Wallet;
+
+    fn tx(inputs: &[(&str, i32)], values: &[i32]) -> Transaction {
+        let to = Wallet::new().get_address();
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: inputs
+                .iter()
+                .map(|(txid, vout)| TXInput {
+                    txid: txid.to_string(),
+                    vout: *vout,
+                    signature: Vec::new(),
+                    pub_key: Vec::new(),
+                })
+                .collect(),
+            vout: values
+                .iter()
+                .map(|value| TXOutput::new(*value, to.clone()).unwrap())
+                .collect(),
+        };
+        tx.id = tx.hash().unwrap();
+        tx
+    }
+
+    fn reason(err: failure::Error) -> MempoolError {
+        err.downcast::<MempoolError>().unwrap()
+    }
+
+    #[test]
+    fn test_reject_conflict_without_higher_fee() {
+        let mut pool = Mempool::new();
+        let first = tx(&[("a", 0)], &[90]);
+        let second = tx(&[("a", 0), ("b", 1)], &[80]);
+        pool.insert(first.clone(), 10).unwrap();
+
+        assert_eq!(
+            reason(pool.insert(second.clone(), 10).unwrap_err()),
+            MempoolError::InsufficientReplacementFee {
+                txid: second.id,
+                fee: 10,
+                required: 10
+            }
+        );
+        assert_eq!(reason(pool.insert(first.clone(), 10).unwrap_err()), MempoolError::AlreadyKnown(first.id.clone()));
+        assert_eq!(pool.spender(&OutPoint::new("a", 0)), Some(first.id.as_str()));
+        assert_eq!(pool.spender(&OutPoint::new("b", 1)), None);
+    }
+
+    #[test]
+    fn test_replace_by_fee_evicts_descendants() {
+        let mut pool = Mempool::new();
+        let parent = tx(&[("a", 0)], &[90]);
+        let child = tx(&[(&parent.id, 0)], &[85]);
+        pool.insert(parent.clone(), 10).unwrap();
+        pool.insert(child.clone(), 5).unwrap();
+
+        // outbids the parent but not the parent and its child together
+        let cheap = tx(&[("a", 0)], &[86]);
+        assert!(matches!(
+            reason(pool.insert(cheap, 14).unwrap_err()),
+            MempoolError::InsufficientReplacementFee { required: 15, .. }
+        ));
+
+        let spends_child = tx(&[("a", 0), (&child.id, 0)], &[10]);
+        assert_eq!(
+            reason(pool.insert(spends_child.clone(), 100).unwrap_err()),
+            MempoolError::SpendsConflict(spends_child.id)
+        );
+
+        let replacement = tx(&[("a", 0)], &[70]);
+        let mut replaced: Vec<String> = pool
+            .insert(replacement.clone(), 30)
+            .unwrap()
+            .into_iter()
+            .map(|tx| tx.id)
+            .collect();
+        replaced.sort();
+        let mut expected = vec![parent.id.clone(), child.id.clone()];
+        expected.sort();
+        assert_eq!(replaced, expected);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.spender(&OutPoint::new("a", 0)), Some(replacement.id.as_str()));
+        assert_eq!(pool.spender(&OutPoint::new(&parent.id, 0)), None);
+    }
+
+    #[test]
+    fn test_remove_block_drops_conflicts() {
+        let mut pool = Mempool::new();
+        let pooled = tx(&[("a", 0)], &[90]);
+        let child = tx(&[(&pooled.id, 0)], &[80]);
+        let unrelated = tx(&[("b", 0)], &[90]);
+        pool.insert(pooled.clone(), 10).unwrap();
+        pool.insert(child.clone(), 10).unwrap();
+        pool.insert(unrelated.clone(), 10).unwrap();
+
+        let coinbase = Transaction::new_coinbase(Wallet::new().get_address(), String::new(), 1, 100).unwrap();
+        let mined = tx(&[("a", 0)], &[95]);
+        let block = Block::new_block(vec![coinbase, mined], String::new(), 1, POW_LIMIT_BITS).unwrap();
+
+        assert_eq!(pool.remove_block(&block).len(), 2);
+        assert_eq!(pool.len(), 1);
+        assert!(pool.contains(&unrelated.id));
+        assert_eq!(pool.spender(&OutPoint::new("a", 0)), None);
+    }
+}
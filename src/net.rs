@@ -0,0 +1,261 @@
+//! wire framing of peer to peer messages
+//!
+//! Every message travels as one frame:
+//!
+//! | field    | size | content                                          |
+//! |----------|------|--------------------------------------------------|
+//! | magic    | 4    | network identifier                               |
+//! | command  | 12   | ASCII command name, padded with NUL bytes        |
+//! | length   | 4    | payload length, little endian                    |
+//! | checksum | 4    | first 4 bytes of sha256(sha256(payload))         |
+//! | payload  | len  | bincode encoded message body                     |
+
+use std::fmt;
+use std::io::{self, Read};
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use failure::format_err;
+
+use crate::errors::Result;
+
+/// Network identifier at the start of every frame
+pub const MAGIC: [u8; 4] = [0xfa, 0xbf, 0xb5, 0xda];
+/// Length of the command field
+pub const CMD_LEN: usize = 12;
+/// Size of a frame header
+pub const HEADER_LEN: usize = 4 + CMD_LEN + 4 + 4;
+/// Largest payload a peer may send in one frame
+pub const MAX_PAYLOAD_LEN: u32 = 8 * 1024 * 1024;
+
+/// WireError is a violation of the wire protocol by a peer
+#[derive(Debug, PartialEq)]
+pub enum WireError {
+    BadMagic([u8; 4]),
+    BadCommand([u8; CMD_LEN]),
+    PayloadTooLarge(u32),
+    BadChecksum { expected: [u8; 4], found: [u8; 4] },
+    /// the connection closed in the middle of a frame
+    Truncated,
+    UnknownCommand(String),
+    MalformedPayload(String),
+}
+
+impl WireError {
+    /// Penalty returns the misbehavior score a peer earns for the violation
+    pub fn penalty(&self) -> u32 {
+        match self {
+            WireError::PayloadTooLarge(_) => 50,
+            WireError::BadMagic(_) | WireError::BadCommand(_) | WireError::MalformedPayload(_) => 20,
+            WireError::BadChecksum { .. } | WireError::UnknownCommand(_) => 10,
+            WireError::Truncated => 5,
+        }
+    }
+
+    /// IsFatal reports whether the stream can no longer be read past the error
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            WireError::BadMagic(_) | WireError::PayloadTooLarge(_) | WireError::Truncated
+        )
+    }
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WireError::BadMagic(magic) => write!(f, "frame has foreign magic {:02x?}", magic),
+            WireError::BadCommand(cmd) => write!(f, "frame has invalid command {:02x?}", cmd),
+            WireError::PayloadTooLarge(len) => write!(
+                f,
+                "frame payload of {} bytes exceeds {} bytes",
+                len, MAX_PAYLOAD_LEN
+            ),
+            WireError::BadChecksum { expected, found } => write!(
+                f,
+                "frame checksum {:02x?} does not match payload checksum {:02x?}",
+                found, expected
+            ),
+            WireError::Truncated => write!(f, "connection closed inside a frame"),
+            WireError::UnknownCommand(cmd) => write!(f, "unknown command {}", cmd),
+            WireError::MalformedPayload(cmd) => write!(f, "malformed {} payload", cmd),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+/// Frame is one message read off the wire
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub command: String,
+    pub payload: Vec<u8>,
+}
+
+/// Checksum returns the first 4 bytes of the double sha256 of `payload`
+pub fn checksum(payload: &[u8]) -> [u8; 4] {
+    let mut hash = [0u8; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(payload);
+    hasher.result(&mut hash);
+    hasher.reset();
+    hasher.input(&hash);
+    hasher.result(&mut hash);
+    let mut sum = [0u8; 4];
+    sum.copy_from_slice(&hash[..4]);
+    sum
+}
+
+/// EncodeFrame builds the frame carrying `payload` under `command`
+pub fn encode_frame(magic: [u8; 4], command: &str, payload: &[u8]) -> Result<Vec<u8>> {
+    if command.is_empty() || command.len() > CMD_LEN || !command.is_ascii() {
+        return Err(format_err!("command {:?} does not fit the frame header", command));
+    }
+    let mut data = Vec::with_capacity(HEADER_LEN + payload.len());
+    data.extend_from_slice(&magic);
+    let mut cmd = [0u8; CMD_LEN];
+    cmd[..command.len()].copy_from_slice(command.as_bytes());
+    data.extend_from_slice(&cmd);
+    data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    data.extend_from_slice(&checksum(payload));
+    data.extend_from_slice(payload);
+    Ok(data)
+}
+
+/// ReadFrame reads the next frame, returning None when the peer closed the
+/// connection between frames
+///
+/// Protocol violations are returned as a WireError. When the error is not
+/// fatal the whole frame has been consumed and the next one can be read.
+pub fn read_frame<R: Read>(r: &mut R, magic: [u8; 4]) -> Result<Option<Frame>> {
+    let mut header = [0u8; HEADER_LEN];
+    let mut read = 0;
+    while read < HEADER_LEN {
+        match r.read(&mut header[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(WireError::Truncated.into()),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let mut found = [0u8; 4];
+    found.copy_from_slice(&header[..4]);
+    if found != magic {
+        return Err(WireError::BadMagic(found).into());
+    }
+    let mut cmd = [0u8; CMD_LEN];
+    cmd.copy_from_slice(&header[4..4 + CMD_LEN]);
+    let mut len = [0u8; 4];
+    len.copy_from_slice(&header[4 + CMD_LEN..8 + CMD_LEN]);
+    let len = u32::from_le_bytes(len);
+    if len > MAX_PAYLOAD_LEN {
+        return Err(WireError::PayloadTooLarge(len).into());
+    }
+    let mut sum = [0u8; 4];
+    sum.copy_from_slice(&header[8 + CMD_LEN..]);
+
+    let mut payload = vec![0u8; len as usize];
+    if let Err(e) = r.read_exact(&mut payload) {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            return Err(WireError::Truncated.into());
+        }
+        return Err(e.into());
+    }
+
+    let command = match parse_command(&cmd) {
+        Some(command) => command,
+        None => return Err(WireError::BadCommand(cmd).into()),
+    };
+    let expected = checksum(&payload);
+    if expected != sum {
+        return Err(WireError::BadChecksum {
+            expected,
+            found: sum,
+        }
+        .into());
+    }
+    Ok(Some(Frame { command, payload }))
+}
+
+/// ParseCommand accepts printable ASCII followed only by NUL padding
+fn parse_command(cmd: &[u8; CMD_LEN]) -> Option<String> {
+    let end = cmd.iter().position(|b| *b == 0).unwrap_or(CMD_LEN);
+    if end == 0
+        || !cmd[..end].iter().all(|b| b.is_ascii_graphic())
+        || cmd[end..].iter().any(|b| *b != 0)
+    {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&cmd[..end]).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reason(err: failure::Error) -> WireError {
+        err.downcast::<WireError>().unwrap()
+    }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let mut data = encode_frame(MAGIC, "version", b"hello").unwrap();
+        data.extend(encode_frame(MAGIC, "getblocks", b"").unwrap());
+        let mut r = &data[..];
+        let frame = read_frame(&mut r, MAGIC).unwrap().unwrap();
+        assert_eq!(frame.command, "version");
+        assert_eq!(frame.payload, b"hello");
+        let frame = read_frame(&mut r, MAGIC).unwrap().unwrap();
+        assert_eq!(frame.command, "getblocks");
+        assert!(frame.payload.is_empty());
+        assert_eq!(read_frame(&mut r, MAGIC).unwrap(), None);
+    }
+
+    #[test]
+    fn test_reject_malformed_frames() {
+        let frame = encode_frame(MAGIC, "tx", b"payload").unwrap();
+
+        let mut r = &frame[..frame.len() - 1];
+        assert_eq!(reason(read_frame(&mut r, MAGIC).unwrap_err()), WireError::Truncated);
+        let mut r = &frame[..3];
+        assert_eq!(reason(read_frame(&mut r, MAGIC).unwrap_err()), WireError::Truncated);
+
+        let mut r = &frame[..];
+        assert_eq!(
+            reason(read_frame(&mut r, [0; 4]).unwrap_err()),
+            WireError::BadMagic(MAGIC)
+        );
+
+        // a corrupt payload is skipped and the stream stays usable
+        let mut data = frame.clone();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        data.extend(encode_frame(MAGIC, "inv", b"").unwrap());
+        let mut r = &data[..];
+        assert!(matches!(
+            reason(read_frame(&mut r, MAGIC).unwrap_err()),
+            WireError::BadChecksum { .. }
+        ));
+        assert_eq!(read_frame(&mut r, MAGIC).unwrap().unwrap().command, "inv");
+
+        let mut data = frame.clone();
+        data[4 + CMD_LEN..8 + CMD_LEN].copy_from_slice(&(MAX_PAYLOAD_LEN + 1).to_le_bytes());
+        let mut r = &data[..];
+        assert_eq!(
+            reason(read_frame(&mut r, MAGIC).unwrap_err()),
+            WireError::PayloadTooLarge(MAX_PAYLOAD_LEN + 1)
+        );
+
+        let mut data = frame;
+        data[4 + 3] = b'x';
+        let mut r = &data[..];
+        assert!(matches!(
+            reason(read_frame(&mut r, MAGIC).unwrap_err()),
+            WireError::BadCommand(_)
+        ));
+
+        assert!(encode_frame(MAGIC, "averylongcommand", b"").is_err());
+    }
+}
@@ -0,0 +1,287 @@
+//! address book and misbehavior tracking of peers
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::Result;
+
+/// Misbehavior score at which a host is disconnected and refused
+pub const BAN_SCORE: u32 = 100;
+/// Seconds a banned host stays banned
+pub const BAN_DURATION: u64 = 24 * 60 * 60;
+/// Seconds to wait before dialing a peer again after its first failure
+pub const BASE_BACKOFF: u64 = 5;
+/// Longest wait between two attempts to dial a failing peer
+pub const MAX_BACKOFF: u64 = 60 * 60;
+/// Failed connection attempts in a row after which an address is forgotten
+pub const MAX_FAILURES: u32 = 10;
+
+/// PeerInfo is what the address book remembers about a peer
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerInfo {
+    pub addr: String,
+    /// unix time of the last message from the peer, 0 if never heard from
+    pub last_seen: u64,
+    /// services announced in the peer's version message
+    pub services: u64,
+    /// failed connection attempts in a row
+    pub failures: u32,
+    /// unix time before which the peer is not dialed again
+    pub retry_at: u64,
+}
+
+/// Misbehavior is invalid data a peer sent, along with the score it earns
+#[derive(Debug, PartialEq)]
+pub struct Misbehavior {
+    pub penalty: u32,
+    pub reason: String,
+}
+
+impl fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (penalty {})", self.reason, self.penalty)
+    }
+}
+
+impl std::error::Error for Misbehavior {}
+
+/// PeerManager keeps the address book and the misbehavior scores of hosts
+///
+/// Addresses and bans are persisted, so a restarted node reconnects to the
+/// peers it knew and keeps refusing the hosts it banned. Scores are kept per
+/// IP address, since an inbound peer is known by its host before it
+/// announces a listening address.
+pub struct PeerManager {
+    db: sled::Db,
+    addrs: HashMap<String, PeerInfo>,
+    scores: HashMap<IpAddr, u32>,
+    /// banned hosts and the unix time their ban ends
+    bans: HashMap<IpAddr, u64>,
+}
+
+impl PeerManager {
+    /// Open loads the address book stored at `path`
+    pub fn open(path: &Path) -> Result<PeerManager> {
+        PeerManager::load(sled::open(path)?)
+    }
+
+    /// OpenTemporary creates an empty address book that is not persisted
+    #[cfg(test)]
+    pub fn open_temporary() -> Result<PeerManager> {
+        PeerManager::load(sled::Config::new().temporary(true).open()?)
+    }
+
+    fn load(db: sled::Db) -> Result<PeerManager> {
+        let mut addrs = HashMap::new();
+        for kv in db.iter() {
+            let (_, value) = kv?;
+            let info: PeerInfo = deserialize(&value)?;
+            addrs.insert(info.addr.clone(), info);
+        }
+        let mut bans = HashMap::new();
+        for kv in db.open_tree("bans")?.iter() {
+            let (key, value) = kv?;
+            let ip: IpAddr = String::from_utf8(key.to_vec())?.parse()?;
+            bans.insert(ip, deserialize(&value)?);
+        }
+        Ok(PeerManager {
+            db,
+            addrs,
+            scores: HashMap::new(),
+            bans,
+        })
+    }
+
+    fn save(&self, addr: &str) -> Result<()> {
+        match self.addrs.get(addr) {
+            Some(info) => self.db.insert(addr, serialize(info)?)?,
+            None => self.db.remove(addr)?,
+        };
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+
+    pub fn contains(&self, addr: &str) -> bool {
+        self.addrs.contains_key(addr)
+    }
+
+    pub fn get(&self, addr: &str) -> Option<&PeerInfo> {
+        self.addrs.get(addr)
+    }
+
+    /// Addresses returns every known address, sorted
+    pub fn addresses(&self) -> Vec<String> {
+        let mut addrs: Vec<String> = self.addrs.keys().cloned().collect();
+        addrs.sort();
+        addrs
+    }
+
+    /// Add remembers an address, returning whether it was new
+    pub fn add(&mut self, addr: &str) -> Result<bool> {
+        if self.contains(addr) {
+            return Ok(false);
+        }
+        self.addrs.insert(
+            addr.to_string(),
+            PeerInfo {
+                addr: addr.to_string(),
+                last_seen: 0,
+                services: 0,
+                failures: 0,
+                retry_at: 0,
+            },
+        );
+        self.save(addr)?;
+        Ok(true)
+    }
+
+    pub fn remove(&mut self, addr: &str) -> Result<()> {
+        self.addrs.remove(addr);
+        self.save(addr)
+    }
+
+    /// MarkSeen records a message from a peer and clears its failures
+    pub fn mark_seen(&mut self, addr: &str, services: Option<u64>, now: u64) -> Result<()> {
+        self.add(addr)?;
+        let info = self.addrs.get_mut(addr).unwrap();
+        info.last_seen = now;
+        info.failures = 0;
+        info.retry_at = 0;
+        if let Some(services) = services {
+            info.services = services;
+        }
+        self.save(addr)
+    }
+
+    /// RecordFailure notes a failed connection attempt
+    ///
+    /// The peer is not dialed again for BASE_BACKOFF seconds, doubling with
+    /// every further failure up to MAX_BACKOFF. After MAX_FAILURES failures
+    /// in a row the address is forgotten.
+    pub fn record_failure(&mut self, addr: &str, now: u64) -> Result<()> {
+        let info = match self.addrs.get_mut(addr) {
+            Some(info) => info,
+            None => return Ok(()),
+        };
+        info.failures += 1;
+        if info.failures >= MAX_FAILURES {
+            return self.remove(addr);
+        }
+        let backoff = BASE_BACKOFF.saturating_mul(1 << (info.failures - 1).min(32));
+        info.retry_at = now + backoff.min(MAX_BACKOFF);
+        self.save(addr)
+    }
+
+    /// CanDial reports whether a peer is not waiting out a backoff
+    pub fn can_dial(&self, addr: &str, now: u64) -> bool {
+        match self.addrs.get(addr) {
+            Some(info) => info.retry_at <= now,
+            None => true,
+        }
+    }
+
+    /// Candidates returns up to `max` addresses to dial, skipping the
+    /// `connected` ones and those in backoff, most recently seen first
+    pub fn candidates(&self, connected: &HashSet<String>, max: usize, now: u64) -> Vec<String> {
+        let mut found: Vec<&PeerInfo> = self
+            .addrs
+            .values()
+            .filter(|info| !connected.contains(&info.addr) && info.retry_at <= now)
+            .collect();
+        found.sort_by(|a, b| b.last_seen.cmp(&a.last_seen).then(a.addr.cmp(&b.addr)));
+        found.into_iter().take(max).map(|info| info.addr.clone()).collect()
+    }
+
+    /// Penalize adds to a host's misbehavior score, returning whether the
+    /// host is now banned
+    ///
+    /// A host reaching BAN_SCORE is banned for BAN_DURATION seconds and its
+    /// score starts over once the ban ends.
+    pub fn penalize(&mut self, ip: IpAddr, penalty: u32, now: u64) -> Result<bool> {
+        let score = self.scores.entry(ip).or_insert(0);
+        *score += penalty;
+        if *score < BAN_SCORE {
+            return Ok(false);
+        }
+        self.scores.remove(&ip);
+        let until = now + BAN_DURATION;
+        self.bans.insert(ip, until);
+        self.db
+            .open_tree("bans")?
+            .insert(ip.to_string(), serialize(&until)?)?;
+        self.db.flush()?;
+        Ok(true)
+    }
+
+    pub fn get_score(&self, ip: IpAddr) -> u32 {
+        self.scores.get(&ip).copied().unwrap_or(0)
+    }
+
+    pub fn is_banned(&self, ip: IpAddr, now: u64) -> bool {
+        match self.bans.get(&ip) {
+            Some(until) => *until > now,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_and_candidates() {
+        let mut book = PeerManager::open_temporary().unwrap();
+        assert!(book.add("a:1").unwrap());
+        assert!(!book.add("a:1").unwrap());
+        book.add("b:1").unwrap();
+        book.mark_seen("c:1", Some(1), 50).unwrap();
+        assert_eq!(book.get("c:1").unwrap().services, 1);
+
+        let mut connected = HashSet::new();
+        connected.insert("b:1".to_string());
+        assert_eq!(book.candidates(&connected, 8, 100), vec!["c:1", "a:1"]);
+
+        book.record_failure("a:1", 100).unwrap();
+        assert!(!book.can_dial("a:1", 100 + BASE_BACKOFF - 1));
+        assert!(book.can_dial("a:1", 100 + BASE_BACKOFF));
+        book.record_failure("a:1", 100).unwrap();
+        assert_eq!(book.get("a:1").unwrap().retry_at, 100 + 2 * BASE_BACKOFF);
+        assert_eq!(book.candidates(&connected, 8, 100), vec!["c:1"]);
+
+        // hearing from the peer clears its failures
+        book.mark_seen("a:1", None, 101).unwrap();
+        assert!(book.can_dial("a:1", 101));
+        for _ in 0..MAX_FAILURES {
+            book.record_failure("a:1", 200).unwrap();
+        }
+        assert!(!book.contains("a:1"));
+        assert_eq!(book.addresses(), vec!["b:1", "c:1"]);
+    }
+
+    #[test]
+    fn test_ban_after_repeated_misbehavior() {
+        let mut book = PeerManager::open_temporary().unwrap();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(!book.penalize(ip, BAN_SCORE / 2, 0).unwrap());
+        assert_eq!(book.get_score(ip), BAN_SCORE / 2);
+        assert!(!book.is_banned(ip, 0));
+        assert!(book.penalize(ip, BAN_SCORE / 2, 0).unwrap());
+        assert!(book.is_banned(ip, BAN_DURATION - 1));
+        assert!(!book.is_banned(ip, BAN_DURATION));
+        assert_eq!(book.get_score(ip), 0);
+
+        // bans survive a reload
+        let reloaded = PeerManager::load(book.db.clone()).unwrap();
+        assert!(reloaded.is_banned(ip, 0));
+    }
+}
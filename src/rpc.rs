@@ -0,0 +1,946 @@
+//! JSON-RPC 2.0 interface of a running node, served over HTTP
+//!
+//! Requests are POSTed to any path as a single call or a batch:
+//!
+//! ```text
+//! {"jsonrpc": "2.0", "method": "getblock", "params": ["0000a3..."], "id": 1}
+//! ```
+//!
+//! Parameters are positional. The endpoint only listens on loopback
+//! addresses and asks for basic auth when a user is configured. `Client`
+//! calls it from the command line while a node is running.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bincode::{deserialize, serialize};
+use failure::format_err;
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+
+use crate::block::{Block, MerkleProof};
+use crate::config::{Network, NetworkParams, RpcParams};
+use crate::errors::Result;
+use crate::explorer;
+use crate::server::Server;
+use crate::transaction::{hash_pub_key, OutputSource, Transaction};
+
+/// Largest message body read, in bytes
+const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+/// Largest start line plus headers read, in bytes
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+/// How long a connection may take to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a client waits for a node to accept its connection
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long a client waits for a node to answer a call
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+/// The method is not available on this node
+const MISC_ERROR: i64 = -1;
+/// A block or transaction asked for is unknown, or an address is invalid
+const INVALID_ADDRESS_OR_KEY: i64 = -5;
+/// A submitted transaction was rejected
+const VERIFY_REJECTED: i64 = -26;
+
+/// RpcError is the error object of a JSON-RPC response
+#[derive(Debug, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> RpcError {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (code {})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl From<failure::Error> for RpcError {
+    fn from(e: failure::Error) -> Self {
+        RpcError::new(INTERNAL_ERROR, e.to_string())
+    }
+}
+
+type RpcResult = std::result::Result<Value, RpcError>;
+
+/// serve answers JSON-RPC requests to `node` on the configured address
+pub fn serve(node: Server) -> Result<()> {
+    let rpc = node.get_params().rpc.clone();
+    let addrs: Vec<_> = rpc.bind_addr.to_socket_addrs()?.collect();
+    if addrs.is_empty() || addrs.iter().any(|a| !a.ip().is_loopback()) {
+        return Err(format_err!(
+            "RPC server must listen on a loopback address, not {}",
+            rpc.bind_addr
+        ));
+    }
+    let listener = TcpListener::bind(&addrs[..])?;
+    info!("RPC server listening on {}", rpc.bind_addr);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("RPC server failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let node = node.clone();
+        let rpc = rpc.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(&node, &rpc, stream) {
+                debug!("RPC connection failed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// HttpMessage is the part of an HTTP request or response the RPC server
+/// and client look at
+#[derive(Debug, PartialEq)]
+struct HttpMessage {
+    /// request line or status line
+    start_line: String,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+fn handle_connection(node: &Server, rpc: &RpcParams, mut stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let (status, body) = match read_message(&mut reader) {
+        Ok(request) => respond(node, rpc, &request),
+        Err(e) => ("400 Bad Request", e.to_string()),
+    };
+    write_response(&mut stream, status, &body)
+}
+
+/// read_message reads a request or a response, refusing a head longer than
+/// MAX_HEAD_SIZE or a body longer than MAX_BODY_SIZE
+fn read_message<R: BufRead>(reader: &mut R) -> Result<HttpMessage> {
+    let mut head_left = MAX_HEAD_SIZE;
+    let mut start_line = String::new();
+    read_head_line(reader, &mut start_line, &mut head_left)?;
+    let start_line = start_line.trim_end().to_string();
+    if start_line.is_empty() {
+        return Err(format_err!("empty message"));
+    }
+
+    let mut line = String::new();
+    let mut authorization = None;
+    let mut length = 0;
+    loop {
+        line.clear();
+        if read_head_line(reader, &mut line, &mut head_left)? == 0 {
+            return Err(format_err!("request ends inside the headers"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => length = value.parse()?,
+                "authorization" => authorization = Some(value.to_string()),
+                _ => {}
+            }
+        }
+    }
+    if length > MAX_BODY_SIZE {
+        return Err(format_err!("request body of {} bytes is too large", length));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(HttpMessage {
+        start_line,
+        authorization,
+        body,
+    })
+}
+
+/// read_head_line reads a line of the message head, counting it against the
+/// `left` bytes the head may still take
+fn read_head_line<R: BufRead>(reader: &mut R, line: &mut String, left: &mut usize) -> Result<usize> {
+    let read = reader.take(*left as u64 + 1).read_line(line)?;
+    if read > *left {
+        return Err(format_err!("message head is longer than {} bytes", MAX_HEAD_SIZE));
+    }
+    *left -= read;
+    Ok(read)
+}
+
+fn write_response<W: Write>(writer: &mut W, status: &str, body: &str) -> Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\n", status);
+    if status.starts_with("401") {
+        head += "WWW-Authenticate: Basic realm=\"jsonrpc\"\r\n";
+    }
+    head += &format!(
+        "Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    writer.write_all(head.as_bytes())?;
+    writer.write_all(body.as_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn respond(node: &Server, rpc: &RpcParams, request: &HttpMessage) -> (&'static str, String) {
+    if !is_authorized(rpc, request.authorization.as_deref()) {
+        return ("401 Unauthorized", String::new());
+    }
+    if !request.start_line.starts_with("POST ") {
+        return ("405 Method Not Allowed", String::new());
+    }
+    match handle_body(node, &request.body) {
+        Some(body) => ("200 OK", body),
+        None => ("204 No Content", String::new()),
+    }
+}
+
+/// is_authorized checks the basic auth credentials of a request
+fn is_authorized(rpc: &RpcParams, authorization: Option<&str>) -> bool {
+    if rpc.user.is_empty() {
+        return true;
+    }
+    let encoded = match authorization.and_then(|a| a.strip_prefix("Basic ")) {
+        Some(encoded) => encoded.trim(),
+        None => return false,
+    };
+    match STANDARD.decode(encoded) {
+        Ok(decoded) => decoded == format!("{}:{}", rpc.user, rpc.password).as_bytes(),
+        Err(_) => false,
+    }
+}
+
+/// handle_body answers a call or a batch of calls, returning None when there
+/// is nothing to answer because every call was a notification
+fn handle_body(node: &Server, body: &[u8]) -> Option<String> {
+    let request: Value = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => return Some(response(Value::Null, Err(RpcError::new(PARSE_ERROR, e.to_string()))).to_string()),
+    };
+    match request {
+        Value::Array(calls) if !calls.is_empty() => {
+            let responses: Vec<Value> = calls.iter().filter_map(|call| handle_call(node, call)).collect();
+            if responses.is_empty() {
+                None
+            } else {
+                Some(Value::Array(responses).to_string())
+            }
+        }
+        call => handle_call(node, &call).map(|r| r.to_string()),
+    }
+}
+
+/// handle_call runs a single call, returning no response to a notification
+fn handle_call(node: &Server, call: &Value) -> Option<Value> {
+    let id = call.get("id").cloned();
+    let method = match (call.get("jsonrpc"), call.get("method")) {
+        (Some(Value::String(version)), Some(Value::String(method))) if version == "2.0" => method,
+        _ => {
+            let error = RpcError::new(INVALID_REQUEST, "not a JSON-RPC 2.0 call");
+            return Some(response(id.unwrap_or(Value::Null), Err(error)));
+        }
+    };
+    let result = match call.get("params") {
+        None => dispatch(node, method, &[]),
+        Some(Value::Array(params)) => dispatch(node, method, params),
+        Some(_) => Err(RpcError::new(INVALID_PARAMS, "params must be an array")),
+    };
+    if let Err(e) = &result {
+        debug!("RPC {} failed: {}", method, e);
+    }
+    id.map(|id| response(id, result))
+}
+
+fn response(id: Value, result: RpcResult) -> Value {
+    match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "result": result, "id": id}),
+        Err(e) => json!({
+            "jsonrpc": "2.0",
+            "error": {"code": e.code, "message": e.message},
+            "id": id,
+        }),
+    }
+}
+
+fn dispatch(node: &Server, method: &str, params: &[Value]) -> RpcResult {
+    match method {
+        "getblockcount" => get_block_count(node),
+        "getbestblockhash" => Ok(json!(node.with_state(|utxo, _| Ok(utxo.blockchain.get_tip_hash()))?)),
+        "getblockhash" => get_block_hash(node, params),
+        "getblock" => get_block(node, params),
+        "getrawtransaction" => get_raw_transaction(node, params),
+        "gettransaction" => get_transaction(node, params),
+        "getaddresshistory" => get_address_history(node, params),
+        "getmerkleproof" => get_merkle_proof(node, params),
+        "verifymerkleproof" => verify_merkle_proof(node, params),
+        "sendrawtransaction" => send_raw_transaction(node, params),
+        "getbalance" => get_balance(node, params),
+        "listunspent" => list_unspent(node, params),
+        "getmempoolinfo" => get_mempool_info(node),
+        "getpeerinfo" => Ok(serde_json::to_value(node.get_peer_info()).map_err(failure::Error::from)?),
+        "generate" => generate(node, params),
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("method {} not found", method))),
+    }
+}
+
+/* ------------------- methods ----------------------------------*/
+
+fn get_block_count(node: &Server) -> RpcResult {
+    let height = node.with_state(|utxo, _| utxo.blockchain.get_best_height())?;
+    Ok(json!(height))
+}
+
+/// getblockhash <height>: the hash of the active chain block at a height
+fn get_block_hash(node: &Server, params: &[Value]) -> RpcResult {
+    let height = param_u64(params, 0, "height")?
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "missing height"))?;
+    let height = i32::try_from(height).map_err(|_| RpcError::new(INVALID_PARAMS, "height out of range"))?;
+    let hash = node
+        .with_state(|utxo, _| utxo.blockchain.get_block_hash(height))
+        .map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))?;
+    Ok(json!(hash))
+}
+
+/// getblock <hash> [verbosity]: the block as hex with verbosity 0, with
+/// transaction ids with 1, the default, and with whole transactions with 2
+fn get_block(node: &Server, params: &[Value]) -> RpcResult {
+    let hash = param_str(params, 0, "hash")?;
+    let verbosity = param_u64(params, 1, "verbosity")?.unwrap_or(1);
+    let (block, best_height) = node
+        .with_state(|utxo, _| Ok((utxo.blockchain.get_block(hash)?, utxo.blockchain.get_best_height()?)))
+        .map_err(|_| RpcError::new(INVALID_ADDRESS_OR_KEY, format!("block {} not found", hash)))?;
+    if verbosity == 0 {
+        return Ok(json!(to_hex(&serialize(&block).map_err(failure::Error::from)?)));
+    }
+
+    let network = node.get_params().network;
+    let mut result = block_json(&block, network, verbosity >= 2)?;
+    result["confirmations"] = json!(best_height - block.get_height() + 1);
+    Ok(result)
+}
+
+/// getrawtransaction <txid> [verbose]: the transaction as hex, or decoded
+/// along with the block holding it when verbose
+fn get_raw_transaction(node: &Server, params: &[Value]) -> RpcResult {
+    let txid = param_str(params, 0, "txid")?;
+    let verbose = param_bool(params, 1, "verbose")?.unwrap_or(false);
+    let (tx, block_hash) = match node.find_transaction(txid)? {
+        Some(found) => found,
+        None => return Err(RpcError::new(INVALID_ADDRESS_OR_KEY, format!("transaction {} not found", txid))),
+    };
+    let hex = to_hex(&serialize(&tx).map_err(failure::Error::from)?);
+    if !verbose {
+        return Ok(json!(hex));
+    }
+    let mut result = transaction_json(&tx, node.get_params().network);
+    result["hex"] = json!(hex);
+    result["blockhash"] = json!(block_hash);
+    Ok(result)
+}
+
+/// gettransaction <txid>: a transaction of the chain or the mempool with its
+/// inputs resolved to the addresses and values they spend
+fn get_transaction(node: &Server, params: &[Value]) -> RpcResult {
+    let txid = param_str(params, 0, "txid")?;
+    let network = node.get_params().network;
+    let details = node.with_state(|utxo, mempool| match mempool.get(txid) {
+        Some(tx) => explorer::describe_transaction(&utxo.blockchain, network, tx, None).map(Some),
+        None => explorer::get_transaction(&utxo.blockchain, network, txid),
+    })?;
+    match details {
+        Some(details) => Ok(serde_json::to_value(details).map_err(failure::Error::from)?),
+        None => Err(RpcError::new(INVALID_ADDRESS_OR_KEY, format!("transaction {} not found", txid))),
+    }
+}
+
+/// getaddresshistory <address>: the confirmed transactions of an address,
+/// oldest first, with the running balance
+fn get_address_history(node: &Server, params: &[Value]) -> RpcResult {
+    let pub_key_hash = param_address(node, params, 0)?;
+    let history = node.with_state(|utxo, _| explorer::get_address_history(&utxo.blockchain, &pub_key_hash))?;
+    Ok(serde_json::to_value(history).map_err(failure::Error::from)?)
+}
+
+/// getmerkleproof <txid>: proof that a transaction of the active chain is
+/// committed to by its block's header
+fn get_merkle_proof(node: &Server, params: &[Value]) -> RpcResult {
+    let txid = param_str(params, 0, "txid")?;
+    match node.with_state(|utxo, _| utxo.blockchain.get_merkle_proof(txid))? {
+        Some(proof) => Ok(merkle_proof_json(&proof)),
+        None => Err(RpcError::new(INVALID_ADDRESS_OR_KEY, format!("transaction {} not found", txid))),
+    }
+}
+
+/// verifymerkleproof <proof>: whether a proof, as getmerkleproof returns it,
+/// holds for a block of the active chain
+fn verify_merkle_proof(node: &Server, params: &[Value]) -> RpcResult {
+    let proof = params
+        .first()
+        .and_then(|value| merkle_proof_from_json(value).ok())
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "proof must be a merkle proof object"))?;
+    let valid = node.with_state(|utxo, _| utxo.blockchain.verify_merkle_proof(&proof))?;
+    Ok(json!(valid))
+}
+
+/// sendrawtransaction <hex>: submits a signed transaction, returning its id
+fn send_raw_transaction(node: &Server, params: &[Value]) -> RpcResult {
+    let hex = param_str(params, 0, "hex")?;
+    let tx: Transaction = from_hex(hex)
+        .ok()
+        .and_then(|data| deserialize(&data).ok())
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "hex does not decode to a transaction"))?;
+    if tx.id != tx.hash()? {
+        return Err(RpcError::new(VERIFY_REJECTED, "transaction id does not match its contents"));
+    }
+    let txid = tx.id.clone();
+    node.submit_transaction(tx)
+        .map_err(|e| RpcError::new(VERIFY_REJECTED, e.to_string()))?;
+    Ok(json!(txid))
+}
+
+/// getbalance <address>: the spendable and the immature value of an address
+fn get_balance(node: &Server, params: &[Value]) -> RpcResult {
+    let pub_key_hash = param_address(node, params, 0)?;
+    let (balance, immature) = node.with_state(|utxo, _| utxo.get_balance(&pub_key_hash))?;
+    Ok(json!({"balance": balance, "immature": immature}))
+}
+
+/// listunspent <address>: the unspent outputs of an address
+fn list_unspent(node: &Server, params: &[Value]) -> RpcResult {
+    let pub_key_hash = param_address(node, params, 0)?;
+    let outputs = node.with_state(|utxo, _| {
+        let best_height = utxo.blockchain.get_best_height()?;
+        let mut outputs = Vec::new();
+        for (outpoint, entry) in utxo.find_unspent(&pub_key_hash)? {
+            outputs.push(json!({
+                "txid": outpoint.txid,
+                "vout": outpoint.vout,
+                "value": entry.output.value,
+                "height": entry.height,
+                "confirmations": best_height - entry.height + 1,
+                "coinbase": entry.is_coinbase,
+                "spendable": utxo.is_spendable(&entry, best_height + 1),
+            }));
+        }
+        Ok(outputs)
+    })?;
+    Ok(Value::Array(outputs))
+}
+
+fn get_mempool_info(node: &Server) -> RpcResult {
+    let (size, bytes, fees) = node.with_state(|_, mempool| {
+        let bytes: usize = mempool.entries().map(|e| e.size).sum();
+        let fees: i64 = mempool.entries().map(|e| e.fee as i64).sum();
+        Ok((mempool.len(), bytes, fees))
+    })?;
+    Ok(json!({"size": size, "bytes": bytes, "fees": fees}))
+}
+
+/// generate <count> [address]: mines blocks right away on regtest, paying
+/// the node's mining address unless another one is given
+fn generate(node: &Server, params: &[Value]) -> RpcResult {
+    if node.get_params().network != Network::Regtest {
+        return Err(RpcError::new(MISC_ERROR, "generate is only available on regtest"));
+    }
+    let count = param_u64(params, 0, "count")?
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "missing count"))?;
+    let count = u32::try_from(count).map_err(|_| RpcError::new(INVALID_PARAMS, "count out of range"))?;
+    let address = match params.get(1) {
+        Some(_) => param_str(params, 1, "address")?,
+        None => node.get_mining_address(),
+    };
+    if address.is_empty() {
+        return Err(RpcError::new(INVALID_PARAMS, "no address to pay the block rewards to"));
+    }
+    param_address(node, &[json!(address)], 0)?;
+    Ok(json!(node.generate(count, address)?))
+}
+
+/* ------------------- client ----------------------------------*/
+
+/// Client calls the JSON-RPC endpoint of a running node
+pub struct Client {
+    addr: SocketAddr,
+    rpc: RpcParams,
+    network: Network,
+}
+
+impl Client {
+    /// connect returns a client of the node listening on the configured
+    /// endpoint, or None when no node is running there
+    pub fn connect(params: &NetworkParams) -> Result<Option<Client>> {
+        if params.rpc.bind_addr.is_empty() {
+            return Ok(None);
+        }
+        for addr in params.rpc.bind_addr.to_socket_addrs()? {
+            if TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).is_ok() {
+                return Ok(Some(Client {
+                    addr,
+                    rpc: params.rpc.clone(),
+                    network: params.network,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn get_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// call runs `method` on the node, returning its result
+    ///
+    /// An error answered by the node is returned as an RpcError.
+    pub fn call(&self, method: &str, params: Vec<Value>) -> Result<Value> {
+        let body = json!({"jsonrpc": "2.0", "method": method, "params": params, "id": 1}).to_string();
+        let mut head = format!(
+            "POST / HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+            self.addr,
+            body.len()
+        );
+        if !self.rpc.user.is_empty() {
+            let credentials = format!("{}:{}", self.rpc.user, self.rpc.password);
+            head += &format!("Authorization: Basic {}\r\n", STANDARD.encode(credentials));
+        }
+        head += "Connection: close\r\n\r\n";
+
+        let mut stream = TcpStream::connect_timeout(&self.addr, CONNECT_TIMEOUT)?;
+        stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(body.as_bytes())?;
+        let reply = read_message(&mut BufReader::new(stream))?;
+        if reply.start_line.split_whitespace().nth(1) != Some("200") {
+            return Err(format_err!("node at {} answered {}", self.addr, reply.start_line));
+        }
+
+        let mut reply: Value = serde_json::from_slice(&reply.body)?;
+        if let Some(error) = reply.get("error") {
+            return Err(RpcError::new(
+                error["code"].as_i64().unwrap_or(INTERNAL_ERROR),
+                error["message"].as_str().unwrap_or_default(),
+            )
+            .into());
+        }
+        Ok(reply["result"].take())
+    }
+
+    pub fn get_block(&self, hash: &str) -> Result<Block> {
+        let hex = self.call("getblock", vec![json!(hash), json!(0)])?;
+        Ok(deserialize(&from_hex(hex.as_str().unwrap_or_default())?)?)
+    }
+
+    pub fn get_raw_transaction(&self, txid: &str) -> Result<Transaction> {
+        let hex = self.call("getrawtransaction", vec![json!(txid)])?;
+        Ok(deserialize(&from_hex(hex.as_str().unwrap_or_default())?)?)
+    }
+
+    pub fn get_merkle_proof(&self, txid: &str) -> Result<MerkleProof> {
+        merkle_proof_from_json(&self.call("getmerkleproof", vec![json!(txid)])?)
+    }
+
+    pub fn verify_merkle_proof(&self, proof: &MerkleProof) -> Result<bool> {
+        let valid = self.call("verifymerkleproof", vec![merkle_proof_json(proof)])?;
+        Ok(serde_json::from_value(valid)?)
+    }
+
+    /// send_raw_transaction submits a signed transaction to the node
+    pub fn send_raw_transaction(&self, tx: &Transaction) -> Result<()> {
+        self.call("sendrawtransaction", vec![json!(to_hex(&serialize(tx)?))])?;
+        Ok(())
+    }
+}
+
+impl OutputSource for Client {
+    fn find_spendable_outputs(&self, pub_key_hash: &[u8], amount: i32) -> Result<(i32, HashMap<String, Vec<i32>>)> {
+        let address = self.network.encode_address(pub_key_hash.to_vec())?;
+        let unspent = self.call("listunspent", vec![json!(address)])?;
+        let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
+        let mut accumulated = 0;
+        for output in unspent.as_array().into_iter().flatten() {
+            if accumulated >= amount {
+                break;
+            }
+            if output["spendable"] != json!(true) {
+                continue;
+            }
+            accumulated += output["value"].as_i64().unwrap_or(0) as i32;
+            unspent_outputs
+                .entry(output["txid"].as_str().unwrap_or_default().to_string())
+                .or_default()
+                .push(output["vout"].as_i64().unwrap_or(-1) as i32);
+        }
+        Ok((accumulated, unspent_outputs))
+    }
+
+    fn sign_transaction(&self, tx: &mut Transaction, private_key: &[u8]) -> Result<()> {
+        let mut prev_txs = HashMap::new();
+        for vin in &tx.vin {
+            let prev = self.get_raw_transaction(&vin.txid)?;
+            prev_txs.insert(prev.id.clone(), prev);
+        }
+        tx.sign(private_key, prev_txs)
+    }
+}
+
+/* ------------------- helpers ----------------------------------*/
+
+/// block_json describes a block with the ids of its transactions, or the
+/// whole transactions when `full`; the command line prints the same schema
+pub(crate) fn block_json(block: &Block, network: Network, full: bool) -> Result<Value> {
+    let header = block.get_header();
+    let txs: Vec<Value> = block
+        .get_transaction()
+        .iter()
+        .map(|tx| if full { transaction_json(tx, network) } else { json!(tx.id) })
+        .collect();
+    Ok(json!({
+        "hash": header.hash,
+        "height": header.height,
+        "prev_block_hash": header.prev_block_hash,
+        "merkle_root": to_hex(&header.merkle_root),
+        "timestamp": header.timestamp as u64,
+        "bits": format!("{:08x}", header.bits),
+        "nonce": header.nonce,
+        "tx": txs,
+    }))
+}
+
+/// transaction_json describes a transaction, naming the addresses its inputs
+/// spend from and its outputs pay to on `network`
+///
+/// An output locked to a hash without an address on the network gets a null
+/// address; its public key hash is listed either way.
+pub(crate) fn transaction_json(tx: &Transaction, network: Network) -> Value {
+    let coinbase = tx.is_coinbase();
+    let vin: Vec<Value> = tx
+        .vin
+        .iter()
+        .map(|vin| {
+            if coinbase {
+                return json!({"txid": vin.txid, "vout": vin.vout});
+            }
+            let mut pub_key_hash = vin.pub_key.clone();
+            hash_pub_key(&mut pub_key_hash);
+            json!({"txid": vin.txid, "vout": vin.vout, "address": network.encode_address(pub_key_hash).ok()})
+        })
+        .collect();
+    let vout: Vec<Value> = tx
+        .vout
+        .iter()
+        .map(|out| {
+            json!({
+                "value": out.value,
+                "pub_key_hash": to_hex(&out.pub_key_hash),
+                "address": network.encode_address(out.pub_key_hash.clone()).ok(),
+            })
+        })
+        .collect();
+    json!({"txid": tx.id, "coinbase": coinbase, "vin": vin, "vout": vout})
+}
+
+fn param_str<'a>(params: &'a [Value], i: usize, name: &str) -> std::result::Result<&'a str, RpcError> {
+    params
+        .get(i)
+        .and_then(Value::as_str)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("{} must be a string", name)))
+}
+
+fn param_u64(params: &[Value], i: usize, name: &str) -> std::result::Result<Option<u64>, RpcError> {
+    match params.get(i) {
+        None => Ok(None),
+        Some(value) => value
+            .as_u64()
+            .map(Some)
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("{} must be a number", name))),
+    }
+}
+
+fn param_bool(params: &[Value], i: usize, name: &str) -> std::result::Result<Option<bool>, RpcError> {
+    match params.get(i) {
+        None => Ok(None),
+        Some(value) => value
+            .as_bool()
+            .map(Some)
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("{} must be a boolean", name))),
+    }
+}
+
+/// param_address returns the public key hash of an address of the node's network
+fn param_address(node: &Server, params: &[Value], i: usize) -> std::result::Result<Vec<u8>, RpcError> {
+    let address = param_str(params, i, "address")?;
+    node.get_params()
+        .network
+        .decode_address(address)
+        .map_err(|e| RpcError::new(INVALID_ADDRESS_OR_KEY, e.to_string()))
+}
+
+/// merkle_proof_json describes a merkle proof with its hashes hex encoded;
+/// the command line prints the same schema
+pub(crate) fn merkle_proof_json(proof: &MerkleProof) -> Value {
+    let lemmas: Vec<String> = proof.lemmas.iter().map(|lemma| to_hex(lemma)).collect();
+    json!({
+        "txid": proof.txid,
+        "block_hash": proof.block_hash,
+        "tx_count": proof.tx_count,
+        "index": proof.index,
+        "lemmas": lemmas,
+    })
+}
+
+/// merkle_proof_from_json reads a proof back from what merkle_proof_json
+/// produces
+pub(crate) fn merkle_proof_from_json(value: &Value) -> Result<MerkleProof> {
+    let field = |name: &str| value[name].as_str().ok_or_else(|| format_err!("proof has no {}", name));
+    let number = |name: &str| {
+        value[name]
+            .as_u64()
+            .and_then(|n| u32::try_from(n).ok())
+            .ok_or_else(|| format_err!("proof has no {}", name))
+    };
+    let lemmas = value["lemmas"]
+        .as_array()
+        .ok_or_else(|| format_err!("proof has no lemmas"))?
+        .iter()
+        .map(|lemma| from_hex(lemma.as_str().unwrap_or("?")))
+        .collect::<Result<Vec<Vec<u8>>>>()?;
+    Ok(MerkleProof {
+        txid: field("txid")?.to_string(),
+        block_hash: field("block_hash")?.to_string(),
+        tx_count: number("tx_count")?,
+        index: number("index")?,
+        lemmas,
+    })
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Result<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return Err(format_err!("invalid hex"));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&text[i..i + 2], 16)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::POW_LIMIT_BITS;
+    use crate::testutil::TestChain;
+    use crate::transaction::Fee;
+    use crate::tx::{TXInput, TXOutput};
+    use crate::wallets::Wallet;
+
+    fn call(node: &Server, body: &str) -> Value {
+        serde_json::from_str(&handle_body(node, body.as_bytes()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_rpc_calls() {
+        let chain = TestChain::new("rpc");
+        let node = Server::new(&chain.params, "", chain.utxo).unwrap();
+        let address = Wallet::new().get_network_address(Network::Regtest);
+
+        let r = call(&node, r#"{"jsonrpc": "2.0", "method": "getblockcount", "id": 7}"#);
+        assert_eq!(r, json!({"jsonrpc": "2.0", "result": 0, "id": 7}));
+
+        let body = json!({"jsonrpc": "2.0", "method": "generate", "params": [2, address], "id": 1});
+        let hashes = call(&node, &body.to_string())["result"].clone();
+        assert_eq!(hashes.as_array().unwrap().len(), 2);
+        let body = json!({"jsonrpc": "2.0", "method": "generate", "params": [1u64 << 32, address], "id": 1});
+        assert_eq!(call(&node, &body.to_string())["error"]["code"], json!(INVALID_PARAMS));
+        let body = json!({"jsonrpc": "2.0", "method": "getblockhash", "params": [1u64 << 32], "id": 1});
+        assert_eq!(call(&node, &body.to_string())["error"]["code"], json!(INVALID_PARAMS));
+
+        let body = json!([
+            {"jsonrpc": "2.0", "method": "getbalance", "params": [address], "id": 1},
+            {"jsonrpc": "2.0", "method": "getblock", "params": [hashes[1]], "id": 2},
+            {"jsonrpc": "2.0", "method": "getblockcount"},
+        ]);
+        let r = call(&node, &body.to_string());
+        assert_eq!(r.as_array().unwrap().len(), 2);
+        assert_eq!(r[0]["result"], json!({"balance": 200, "immature": 0}));
+        assert_eq!(r[1]["result"]["height"], json!(2));
+        let coinbase = r[1]["result"]["tx"][0].as_str().unwrap().to_string();
+
+        let body = json!({"jsonrpc": "2.0", "method": "getrawtransaction", "params": [coinbase, true], "id": 3});
+        let r = call(&node, &body.to_string());
+        assert_eq!(r["result"]["blockhash"], hashes[1]);
+        assert_eq!(r["result"]["vout"][0]["address"], json!(address));
+        let body = json!({"jsonrpc": "2.0", "method": "sendrawtransaction", "params": [r["result"]["hex"]], "id": 4});
+        assert_eq!(call(&node, &body.to_string())["error"]["code"], json!(VERIFY_REJECTED));
+
+        let body = json!({"jsonrpc": "2.0", "method": "getmerkleproof", "params": [coinbase], "id": 5});
+        let proof = call(&node, &body.to_string())["result"].clone();
+        assert_eq!(proof["block_hash"], hashes[1]);
+        assert_eq!(merkle_proof_json(&merkle_proof_from_json(&proof).unwrap()), proof);
+        let body = json!({"jsonrpc": "2.0", "method": "verifymerkleproof", "params": [proof], "id": 5});
+        assert_eq!(call(&node, &body.to_string())["result"], json!(true));
+        let mut forged = proof.clone();
+        forged["txid"] = json!("00".repeat(32));
+        let body = json!({"jsonrpc": "2.0", "method": "verifymerkleproof", "params": [forged], "id": 5});
+        assert_eq!(call(&node, &body.to_string())["result"], json!(false));
+
+        let main_address = Wallet::new().get_address();
+        let body = json!({"jsonrpc": "2.0", "method": "listunspent", "params": [main_address], "id": 5});
+        assert_eq!(call(&node, &body.to_string())["error"]["code"], json!(INVALID_ADDRESS_OR_KEY));
+        let r = call(&node, r#"{"jsonrpc": "2.0", "method": "stop", "id": 6}"#);
+        assert_eq!(r["error"]["code"], json!(METHOD_NOT_FOUND));
+        assert_eq!(call(&node, "{")["error"]["code"], json!(PARSE_ERROR));
+        assert_eq!(call(&node, "[]")["error"]["code"], json!(INVALID_REQUEST));
+        assert!(handle_body(&node, br#"{"jsonrpc": "2.0", "method": "getblockcount"}"#).is_none());
+    }
+
+    #[test]
+    fn test_client() {
+        let mut chain = TestChain::new("rpc-client");
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        chain.params.rpc.bind_addr = format!("127.0.0.1:{}", port);
+        chain.params.rpc.user = String::from("u");
+        chain.params.rpc.password = String::from("p");
+        let params = &chain.params;
+        let node = Server::new(params, "", chain.utxo).unwrap();
+        thread::spawn(move || serve(node));
+
+        let mut client = None;
+        for _ in 0..100 {
+            client = Client::connect(params).unwrap();
+            if client.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let client = client.expect("RPC server did not start");
+        let wallet = Wallet::new();
+        let address = wallet.get_network_address(Network::Regtest);
+        let hashes = client.call("generate", vec![json!(2), json!(address)]).unwrap();
+        let hash = hashes[1].as_str().unwrap();
+        assert_eq!(client.get_block(hash).unwrap().get_hash(), hash);
+        let err = client.call("stop", vec![]).unwrap_err();
+        assert_eq!(err.downcast::<RpcError>().unwrap().code, METHOD_NOT_FOUND);
+
+        // a wallet spends through the node's UTXO set
+        let mut pub_key_hash = wallet.public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        let (found, outputs) = client.find_spendable_outputs(&pub_key_hash, 1).unwrap();
+        assert_eq!((found, outputs.len()), (100, 1));
+        let to = Wallet::new().get_network_address(Network::Regtest);
+        let tx = Transaction::new_UTXO(&wallet, &to, 10, Fee::Absolute(1), &client).unwrap();
+        client.send_raw_transaction(&tx).unwrap();
+        assert_eq!(client.call("getmempoolinfo", vec![]).unwrap()["size"], json!(1));
+
+        let mut wrong = params.clone();
+        wrong.rpc.password = String::from("x");
+        let err = Client::connect(&wrong).unwrap().unwrap().call("getblockcount", vec![]).unwrap_err();
+        assert!(err.to_string().contains("401"), "{}", err);
+    }
+
+    #[test]
+    fn test_transaction_json() {
+        let wallet = Wallet::new();
+        let address = wallet.get_network_address(Network::Regtest);
+        let mut cbtx = Transaction::new_coinbase(wallet.get_address(), String::new(), 0, 50).unwrap();
+        let tx = Transaction {
+            id: String::from("ab"),
+            vin: vec![TXInput {
+                txid: cbtx.id.clone(),
+                vout: 0,
+                signature: Vec::new(),
+                pub_key: wallet.public_key.clone(),
+            }],
+            vout: vec![TXOutput {
+                value: 7,
+                pub_key_hash: vec![1; 5],
+            }],
+        };
+        assert_eq!(
+            transaction_json(&tx, Network::Regtest),
+            json!({
+                "txid": "ab",
+                "coinbase": false,
+                "vin": [{"txid": cbtx.id, "vout": 0, "address": address}],
+                // CashAddr has no address for a 5 byte hash
+                "vout": [{"value": 7, "pub_key_hash": "0101010101", "address": null}],
+            })
+        );
+
+        cbtx.vout.push(tx.vout[0].clone());
+        let block = Block::new_block(vec![cbtx.clone()], String::new(), 0, POW_LIMIT_BITS).unwrap();
+        let summary = block_json(&block, Network::Regtest, false).unwrap();
+        assert_eq!(summary["hash"], json!(block.get_hash()));
+        assert_eq!(summary["height"], json!(0));
+        assert_eq!(summary["bits"], json!(format!("{:08x}", POW_LIMIT_BITS)));
+        assert_eq!(summary["merkle_root"], json!(to_hex(&block.get_header().merkle_root)));
+        assert_eq!(summary["tx"], json!([cbtx.id]));
+        let full = block_json(&block, Network::Regtest, true).unwrap();
+        assert_eq!(full["tx"][0]["coinbase"], json!(true));
+        assert_eq!(full["tx"][0]["vin"][0], json!({"txid": "", "vout": -1}));
+        assert_eq!(full["tx"][0]["vout"][0]["address"], json!(address));
+        assert_eq!(full["tx"][0]["vout"][1]["address"], Value::Null);
+    }
+
+    #[test]
+    fn test_http_and_auth() {
+        let raw = "POST / HTTP/1.1\r\nHost: x\r\nAuthorization: Basic dTpw\r\ncontent-length: 2\r\n\r\n{}";
+        let request = read_message(&mut raw.as_bytes()).unwrap();
+        assert_eq!(
+            request,
+            HttpMessage {
+                start_line: String::from("POST / HTTP/1.1"),
+                authorization: Some(String::from("Basic dTpw")),
+                body: b"{}".to_vec(),
+            }
+        );
+        assert!(read_message(&mut "POST / HTTP/1.1\r\nContent-Length: 2\r\n".as_bytes()).is_err());
+        let long = format!("POST / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_HEAD_SIZE));
+        assert!(read_message(&mut long.as_bytes()).is_err());
+        let many = format!("POST / HTTP/1.1\r\n{}\r\n", "X: a\r\n".repeat(MAX_HEAD_SIZE / 6));
+        assert!(read_message(&mut many.as_bytes()).is_err());
+
+        let rpc = RpcParams {
+            user: String::from("u"),
+            password: String::from("p"),
+            ..RpcParams::default()
+        };
+        assert!(is_authorized(&rpc, request.authorization.as_deref()));
+        assert!(!is_authorized(&rpc, Some("Basic dTpx")));
+        assert!(!is_authorized(&rpc, None));
+        assert!(is_authorized(&RpcParams::default(), None));
+
+        let mut out = Vec::new();
+        write_response(&mut out, "401 Unauthorized", "").unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("WWW-Authenticate: Basic"));
+        assert_eq!(from_hex(&to_hex(&[0, 171, 255])).unwrap(), vec![0, 171, 255]);
+        assert!(from_hex("abc").is_err());
+    }
+}
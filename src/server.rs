@@ -2,21 +2,33 @@
 
 use super::*;
 use crate::block::*;
+use crate::blockchain::ChainUpdate;
+use crate::mempool::{Mempool, MempoolError};
+use crate::config::NetworkParams;
+use crate::light::FilteredBlock;
+use crate::net::{encode_frame, read_frame, WireError};
+use crate::peers::{Misbehavior, PeerManager};
+use crate::rpc;
+use crate::sync::BlockDownload;
 use crate::transaction::*;
 use crate::utxoset::*;
+use crate::tx::OutPoint;
+use crate::validation::{check_maturity, check_transaction, BlockError};
 use bincode::{deserialize, serialize};
 use failure::format_err;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
+use std::io::BufReader;
+use std::net::{IpAddr, TcpListener, TcpStream};
 use std::sync::*;
 use std::thread;
-use std::time::Duration;
-use log::{debug, info};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{debug, info, warn};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-enum Message {
+pub(crate) enum Message {
     Addr(Vec<String>),
     Version(Versionmsg),
     Tx(Txmsg),
@@ -24,85 +36,286 @@ enum Message {
     GetBlock(GetBlocksmsg),
     Inv(Invmsg),
     Block(Blockmsg),
+    GetHeaders(GetHeadersmsg),
+    Headers(Headersmsg),
+    GetProof(GetProofmsg),
+    Proof(Proofmsg),
+    FilterLoad(FilterLoadmsg),
+    MerkleBlock(MerkleBlockmsg),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-struct Blockmsg {
+pub(crate) struct Blockmsg {
     addr_from: String,
     block: Block,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-struct GetBlocksmsg {
+pub(crate) struct GetBlocksmsg {
     addr_from: String,
+    /// hashes describing the sender's active chain, newest first
+    locator: Vec<String>,
+    /// last block wanted, or empty for a full batch
+    stop_hash: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-struct GetDatamsg {
+pub(crate) struct GetHeadersmsg {
+    pub(crate) addr_from: String,
+    /// hashes describing the sender's best header chain, newest first
+    pub(crate) locator: Vec<String>,
+    /// last header wanted, or empty for as many as fit in one message
+    pub(crate) stop_hash: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Headersmsg {
+    pub(crate) addr_from: String,
+    pub(crate) headers: Vec<BlockHeader>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct GetProofmsg {
     addr_from: String,
-    kind: String,
-    id: String,
+    txid: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-struct Invmsg {
+pub(crate) struct Proofmsg {
     addr_from: String,
-    kind: String,
-    items: Vec<String>,
+    txid: String,
+    /// None when the transaction is not in the sender's active chain
+    proof: Option<MerkleProof>,
+}
+
+/// FilterLoadmsg registers the public key hashes a light client follows;
+/// its merkleblock requests are answered with the matching transactions
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct FilterLoadmsg {
+    pub(crate) addr_from: String,
+    pub(crate) pub_key_hashes: Vec<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct MerkleBlockmsg {
+    pub(crate) addr_from: String,
+    pub(crate) block: FilteredBlock,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct GetDatamsg {
+    pub(crate) addr_from: String,
+    pub(crate) kind: String,
+    pub(crate) id: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-struct Txmsg {
+pub(crate) struct Invmsg {
+    pub(crate) addr_from: String,
+    pub(crate) kind: String,
+    pub(crate) items: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Txmsg {
     addr_from: String,
     transaction: Transaction,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
-struct Versionmsg {
-    addr_from: String,
-    version: i32,
-    best_height: i32,
+pub(crate) struct Versionmsg {
+    pub(crate) addr_from: String,
+    pub(crate) version: i32,
+    /// bit set of the services the sender offers
+    pub(crate) services: u64,
+    pub(crate) best_height: i32,
 }
 
+#[derive(Clone)]
 pub struct Server {
     node_address: String,
     mining_address: String,
+    params: Arc<NetworkParams>,
     inner: Arc<Mutex<ServerInner>>,
+    peers: Arc<Mutex<Peers>>,
+}
+
+/// Peers holds the open connections, keyed by the listening address each
+/// peer announced, and the address book
+struct Peers {
+    connections: HashMap<String, Connection>,
+    /// accepted connections still open, registered or not
+    inbound: usize,
+    book: PeerManager,
+}
+
+struct Connection {
+    stream: TcpStream,
+    inbound: bool,
+    /// public key hashes a light client registered on this connection
+    filter: Option<HashSet<Vec<u8>>>,
+}
+
+/// PeerStatus describes an open connection to a peer
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct PeerStatus {
+    pub addr: String,
+    pub inbound: bool,
+    pub services: u64,
+    pub last_seen: u64,
+    pub ban_score: u32,
+}
+
+impl Peers {
+    fn outbound(&self) -> usize {
+        self.connections.values().filter(|c| !c.inbound).count()
+    }
 }
 
 struct ServerInner {
-    known_nodes: HashSet<String>,
     utxo: UTXOSet,
-    blocks_in_transit: Vec<String>,
-    mempool: HashMap<String, Transaction>,
+    mempool: Mempool,
+    download: BlockDownload,
+    /// downloaded blocks waiting for their parent, keyed by the parent's hash
+    orphans: HashMap<String, Vec<Block>>,
 }
 
-const KNOWN_NODE1: &str = "localhost:3000";
-const CMD_LEN: usize = 12;
-const VERSION: i32 = 1;
+impl ServerInner {
+    /// accept_transaction checks a transaction against the UTXO set and the
+    /// mempool and adds it to the mempool
+    ///
+    /// Inputs may spend unspent outputs or outputs of other mempool
+    /// transactions. A transaction spending an output another mempool
+    /// transaction already spends is subject to the mempool replacement policy.
+    fn accept_transaction(&mut self, tx: Transaction) -> Result<Vec<Transaction>> {
+        if tx.is_coinbase() || self.mempool.contains(&tx.id) {
+            return Err(MempoolError::AlreadyKnown(tx.id).into());
+        }
+        // what would keep the transaction out of a block keeps it out of the
+        // mempool, or every template including it would fail validation
+        check_transaction(&tx).map_err(|e| match e.downcast::<BlockError>() {
+            Ok(reason) => MempoolError::Invalid(reason).into(),
+            Err(e) => e,
+        })?;
+
+        let mut prev_txs = HashMap::new();
+        for vin in &tx.vin {
+            let outpoint = OutPoint::new(&vin.txid, vin.vout);
+            let prev = if self.utxo.get_entry(&outpoint)?.is_some() {
+                self.utxo.blockchain.find_transacton(&vin.txid)?
+            } else {
+                match self.mempool.get(&vin.txid) {
+                    Some(prev) if vin.vout >= 0 && (vin.vout as usize) < prev.vout.len() => {
+                        prev.clone()
+                    }
+                    _ => {
+                        return Err(MempoolError::MissingInput {
+                            txid: vin.txid.clone(),
+                            vout: vin.vout,
+                        }
+                        .into())
+                    }
+                }
+            };
+            prev_txs.insert(prev.id.clone(), prev);
+        }
+
+        let height = self.utxo.blockchain.get_best_height()? + 1;
+        check_maturity(&self.utxo.blockchain, &tx, &prev_txs, height)?;
+        if !tx.verify(prev_txs.clone())? {
+            return Err(MempoolError::InvalidSignature(tx.id).into());
+        }
+        let fee = tx
+            .fee(&prev_txs)?
+            .ok_or_else(|| MempoolError::ValueOverflow(tx.id.clone()))?;
+        if fee < 0 {
+            return Err(MempoolError::OutputsExceedInputs(tx.id).into());
+        }
+        self.mempool.insert(tx, fee)
+    }
+
+    /// apply_chain_update brings the UTXO set to the active chain's new tip
+    ///
+    /// The chain has already moved when this runs. If the set can't follow,
+    /// for example because an undo record is missing, it is rebuilt from the
+    /// active chain so the two never disagree.
+    fn apply_chain_update(&self, update: &ChainUpdate) -> Result<()> {
+        let result = update
+            .disconnected
+            .iter()
+            .try_for_each(|b| self.utxo.rollback(b))
+            .and_then(|_| update.connected.iter().try_for_each(|b| self.utxo.update(b)));
+        if let Err(e) = result {
+            warn!("UTXO set could not follow the chain, reindexing it: {}", e);
+            self.utxo.reindex()?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) const VERSION: i32 = 1;
+/// Service bit of nodes serving the full block chain
+const NODE_NETWORK: u64 = 1;
+/// Number of connections accepted from other nodes
+const MAX_INBOUND: usize = 32;
+/// Number of connections opened to other nodes
+const MAX_OUTBOUND: usize = 8;
+/// Penalty for relaying a block or header that fails validation
+const INVALID_BLOCK_PENALTY: u32 = 100;
+/// Penalty for relaying a block timestamped too far in the future, which
+/// may only be a clock difference
+const FUTURE_BLOCK_PENALTY: u32 = 10;
+/// Number of public key hashes a light client may register
+const MAX_FILTER_LEN: usize = 1000;
+/// Penalty for answering with a merkle proof that does not hold
+const INVALID_PROOF_PENALTY: u32 = 20;
+/// Penalty for relaying a transaction that can never be valid
+const INVALID_TX_PENALTY: u32 = 10;
+/// Number of headers sent in reply to one getheaders message
+pub(crate) const MAX_HEADERS_PER_MSG: usize = 2000;
+/// Number of block hashes sent in reply to one getblocks message
+const MAX_BLOCKS_PER_INV: usize = 500;
+/// Number of missing blocks scheduled for download at a time
+const DOWNLOAD_WINDOW: usize = 1024;
+/// Number of downloaded blocks kept while their parent is missing
+const MAX_ORPHAN_BLOCKS: usize = 256;
+/// Time a write to a peer may block before the connection is given up
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Serialized size of the transactions a miner puts into one block
+const MAX_BLOCK_TEMPLATE_SIZE: usize = 100_000;
 
 impl Server {
-    pub fn new(port: &str, miner_address: &str, utxo: UTXOSet) -> Result<Server> {
-        let mut node_set = HashSet::new();
-        node_set.insert(String::from(KNOWN_NODE1));
+    /// new creates a node of the network `params` describes, advertising
+    /// itself at the advertised address
+    pub fn new(params: &NetworkParams, miner_address: &str, utxo: UTXOSet) -> Result<Server> {
+        let mut book = PeerManager::open(&params.data_path("peers"))?;
+        if book.is_empty() {
+            for seed in &params.seeds {
+                if *seed != params.advertised_addr {
+                    book.add(seed)?;
+                }
+            }
+        }
         Ok(Server {
-            node_address: String::from("localhost:") + port,
+            node_address: params.advertised_addr.clone(),
             mining_address: miner_address.to_string(),
+            params: Arc::new(params.clone()),
             inner: Arc::new(Mutex::new(ServerInner {
-                known_nodes: node_set,
                 utxo,
-                blocks_in_transit: Vec::new(),
-                mempool: HashMap::new(),
+                mempool: Mempool::new(),
+                download: BlockDownload::new(),
+                orphans: HashMap::new(),
+            })),
+            peers: Arc::new(Mutex::new(Peers {
+                connections: HashMap::new(),
+                inbound: 0,
+                book,
             })),
         })
     }
 
     pub fn start_server(&self) -> Result<()> {
-        let server1 = Server {
-            node_address: self.node_address.clone(),
-            mining_address: self.mining_address.clone(),
-            inner: Arc::clone(&self.inner),
-        };
+        let server1 = self.clone();
         info!(
             "Start server at {}, minning address: {}",
             &self.node_address, &self.mining_address
@@ -113,143 +326,445 @@ impl Server {
             if server1.get_best_height()? == -1 {
                 server1.request_blocks()
             } else {
-                server1.send_version(KNOWN_NODE1)
+                Ok(())
+            }
+        });
+
+        // peers from the address book are dialed as outbound slots free up
+        let server1 = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            if let Err(e) = server1.check_downloads() {
+                warn!("block download check failed: {}", e);
+            }
+            if let Err(e) = server1.connect_peers() {
+                warn!("connecting to peers failed: {}", e);
             }
         });
 
-        let listener = TcpListener::bind(&self.node_address).unwrap();
+        if !self.params.rpc.bind_addr.is_empty() {
+            let server1 = self.clone();
+            thread::spawn(move || {
+                if let Err(e) = rpc::serve(server1) {
+                    warn!("RPC server stopped: {}", e);
+                }
+            });
+        }
+
+        let listener = TcpListener::bind(&self.params.bind_addr)?;
         info!("Server listen...");
 
         for stream in listener.incoming() {
-            let stream = stream?;
-            let server1 = Server {
-                node_address: self.node_address.clone(),
-                mining_address: self.mining_address.clone(),
-                inner: Arc::clone(&self.inner),
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("failed to accept a connection: {}", e);
+                    continue;
+                }
             };
-            thread::spawn(move || server1.handle_connection(stream));
+            if !self.admit_inbound(&stream) {
+                continue;
+            }
+            let server1 = self.clone();
+            thread::spawn(move || server1.handle_connection(stream, None));
         }
 
         Ok(())
     }
 
-    pub fn send_transaction(tx: &Transaction, utxoset: UTXOSet) -> Result<()> {
-        let server = Server::new("7000", "", utxoset)?;
-        server.send_tx(KNOWN_NODE1, tx)?;
+    /// send_transaction hands a transaction to the seed nodes of the network
+    pub fn send_transaction(params: &NetworkParams, tx: &Transaction, utxoset: UTXOSet) -> Result<()> {
+        let server = Server::new(params, "", utxoset)?;
+        for seed in &params.seeds {
+            server.send_tx(seed, tx)?;
+        }
         Ok(())
     }
 
-    /* ------------------- inner halp functions ----------------------------------*/
+    /* ------------------- state offered to the RPC interface -------------------*/
 
-    fn remove_node(&self, addr: &str) {
-        self.inner.lock().unwrap().known_nodes.remove(addr);
+    pub(crate) fn get_params(&self) -> &NetworkParams {
+        &self.params
     }
 
-    fn add_nodes(&self, addr: &str) {
-        self.inner
-            .lock()
-            .unwrap()
-            .known_nodes
-            .insert(String::from(addr));
+    pub(crate) fn get_mining_address(&self) -> &str {
+        &self.mining_address
     }
 
-    fn get_known_nodes(&self) -> HashSet<String> {
-        self.inner.lock().unwrap().known_nodes.clone()
+    /// with_state runs `f` on the UTXO set and the mempool, holding the
+    /// node's lock so both describe the same chain tip
+    pub(crate) fn with_state<T>(&self, f: impl FnOnce(&UTXOSet, &Mempool) -> Result<T>) -> Result<T> {
+        let inner = self.inner.lock().unwrap();
+        f(&inner.utxo, &inner.mempool)
     }
 
-    fn node_is_known(&self, addr: &str) -> bool {
-        self.inner.lock().unwrap().known_nodes.get(addr).is_some()
+    /// find_transaction looks a transaction up in the mempool and then in the
+    /// active chain, returning it with the hash of the block holding it
+    pub(crate) fn find_transaction(&self, txid: &str) -> Result<Option<(Transaction, Option<String>)>> {
+        let inner = self.inner.lock().unwrap();
+        if let Some(tx) = inner.mempool.get(txid) {
+            return Ok(Some((tx.clone(), None)));
+        }
+        let found = inner.utxo.blockchain.locate_transaction(txid)?;
+        Ok(found.map(|(tx, location)| (tx, Some(location.block_hash))))
     }
 
-    fn replace_in_transit(&self, hashs: Vec<String>) {
-        let bit = &mut self.inner.lock().unwrap().blocks_in_transit;
-        bit.clone_from(&hashs);
+    /// submit_transaction adds a transaction from a local client to the
+    /// mempool, announces it to the known nodes and mines it when this node
+    /// is a miner
+    pub(crate) fn submit_transaction(&self, tx: Transaction) -> Result<()> {
+        for replaced in self.insert_mempool(tx.clone())? {
+            info!("tx {} replaced by {}", replaced.id, tx.id);
+        }
+        for node in self.get_known_nodes() {
+            self.send_inv(&node, "tx", vec![tx.id.clone()])?;
+        }
+        if !self.mining_address.is_empty() {
+            let (txs, fees) = self.select_transactions()?;
+            if !txs.is_empty() {
+                self.mine_template(txs, fees, &self.mining_address)?;
+            }
+        }
+        Ok(())
     }
 
-    fn get_in_transit(&self) -> Vec<String> {
-        self.inner.lock().unwrap().blocks_in_transit.clone()
+    /// generate mines `count` blocks right away from the mempool, paying the
+    /// rewards to `address`, and returns their hashes
+    pub(crate) fn generate(&self, count: u32, address: &str) -> Result<Vec<String>> {
+        let mut hashes = Vec::new();
+        for _ in 0..count {
+            let (txs, fees) = self.select_transactions()?;
+            hashes.push(self.mine_template(txs, fees, address)?.get_hash());
+        }
+        Ok(hashes)
     }
 
-    fn get_mempool_tx(&self, addr: &str) -> Option<Transaction> {
-        match self.inner.lock().unwrap().mempool.get(addr) {
-            Some(tx) => Some(tx.clone()),
-            None => None,
+    /// get_peer_info describes the open connections, sorted by address
+    pub(crate) fn get_peer_info(&self) -> Vec<PeerStatus> {
+        let peers = self.peers.lock().unwrap();
+        let mut status: Vec<PeerStatus> = peers
+            .connections
+            .iter()
+            .map(|(addr, conn)| {
+                let info = peers.book.get(addr);
+                let ip = conn.stream.peer_addr().ok().map(|a| a.ip());
+                PeerStatus {
+                    addr: addr.clone(),
+                    inbound: conn.inbound,
+                    services: info.map_or(0, |i| i.services),
+                    last_seen: info.map_or(0, |i| i.last_seen),
+                    ban_score: ip.map_or(0, |ip| peers.book.get_score(ip)),
+                }
+            })
+            .collect();
+        status.sort_by(|a, b| a.addr.cmp(&b.addr));
+        status
+    }
+
+    /* ------------------- inner halp functions ----------------------------------*/
+
+    fn add_nodes(&self, addr: &str) -> Result<()> {
+        if addr != self.node_address {
+            self.peers.lock().unwrap().book.add(addr)?;
         }
+        Ok(())
     }
 
-    fn get_mempool(&self) -> HashMap<String, Transaction> {
-        self.inner.lock().unwrap().mempool.clone()
+    fn get_known_nodes(&self) -> Vec<String> {
+        self.peers.lock().unwrap().book.addresses()
     }
 
-    fn insert_mempool(&self, tx: Transaction) {
-        self.inner.lock().unwrap().mempool.insert(tx.id.clone(), tx);
+    fn get_mempool_tx(&self, addr: &str) -> Option<Transaction> {
+        self.inner.lock().unwrap().mempool.get(addr).cloned()
     }
 
-    fn clear_mempool(&self) {
-        self.inner.lock().unwrap().mempool.clear()
+    /// insert_mempool validates a transaction and adds it to the mempool,
+    /// returning the transactions it replaced
+    fn insert_mempool(&self, tx: Transaction) -> Result<Vec<Transaction>> {
+        self.inner.lock().unwrap().accept_transaction(tx)
     }
 
     fn get_best_height(&self) -> Result<i32> {
         self.inner.lock().unwrap().utxo.blockchain.get_best_height()
     }
 
-    fn get_block_hashs(&self) -> Vec<String> {
-        self.inner.lock().unwrap().utxo.blockchain.get_block_hashs()
+    fn get_block_subsidy(&self, height: i32) -> i32 {
+        self.inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .get_block_subsidy(height)
     }
 
-    fn get_block(&self, block_hash: &str) -> Result<Block> {
+    fn get_header(&self, block_hash: &str) -> Result<BlockHeader> {
         self.inner
             .lock()
             .unwrap()
             .utxo
             .blockchain
-            .get_block(block_hash)
+            .get_header(block_hash)
     }
 
-    fn verify_tx(&self, tx: &Transaction) -> Result<bool> {
+    fn get_block(&self, block_hash: &str) -> Result<Block> {
         self.inner
             .lock()
             .unwrap()
             .utxo
             .blockchain
-            .verify_transacton(tx)
+            .get_block(block_hash)
     }
 
-    fn add_block(&self, block: Block) -> Result<()> {
-        self.inner.lock().unwrap().utxo.blockchain.add_block(block)
+    /// add_block accepts a block and brings the UTXO set and mempool in line
+    /// with the resulting active chain
+    fn add_block(&self, block: Block) -> Result<ChainUpdate> {
+        let mut inner = self.inner.lock().unwrap();
+        let update = inner.utxo.blockchain.add_block(block)?;
+        inner.apply_chain_update(&update)?;
+
+        for b in &update.connected {
+            for tx in inner.mempool.remove_block(b) {
+                info!("drop tx {} from mempool, it conflicts with block {}", tx.id, b.get_hash());
+            }
+        }
+        // oldest block first, so parents go back in before the transactions spending them
+        for b in update.disconnected.iter().rev() {
+            for tx in b.get_transaction() {
+                if tx.is_coinbase() {
+                    continue;
+                }
+                if let Err(e) = inner.accept_transaction(tx.clone()) {
+                    info!("drop tx {} of disconnected block {}: {}", tx.id, b.get_hash(), e);
+                }
+            }
+        }
+        Ok(update)
     }
 
     fn mine_block(&self, txs: Vec<Transaction>) -> Result<Block> {
-        self.inner.lock().unwrap().utxo.blockchain.mine_block(txs)
+        let mut inner = self.inner.lock().unwrap();
+        let block = inner.utxo.blockchain.mine_block(txs)?;
+        inner.apply_chain_update(&ChainUpdate {
+            connected: vec![block.clone()],
+            ..ChainUpdate::default()
+        })?;
+        inner.mempool.remove_block(&block);
+        Ok(block)
     }
 
-    fn utxo_reindex(&self) -> Result<()> {
-        self.inner.lock().unwrap().utxo.reindex()
+    /// mine_template mines a block of `txs` whose coinbase pays the subsidy
+    /// and `fees` to `address`, and announces it to the known nodes
+    fn mine_template(&self, mut txs: Vec<Transaction>, fees: i32, address: &str) -> Result<Block> {
+        let height = self.get_best_height()? + 1;
+        let cbtx = Transaction::new_coinbase(
+            address.to_string(),
+            String::new(),
+            height,
+            self.get_block_subsidy(height)
+                .checked_add(fees)
+                .ok_or_else(|| format_err!("block reward overflows"))?,
+        )?;
+        txs.insert(0, cbtx);
+
+        let new_block = self.mine_block(txs)?;
+
+        for node in self.get_known_nodes() {
+            if node != self.node_address {
+                self.send_inv(&node, "block", vec![new_block.get_hash()])?;
+            }
+        }
+        Ok(new_block)
+    }
+
+    /// select_transactions builds a block template from the mempool
+    ///
+    /// Transactions are taken by descending fee rate until the template is
+    /// full. Ones that spend outputs of other mempool transactions wait for a
+    /// later block. The mempool never holds two spends of one output, so the
+    /// template is free of double spends. Returns the transactions and the
+    /// fees they pay.
+    fn select_transactions(&self) -> Result<(Vec<Transaction>, i32)> {
+        let inner = self.inner.lock().unwrap();
+        let height = inner.utxo.blockchain.get_best_height()? + 1;
+        let subsidy = inner.utxo.blockchain.get_block_subsidy(height);
+        let mut candidates = Vec::new();
+        for entry in inner.mempool.entries() {
+            let tx = &entry.tx;
+            let in_utxo_set = tx
+                .vin
+                .iter()
+                .all(|vin| inner.mempool.get(&vin.txid).is_none());
+            if !in_utxo_set {
+                continue;
+            }
+            match inner.utxo.blockchain.verify_transacton(tx) {
+                Ok(true) => {}
+                _ => continue,
+            }
+            candidates.push((entry.fee, entry.size, tx.clone()));
+        }
+        // a.fee / a.size > b.fee / b.size, compared without division
+        candidates.sort_by(|a, b| {
+            (b.0 as i64 * a.1 as i64).cmp(&(a.0 as i64 * b.1 as i64))
+        });
+
+        let mut txs = Vec::new();
+        let mut size = 0;
+        let mut fees: i32 = 0;
+        for (fee, tx_size, tx) in candidates {
+            if size + tx_size > MAX_BLOCK_TEMPLATE_SIZE {
+                continue;
+            }
+            // the coinbase has to be able to claim the subsidy and every fee
+            let total = match fees.checked_add(fee) {
+                Some(total) if total.checked_add(subsidy).is_some() => total,
+                _ => continue,
+            };
+            size += tx_size;
+            fees = total;
+            txs.push(tx);
+        }
+        Ok((txs, fees))
     }
 
     /* -----------------------------------------------------*/
 
-    fn send_data(&self, addr: &str, data: &[u8]) -> Result<()> {
-        if addr == &self.node_address {
+    /// send_message frames `msg` and writes it to the connection with `addr`
+    ///
+    /// The connection is opened on first use and kept for later messages;
+    /// whatever the peer sends back on it is handled like inbound traffic.
+    fn send_message<T: Serialize>(&self, addr: &str, command: &str, msg: &T) -> Result<()> {
+        if addr == self.node_address {
             return Ok(());
         }
+        let frame = encode_frame(self.params.magic_bytes(), command, &serialize(msg)?)?;
+
+        let existing = self
+            .peers
+            .lock()
+            .unwrap()
+            .connections
+            .get(addr)
+            .map(|conn| conn.stream.try_clone())
+            .transpose()?;
+        if let Some(mut stream) = existing {
+            if stream.write_all(&frame).is_ok() {
+                debug!("send {} to {}", command, addr);
+                return Ok(());
+            }
+            self.drop_connection(addr, &stream);
+        }
+
+        {
+            let peers = self.peers.lock().unwrap();
+            if peers.outbound() >= MAX_OUTBOUND {
+                debug!("drop {} to {}, no outbound slot left", command, addr);
+                return Ok(());
+            }
+            if !peers.book.can_dial(addr, unix_time()) {
+                debug!("drop {} to {}, waiting to retry", command, addr);
+                return Ok(());
+            }
+        }
+
         let mut stream = match TcpStream::connect(addr) {
             Ok(s) => s,
-            Err(_) => {
-                self.remove_node(addr);
+            Err(e) => {
+                info!("connecting to {} failed: {}", addr, e);
+                self.peers
+                    .lock()
+                    .unwrap()
+                    .book
+                    .record_failure(addr, unix_time())?;
                 return Ok(());
             }
         };
+        if self.is_banned(stream.peer_addr()?.ip()) {
+            return Ok(());
+        }
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        stream.write_all(&frame)?;
+        let reader = stream.try_clone()?;
+        let server1 = self.clone();
+        let peer = addr.to_string();
+        thread::spawn(move || server1.handle_connection(reader, Some(peer)));
+        self.peers.lock().unwrap().connections.insert(
+            addr.to_string(),
+            Connection {
+                stream,
+                inbound: false,
+                filter: None,
+            },
+        );
+
+        debug!("send {} to {} on a new connection", command, addr);
+        Ok(())
+    }
+
+    /// request_downloads schedules the blocks of the best header chain that
+    /// are still missing and asks the serving peers for them
+    fn request_downloads(&self) -> Result<()> {
+        let requests = {
+            let mut inner = self.inner.lock().unwrap();
+            let missing: Vec<String> = inner
+                .utxo
+                .blockchain
+                .get_missing_blocks(DOWNLOAD_WINDOW)?
+                .into_iter()
+                .filter(|hash| {
+                    !inner
+                        .orphans
+                        .values()
+                        .any(|blocks| blocks.iter().any(|b| &b.get_hash() == hash))
+                })
+                .collect();
+            inner.download.enqueue(missing);
+            inner.download.next_requests(Instant::now())
+        };
+        for (peer, hash) in requests {
+            self.send_get_data(&peer, "block", &hash)?;
+        }
+        Ok(())
+    }
 
-        stream.write(data)?;
+    /// check_downloads retries block requests that timed out
+    ///
+    /// When no peer is left to download from, every known node is asked for
+    /// headers so that the ones that answer take over.
+    fn check_downloads(&self) -> Result<()> {
+        let stalled = {
+            let mut inner = self.inner.lock().unwrap();
+            for peer in inner.download.expire(Instant::now()) {
+                warn!("stop downloading blocks from {}, it keeps timing out", peer);
+            }
+            !inner.download.is_idle() && !inner.download.has_peers()
+        };
+        if stalled {
+            for node in self.get_known_nodes() {
+                self.send_get_headers(&node)?;
+            }
+        }
+        self.request_downloads()
+    }
 
-        info!("data send successfully");
+    /// connect_peers fills free outbound slots with peers from the address book
+    fn connect_peers(&self) -> Result<()> {
+        let candidates = {
+            let peers = self.peers.lock().unwrap();
+            let free = MAX_OUTBOUND.saturating_sub(peers.outbound());
+            let mut connected: HashSet<String> = peers.connections.keys().cloned().collect();
+            connected.insert(self.node_address.clone());
+            peers.book.candidates(&connected, free, unix_time())
+        };
+        for addr in candidates {
+            self.send_version(&addr)?;
+        }
         Ok(())
     }
 
     fn request_blocks(&self) -> Result<()> {
         for node in self.get_known_nodes() {
-            self.send_get_blocks(&node)?
+            self.send_get_blocks(&node, None)?
         }
         Ok(())
     }
@@ -260,15 +775,13 @@ impl Server {
             addr_from: self.node_address.clone(),
             block: b.clone(),
         };
-        let data = serialize(&(cmd_to_bytes("block"), data))?;
-        self.send_data(addr, &data)
+        self.send_message(addr, "block", &data)
     }
 
     fn send_addr(&self, addr: &str) -> Result<()> {
         info!("send address info to: {}", addr);
         let nodes = self.get_known_nodes();
-        let data = serialize(&(cmd_to_bytes("addr"), nodes))?;
-        self.send_data(addr, &data)
+        self.send_message(addr, "addr", &nodes)
     }
 
     fn send_inv(&self, addr: &str, kind: &str, items: Vec<String>) -> Result<()> {
@@ -281,17 +794,66 @@ impl Server {
             kind: kind.to_string(),
             items,
         };
-        let data = serialize(&(cmd_to_bytes("inv"), data))?;
-        self.send_data(addr, &data)
+        self.send_message(addr, "inv", &data)
     }
 
-    fn send_get_blocks(&self, addr: &str) -> Result<()> {
+    /// send_get_blocks asks a peer for the block hashes after our active chain,
+    /// or after `from` when continuing a previous batch
+    fn send_get_blocks(&self, addr: &str, from: Option<&str>) -> Result<()> {
         info!("send get blocks message to: {}", addr);
+        let mut locator = self.inner.lock().unwrap().utxo.blockchain.get_chain_locator()?;
+        if let Some(hash) = from {
+            locator.insert(0, hash.to_string());
+        }
         let data = GetBlocksmsg {
             addr_from: self.node_address.clone(),
+            locator,
+            stop_hash: String::new(),
+        };
+        self.send_message(addr, "getblocks", &data)
+    }
+
+    fn send_get_headers(&self, addr: &str) -> Result<()> {
+        info!("send get headers message to: {}", addr);
+        let data = GetHeadersmsg {
+            addr_from: self.node_address.clone(),
+            locator: self.inner.lock().unwrap().utxo.blockchain.get_block_locator()?,
+            stop_hash: String::new(),
+        };
+        self.send_message(addr, "getheaders", &data)
+    }
+
+    fn send_headers(&self, addr: &str, headers: Vec<BlockHeader>) -> Result<()> {
+        info!("send {} headers to: {}", headers.len(), addr);
+        let data = Headersmsg {
+            addr_from: self.node_address.clone(),
+            headers,
         };
-        let data = serialize(&(cmd_to_bytes("getblocks"), data))?;
-        self.send_data(addr, &data)
+        self.send_message(addr, "headers", &data)
+    }
+
+    fn send_proof(&self, addr: &str, txid: &str, proof: Option<MerkleProof>) -> Result<()> {
+        info!("send merkle proof of {} to: {}", txid, addr);
+        let data = Proofmsg {
+            addr_from: self.node_address.clone(),
+            txid: txid.to_string(),
+            proof,
+        };
+        self.send_message(addr, "proof", &data)
+    }
+
+    fn send_merkle_block(&self, addr: &str, block: FilteredBlock) -> Result<()> {
+        info!(
+            "send merkle block {} with {} transactions to: {}",
+            block.header.hash,
+            block.transactions.len(),
+            addr
+        );
+        let data = MerkleBlockmsg {
+            addr_from: self.node_address.clone(),
+            block,
+        };
+        self.send_message(addr, "merkleblock", &data)
     }
 
     fn send_get_data(&self, addr: &str, kind: &str, id: &str) -> Result<()> {
@@ -304,8 +866,7 @@ impl Server {
             kind: kind.to_string(),
             id: id.to_string(),
         };
-        let data = serialize(&(cmd_to_bytes("getdata"), data))?;
-        self.send_data(addr, &data)
+        self.send_message(addr, "getdata", &data)
     }
 
     pub fn send_tx(&self, addr: &str, tx: &Transaction) -> Result<()> {
@@ -314,8 +875,7 @@ impl Server {
             addr_from: self.node_address.clone(),
             transaction: tx.clone(),
         };
-        let data = serialize(&(cmd_to_bytes("tx"), data))?;
-        self.send_data(addr, &data)
+        self.send_message(addr, "tx", &data)
     }
 
     fn send_version(&self, addr: &str) -> Result<()> {
@@ -324,32 +884,46 @@ impl Server {
             addr_from: self.node_address.clone(),
             best_height: self.get_best_height()?,
             version: VERSION,
+            services: NODE_NETWORK,
         };
-        let data = serialize(&(cmd_to_bytes("version"), data))?;
-        self.send_data(addr, &data)
+        self.send_message(addr, "version", &data)
     }
 
-    fn handle_version(&self, msg: Versionmsg) -> Result<()> {
+    /// handle_version answers a peer's version message
+    ///
+    /// Replies go to the connection `msg.addr_from` names. `advertised` is
+    /// the listening address the peer announced, which is only used for the
+    /// address book.
+    fn handle_version(&self, msg: Versionmsg, advertised: &str) -> Result<()> {
         info!("receive version msg: {:#?}", msg);
         let my_best_height = self.get_best_height()?;
         if my_best_height < msg.best_height {
-            self.send_get_blocks(&msg.addr_from)?;
+            self.send_get_headers(&msg.addr_from)?;
         } else if my_best_height > msg.best_height {
             self.send_version(&msg.addr_from)?;
         }
 
         self.send_addr(&msg.addr_from)?;
-
-        if !self.node_is_known(&msg.addr_from) {
-            self.add_nodes(&msg.addr_from);
+        // light clients announce addresses nobody else can sync from, so only
+        // full nodes are remembered and passed on to other peers
+        let mut peers = self.peers.lock().unwrap();
+        if msg.services & NODE_NETWORK == 0 {
+            // only a connection to the address itself may take it out of the book
+            if advertised == msg.addr_from {
+                peers.book.remove(advertised)?;
+            }
+            return Ok(());
         }
-        Ok(())
+        if advertised.is_empty() || advertised == self.node_address {
+            return Ok(());
+        }
+        peers.book.mark_seen(advertised, Some(msg.services), unix_time())
     }
 
     fn handle_addr(&self, msg: Vec<String>) -> Result<()> {
         info!("receive address msg: {:#?}", msg);
         for node in msg {
-            self.add_nodes(&node);
+            self.add_nodes(&node)?;
         }
         //self.request_blocks()?;
         Ok(())
@@ -361,36 +935,211 @@ impl Server {
             msg.addr_from,
             msg.block.get_hash()
         );
-        self.add_block(msg.block)?;
-
-        let mut in_transit = self.get_in_transit();
-        if in_transit.len() > 0 {
-            let block_hash = &in_transit[0];
-            self.send_get_data(&msg.addr_from, "block", block_hash)?;
-            in_transit.remove(0);
-            self.replace_in_transit(in_transit);
-        } else {
-            self.utxo_reindex()?;
+        self.inner
+            .lock()
+            .unwrap()
+            .download
+            .received(&msg.block.get_hash());
+
+        let mut pending = vec![msg.block];
+        while let Some(block) = pending.pop() {
+            let hash = block.get_hash();
+            let e = match self.add_block(block.clone()) {
+                Ok(_) => {
+                    if let Some(children) = self.inner.lock().unwrap().orphans.remove(&hash) {
+                        pending.extend(children);
+                    }
+                    continue;
+                }
+                Err(e) => e,
+            };
+            match e.downcast::<BlockError>() {
+                Ok(BlockError::UnknownParent(parent)) => {
+                    let header_known = self.get_header(&parent).is_ok();
+                    let mut inner = self.inner.lock().unwrap();
+                    let orphans: usize = inner.orphans.values().map(|v| v.len()).sum();
+                    if header_known && orphans < MAX_ORPHAN_BLOCKS {
+                        debug!("block {} waits for its parent {}", hash, parent);
+                        inner.orphans.entry(parent).or_default().push(block);
+                    } else if !header_known {
+                        drop(inner);
+                        self.send_get_headers(&msg.addr_from)?;
+                    }
+                }
+                Ok(reason) => {
+                    // check_header compares the hash first, but the header
+                    // must hash to it before the hash can be refused
+                    if reason.proves_invalid_header() && block.get_header().calculate_hash()? == hash {
+                        self.inner
+                            .lock()
+                            .unwrap()
+                            .utxo
+                            .blockchain
+                            .invalidate_header(&hash)?;
+                    } else {
+                        // the block may still be valid, ask the other peers for it
+                        self.inner.lock().unwrap().download.remove_peer(&msg.addr_from);
+                        self.request_downloads()?;
+                    }
+                    let penalty = match reason {
+                        BlockError::TimestampTooNew(_) => FUTURE_BLOCK_PENALTY,
+                        _ => INVALID_BLOCK_PENALTY,
+                    };
+                    return Err(Misbehavior {
+                        penalty,
+                        reason: format!("invalid block {}: {}", hash, reason),
+                    }
+                    .into());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.request_downloads()
+    }
+
+    fn handle_headers(&self, msg: Headersmsg) -> Result<()> {
+        info!(
+            "receive {} headers from {}",
+            msg.headers.len(),
+            msg.addr_from
+        );
+        let mut added = 0;
+        for header in &msg.headers {
+            let result = self
+                .inner
+                .lock()
+                .unwrap()
+                .utxo
+                .blockchain
+                .add_header(header);
+            match result {
+                Ok(true) => added += 1,
+                Ok(false) => {}
+                Err(e) => {
+                    return match e.downcast::<BlockError>() {
+                        Ok(BlockError::UnknownParent(_)) => {
+                            warn!("header {} from {} does not connect", header.hash, msg.addr_from);
+                            self.send_get_headers(&msg.addr_from)
+                        }
+                        Ok(reason) => Err(Misbehavior {
+                            penalty: INVALID_BLOCK_PENALTY,
+                            reason: format!("invalid header {}: {}", header.hash, reason),
+                        }
+                        .into()),
+                        Err(e) => Err(e),
+                    };
+                }
+            }
         }
+        self.inner.lock().unwrap().download.add_peer(&msg.addr_from);
 
+        if msg.headers.len() == MAX_HEADERS_PER_MSG && added > 0 {
+            self.send_get_headers(&msg.addr_from)?;
+        }
+        self.request_downloads()
+    }
+
+    fn handle_get_headers(&self, msg: GetHeadersmsg) -> Result<()> {
+        info!("receive get headers msg from {}", msg.addr_from);
+        let headers = self
+            .inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .get_headers_after(&msg.locator, &msg.stop_hash, MAX_HEADERS_PER_MSG)?;
+        self.send_headers(&msg.addr_from, headers)
+    }
+
+    fn handle_get_proof(&self, msg: GetProofmsg) -> Result<()> {
+        info!("receive get proof msg from {} for {}", msg.addr_from, msg.txid);
+        let proof = self
+            .inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .get_merkle_proof(&msg.txid)?;
+        self.send_proof(&msg.addr_from, &msg.txid, proof)
+    }
+
+    fn handle_proof(&self, msg: Proofmsg) -> Result<()> {
+        let proof = match msg.proof {
+            Some(proof) if proof.txid == msg.txid => proof,
+            Some(_) => {
+                return Err(Misbehavior {
+                    penalty: INVALID_PROOF_PENALTY,
+                    reason: format!("merkle proof for a transaction other than {}", msg.txid),
+                }
+                .into())
+            }
+            None => {
+                info!("{} has no merkle proof for {}", msg.addr_from, msg.txid);
+                return Ok(());
+            }
+        };
+        let header = match self.get_header(&proof.block_hash) {
+            Ok(header) => header,
+            Err(_) => {
+                info!("merkle proof from {} is for unknown block {}", msg.addr_from, proof.block_hash);
+                return Ok(());
+            }
+        };
+        if !proof.verify(&header)? {
+            return Err(Misbehavior {
+                penalty: INVALID_PROOF_PENALTY,
+                reason: format!("invalid merkle proof for {}", msg.txid),
+            }
+            .into());
+        }
+        let active = self
+            .inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .verify_merkle_proof(&proof)?;
+        info!(
+            "merkle proof from {}: {} is in block {}{}",
+            msg.addr_from,
+            msg.txid,
+            proof.block_hash,
+            if active { "" } else { ", off the active chain" }
+        );
         Ok(())
     }
 
     fn handle_inv(&self, msg: Invmsg) -> Result<()> {
         info!("receive inv msg: {:#?}", msg);
         if msg.kind == "block" {
-            let block_hash = &msg.items[0];
-            self.send_get_data(&msg.addr_from, "block", block_hash)?;
-
-            let mut new_in_transit = Vec::new();
-            for b in &msg.items {
-                if b != block_hash {
-                    new_in_transit.push(b.clone());
+            // announced blocks are fetched through their headers
+            let mut unknown = false;
+            for hash in &msg.items {
+                if self.get_header(hash).is_err() {
+                    unknown = true;
+                    break;
                 }
             }
-            self.replace_in_transit(new_in_transit);
+            if unknown {
+                self.send_get_headers(&msg.addr_from)?;
+            }
+            // a full batch means the peer has more blocks past the last one
+            if msg.items.len() == MAX_BLOCKS_PER_INV {
+                self.send_get_blocks(&msg.addr_from, msg.items.last().map(|h| h.as_str()))?;
+            }
         } else if msg.kind == "tx" {
-            let txid = &msg.items[0];
+            let txid = match msg.items.first() {
+                Some(txid) => txid,
+                None => {
+                    let reason = WireError::MalformedPayload(String::from("inv"));
+                    return Err(Misbehavior {
+                        penalty: reason.penalty(),
+                        reason: reason.to_string(),
+                    }
+                    .into());
+                }
+            };
             match self.get_mempool_tx(txid) {
                 Some(tx) => {
                     if tx.id.is_empty() {
@@ -405,9 +1154,18 @@ impl Server {
 
     fn handle_get_blocks(&self, msg: GetBlocksmsg) -> Result<()> {
         info!("receive get blocks msg: {:#?}", msg);
-        let block_hashs = self.get_block_hashs();
-        self.send_inv(&msg.addr_from, "block", block_hashs)?;
-        Ok(())
+        // oldest first, so every block's parent is known when its target is checked
+        let block_hashs = self
+            .inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .get_hashes_after(&msg.locator, &msg.stop_hash, MAX_BLOCKS_PER_INV)?;
+        if block_hashs.is_empty() {
+            return Ok(());
+        }
+        self.send_inv(&msg.addr_from, "block", block_hashs)
     }
 
     fn handle_get_data(&self, msg: GetDatamsg) -> Result<()> {
@@ -415,132 +1173,347 @@ impl Server {
         if msg.kind == "block" {
             let block = self.get_block(&msg.id)?;
             self.send_block(&msg.addr_from, &block)?;
+        } else if msg.kind == "merkleblock" {
+            let block = self.get_block(&msg.id)?;
+            let filter = self
+                .peers
+                .lock()
+                .unwrap()
+                .connections
+                .get(&msg.addr_from)
+                .and_then(|conn| conn.filter.clone())
+                .unwrap_or_default();
+            self.send_merkle_block(&msg.addr_from, FilteredBlock::new(&block, &filter)?)?;
         } else if msg.kind == "tx" {
-            let tx = self.get_mempool_tx(&msg.id).unwrap();
-            self.send_tx(&msg.addr_from, &tx)?;
+            // the transaction may have been mined or replaced since it was announced
+            if let Some(tx) = self.get_mempool_tx(&msg.id) {
+                self.send_tx(&msg.addr_from, &tx)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_filter_load(&self, msg: FilterLoadmsg) -> Result<()> {
+        info!(
+            "receive filter of {} public key hashes from {}",
+            msg.pub_key_hashes.len(),
+            msg.addr_from
+        );
+        if msg.pub_key_hashes.len() > MAX_FILTER_LEN {
+            return Err(Misbehavior {
+                penalty: INVALID_TX_PENALTY,
+                reason: format!("filter of {} public key hashes", msg.pub_key_hashes.len()),
+            }
+            .into());
+        }
+        if let Some(conn) = self.peers.lock().unwrap().connections.get_mut(&msg.addr_from) {
+            conn.filter = Some(msg.pub_key_hashes.into_iter().collect());
         }
         Ok(())
     }
 
     fn handle_tx(&self, msg: Txmsg) -> Result<()> {
         info!("receive tx msg: {} {}", msg.addr_from, &msg.transaction.id);
-        self.insert_mempool(msg.transaction.clone());
+        match self.insert_mempool(msg.transaction.clone()) {
+            Ok(replaced) => {
+                for tx in replaced {
+                    info!("tx {} replaced by {}", tx.id, msg.transaction.id);
+                }
+            }
+            Err(e) => {
+                warn!("reject tx {} from {}: {}", msg.transaction.id, msg.addr_from, e);
+                return match e.downcast::<MempoolError>() {
+                    Ok(MempoolError::InvalidSignature(txid))
+                    | Ok(MempoolError::OutputsExceedInputs(txid))
+                    | Ok(MempoolError::ValueOverflow(txid)) => Err(Misbehavior {
+                        penalty: INVALID_TX_PENALTY,
+                        reason: format!("invalid transaction {}", txid),
+                    }
+                    .into()),
+                    Ok(MempoolError::Invalid(reason)) => Err(Misbehavior {
+                        penalty: INVALID_TX_PENALTY,
+                        reason: reason.to_string(),
+                    }
+                    .into()),
+                    _ => Ok(()),
+                };
+            }
+        }
 
         let known_nodes = self.get_known_nodes();
-        if self.node_address == KNOWN_NODE1 {
+        if self.params.seeds.contains(&self.node_address) {
             for node in known_nodes {
                 if node != self.node_address && node != msg.addr_from {
                     self.send_inv(&node, "tx", vec![msg.transaction.id.clone()])?;
                 }
             }
         } else {
-            let mut mempool = self.get_mempool();
-            debug!("Current mempool: {:#?}", &mempool);
-            if mempool.len() >= 1 && !self.mining_address.is_empty() {
+            let mempool_empty = {
+                let inner = self.inner.lock().unwrap();
+                debug!("Current mempool size: {}", inner.mempool.len());
+                inner.mempool.is_empty()
+            };
+            if !mempool_empty && !self.mining_address.is_empty() {
                 loop {
-                    let mut txs = Vec::new();
-
-                    for (_, tx) in &mempool {
-                        if self.verify_tx(tx)? {
-                            txs.push(tx.clone());
-                        }
-                    }
-
+                    let (txs, fees) = self.select_transactions()?;
                     if txs.is_empty() {
-                        return Ok(());
+                        break;
                     }
+                    self.mine_template(txs, fees, &self.mining_address)?;
+                }
+            }
+        }
 
-                    let cbtx =
-                        Transaction::new_coinbase(self.mining_address.clone(), String::new())?;
-                    txs.push(cbtx);
+        Ok(())
+    }
 
-                    for tx in &txs {
-                        mempool.remove(&tx.id);
-                    }
+    /// handle_connection reads frames off a connection until it closes
+    ///
+    /// `peer` is the listening address of the remote node for connections
+    /// this node opened. Inbound connections are registered under their
+    /// socket address, which the remote end can't choose, so replies travel
+    /// back over them. A peer sending malformed frames or invalid data is
+    /// penalized and, once its score reaches BAN_SCORE, disconnected.
+    fn handle_connection(&self, stream: TcpStream, peer: Option<String>) -> Result<()> {
+        let mut guard = ConnectionGuard {
+            server: self,
+            stream: &stream,
+            inbound: peer.is_none(),
+            peer,
+        };
+        self.read_messages(&stream, &mut guard.peer)
+    }
 
-                    let new_block = self.mine_block(txs)?;
-                    self.utxo_reindex()?;
+    fn read_messages(&self, stream: &TcpStream, peer: &mut Option<String>) -> Result<()> {
+        let remote = stream.peer_addr()?;
+        info!("Accept connection: {}", remote);
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let key = match peer {
+            Some(addr) => addr.clone(),
+            None => {
+                let conn = Connection {
+                    stream: stream.try_clone()?,
+                    inbound: true,
+                    filter: None,
+                };
+                self.peers.lock().unwrap().connections.insert(remote.to_string(), conn);
+                peer.insert(remote.to_string()).clone()
+            }
+        };
 
-                    for node in self.get_known_nodes() {
-                        if node != self.node_address {
-                            self.send_inv(&node, "block", vec![new_block.get_hash()])?;
+        loop {
+            let frame = match read_frame(&mut reader, self.params.magic_bytes()) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => match e.downcast::<WireError>() {
+                    Ok(reason) => {
+                        let banned = self.penalize(remote.ip(), reason.penalty(), &reason)?;
+                        if banned || reason.is_fatal() {
+                            break;
                         }
+                        continue;
+                    }
+                    Err(e) => {
+                        info!("connection with {} failed: {}", remote, e);
+                        break;
                     }
+                },
+            };
 
-                    if mempool.len() == 0 {
+            let mut msg = match decode_message(&frame.command, &frame.payload) {
+                Ok(msg) => msg,
+                Err(reason) => {
+                    if self.penalize(remote.ip(), reason.penalty(), &reason)? {
                         break;
                     }
+                    continue;
+                }
+            };
+            // whatever the sender claims, handlers answer through this connection
+            let advertised = msg.set_addr_from(&key).unwrap_or_default();
+            {
+                // peers enter the book through their version message, which
+                // says whether they serve blocks
+                let mut peers = self.peers.lock().unwrap();
+                if peers.book.contains(&key) {
+                    peers.book.mark_seen(&key, None, unix_time())?;
                 }
-                self.clear_mempool();
             }
-        }
 
+            if let Err(e) = self.handle_message(msg, &advertised) {
+                match e.downcast::<Misbehavior>() {
+                    Ok(reason) => {
+                        if self.penalize(remote.ip(), reason.penalty, &reason)? {
+                            break;
+                        }
+                    }
+                    Err(e) => warn!("failed to handle {} from {}: {}", frame.command, remote, e),
+                }
+            }
+        }
         Ok(())
     }
 
-    fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
-        let mut buffer = Vec::new();
-        let count = stream.read_to_end(&mut buffer)?;
-        info!("Accept request: length {}", count);
+    /// handle_message dispatches a message whose addr_from names the
+    /// connection it came in on; `advertised` is the address it announced
+    fn handle_message(&self, msg: Message, advertised: &str) -> Result<()> {
+        match msg {
+            Message::Addr(data) => self.handle_addr(data),
+            Message::Block(data) => self.handle_block(data),
+            Message::Inv(data) => self.handle_inv(data),
+            Message::GetBlock(data) => self.handle_get_blocks(data),
+            Message::GetData(data) => self.handle_get_data(data),
+            Message::Tx(data) => self.handle_tx(data),
+            Message::Version(data) => self.handle_version(data, advertised),
+            Message::GetHeaders(data) => self.handle_get_headers(data),
+            Message::Headers(data) => self.handle_headers(data),
+            Message::GetProof(data) => self.handle_get_proof(data),
+            Message::Proof(data) => self.handle_proof(data),
+            Message::FilterLoad(data) => self.handle_filter_load(data),
+            // merkle blocks are only asked for by light clients
+            Message::MerkleBlock(_) => Ok(()),
+        }
+    }
 
-        let cmd = bytes_to_cmd(&buffer)?;
+    /// admit_inbound takes an inbound slot for an accepted connection,
+    /// refusing banned hosts and connections beyond MAX_INBOUND
+    fn admit_inbound(&self, stream: &TcpStream) -> bool {
+        let remote = match stream.peer_addr() {
+            Ok(remote) => remote,
+            Err(_) => return false,
+        };
+        if self.is_banned(remote.ip()) {
+            info!("refuse connection from banned host {}", remote);
+            return false;
+        }
+        let mut peers = self.peers.lock().unwrap();
+        if peers.inbound >= MAX_INBOUND {
+            info!("refuse connection from {}, no inbound slot left", remote);
+            return false;
+        }
+        peers.inbound += 1;
+        true
+    }
 
-        match cmd {
-            Message::Addr(data) => self.handle_addr(data)?,
-            Message::Block(data) => self.handle_block(data)?,
-            Message::Inv(data) => self.handle_inv(data)?,
-            Message::GetBlock(data) => self.handle_get_blocks(data)?,
-            Message::GetData(data) => self.handle_get_data(data)?,
-            Message::Tx(data) => self.handle_tx(data)?,
-            Message::Version(data) => self.handle_version(data)?,
+    /// drop_connection forgets the connection registered for `addr` if it is `stream`
+    fn drop_connection(&self, addr: &str, stream: &TcpStream) {
+        let mut peers = self.peers.lock().unwrap_or_else(PoisonError::into_inner);
+        let same = match peers.connections.get(addr) {
+            Some(conn) => {
+                conn.stream.local_addr().ok() == stream.local_addr().ok()
+                    && conn.stream.peer_addr().ok() == stream.peer_addr().ok()
+            }
+            None => false,
+        };
+        if same {
+            peers.connections.remove(addr);
         }
+    }
 
-        Ok(())
+    /// penalize adds to a host's misbehavior score, returning whether the
+    /// host is now banned
+    ///
+    /// The connections of a banned host are shut down.
+    fn penalize(&self, ip: IpAddr, penalty: u32, reason: &dyn fmt::Display) -> Result<bool> {
+        let mut peers = self.peers.lock().unwrap();
+        let banned = peers.book.penalize(ip, penalty, unix_time())?;
+        if !banned {
+            warn!(
+                "peer {} misbehaved ({}), score {}",
+                ip,
+                reason,
+                peers.book.get_score(ip)
+            );
+            return Ok(false);
+        }
+        warn!("ban peer {}: {}", ip, reason);
+        peers.connections.retain(|_, conn| match conn.stream.peer_addr() {
+            Ok(addr) if addr.ip() == ip => {
+                conn.stream.shutdown(std::net::Shutdown::Both).ok();
+                false
+            }
+            _ => true,
+        });
+        Ok(true)
+    }
+
+    fn is_banned(&self, ip: IpAddr) -> bool {
+        self.peers.lock().unwrap().book.is_banned(ip, unix_time())
+    }
+}
+
+/// unix_time returns the current time in seconds since the unix epoch
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// ConnectionGuard releases what a connection holds once it is no longer
+/// handled, even when handling it panicked
+struct ConnectionGuard<'a> {
+    server: &'a Server,
+    stream: &'a TcpStream,
+    inbound: bool,
+    /// listening address of the remote node, once known
+    peer: Option<String>,
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(addr) = &self.peer {
+            self.server.drop_connection(addr, self.stream);
+            let mut inner = self.server.inner.lock().unwrap_or_else(PoisonError::into_inner);
+            inner.download.remove_peer(addr);
+        }
+        if self.inbound {
+            self.server.peers.lock().unwrap_or_else(PoisonError::into_inner).inbound -= 1;
+        }
     }
 }
 
-fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
-    let mut data = [0; CMD_LEN];
-    for (i, d) in cmd.as_bytes().iter().enumerate() {
-        data[i] = *d;
+impl Message {
+    /// set_addr_from replaces the address the sender announced, returning it
+    fn set_addr_from(&mut self, addr: &str) -> Option<String> {
+        let field = match self {
+            Message::Addr(_) => return None,
+            Message::Version(msg) => &mut msg.addr_from,
+            Message::Tx(msg) => &mut msg.addr_from,
+            Message::GetData(msg) => &mut msg.addr_from,
+            Message::GetBlock(msg) => &mut msg.addr_from,
+            Message::Inv(msg) => &mut msg.addr_from,
+            Message::Block(msg) => &mut msg.addr_from,
+            Message::GetHeaders(msg) => &mut msg.addr_from,
+            Message::Headers(msg) => &mut msg.addr_from,
+            Message::GetProof(msg) => &mut msg.addr_from,
+            Message::Proof(msg) => &mut msg.addr_from,
+            Message::FilterLoad(msg) => &mut msg.addr_from,
+            Message::MerkleBlock(msg) => &mut msg.addr_from,
+        };
+        Some(std::mem::replace(field, addr.to_string()))
     }
-    data
 }
 
-fn bytes_to_cmd(bytes: &[u8]) -> Result<Message> {
-    let mut cmd = Vec::new();
-    let cmd_bytes = &bytes[..CMD_LEN];
-    let data = &bytes[CMD_LEN..];
-    for b in cmd_bytes {
-        if 0 as u8 != *b {
-            cmd.push(*b);
-        }
-    }
-    info!("cmd: {}", String::from_utf8(cmd.clone())?);
-
-    if cmd == "addr".as_bytes() {
-        let data: Vec<String> = deserialize(data)?;
-        Ok(Message::Addr(data))
-    } else if cmd == "block".as_bytes() {
-        let data: Blockmsg = deserialize(data)?;
-        Ok(Message::Block(data))
-    } else if cmd == "inv".as_bytes() {
-        let data: Invmsg = deserialize(data)?;
-        Ok(Message::Inv(data))
-    } else if cmd == "getblocks".as_bytes() {
-        let data: GetBlocksmsg = deserialize(data)?;
-        Ok(Message::GetBlock(data))
-    } else if cmd == "getdata".as_bytes() {
-        let data: GetDatamsg = deserialize(data)?;
-        Ok(Message::GetData(data))
-    } else if cmd == "tx".as_bytes() {
-        let data: Txmsg = deserialize(data)?;
-        Ok(Message::Tx(data))
-    } else if cmd == "version".as_bytes() {
-        let data: Versionmsg = deserialize(data)?;
-        Ok(Message::Version(data))
-    } else {
-        Err(format_err!("Unknown command in the server"))
+/// decode_message turns the payload of a frame into the Message its command names
+pub(crate) fn decode_message(command: &str, payload: &[u8]) -> std::result::Result<Message, WireError> {
+    let malformed = |_| WireError::MalformedPayload(command.to_string());
+    match command {
+        "addr" => Ok(Message::Addr(deserialize(payload).map_err(malformed)?)),
+        "block" => Ok(Message::Block(deserialize(payload).map_err(malformed)?)),
+        "inv" => Ok(Message::Inv(deserialize(payload).map_err(malformed)?)),
+        "getblocks" => Ok(Message::GetBlock(deserialize(payload).map_err(malformed)?)),
+        "getdata" => Ok(Message::GetData(deserialize(payload).map_err(malformed)?)),
+        "tx" => Ok(Message::Tx(deserialize(payload).map_err(malformed)?)),
+        "version" => Ok(Message::Version(deserialize(payload).map_err(malformed)?)),
+        "getheaders" => Ok(Message::GetHeaders(deserialize(payload).map_err(malformed)?)),
+        "headers" => Ok(Message::Headers(deserialize(payload).map_err(malformed)?)),
+        "getproof" => Ok(Message::GetProof(deserialize(payload).map_err(malformed)?)),
+        "proof" => Ok(Message::Proof(deserialize(payload).map_err(malformed)?)),
+        "filterload" => Ok(Message::FilterLoad(deserialize(payload).map_err(malformed)?)),
+        "merkleblock" => Ok(Message::MerkleBlock(deserialize(payload).map_err(malformed)?)),
+        _ => Err(WireError::UnknownCommand(command.to_string())),
     }
 }
 
@@ -548,26 +1521,289 @@ fn bytes_to_cmd(bytes: &[u8]) -> Result<Message> {
 mod test {
     use super::*;
     use crate::blockchain::*;
-    use crate::wallets::*;
+    use crate::testutil::TestChain;
+    use crate::tx::{TXInput, TXOutput};
+    use crate::wallets::Wallet;
 
     #[test]
     fn test_cmd() {
-        let mut ws = Wallets::new().unwrap();
-        let wa1 = ws.create_wallet();
-        let bc = Blockchain::create_blockchain(wa1).unwrap();
-        let utxo_set = UTXOSet { blockchain: bc };
-        let server = Server::new("7878", "localhost:3001", utxo_set).unwrap();
+        let mut chain = TestChain::new("server");
+        chain.params.set_port("7878").unwrap();
+        let params = &chain.params;
+        let server = Server::new(params, "localhost:3001", chain.utxo).unwrap();
 
         let vmsg = Versionmsg {
             addr_from: server.node_address.clone(),
             best_height: server.get_best_height().unwrap(),
             version: VERSION,
+            services: NODE_NETWORK,
         };
-        let data = serialize(&(cmd_to_bytes("version"), vmsg.clone())).unwrap();
-        if let Message::Version(v) = bytes_to_cmd(&data).unwrap() {
+        let magic = params.magic_bytes();
+        let frame = encode_frame(magic, "version", &serialize(&vmsg).unwrap()).unwrap();
+        let frame = read_frame(&mut &frame[..], magic).unwrap().unwrap();
+        if let Message::Version(v) = decode_message(&frame.command, &frame.payload).unwrap() {
             assert_eq!(v, vmsg);
         } else {
             panic!("wrong!");
         }
+
+        assert_eq!(
+            decode_message("version", &[1, 2]).unwrap_err(),
+            WireError::MalformedPayload("version".to_string())
+        );
+        assert_eq!(
+            decode_message("ping", &[]).unwrap_err(),
+            WireError::UnknownCommand("ping".to_string())
+        );
+    }
+
+    #[test]
+    fn test_swapped_block_body() {
+        let chain = TestChain::new("server-body");
+        let params = &chain.params;
+        let mut miner = Blockchain::new(&params.data_path("miner"), &params.genesis).unwrap();
+        let server = Server::new(params, "", chain.utxo).unwrap();
+
+        let wallet = Wallet::new();
+        let address = wallet.get_address();
+        let mut blocks = Vec::new();
+        for height in 1..=2 {
+            let subsidy = miner.get_block_subsidy(height);
+            let cbtx = Transaction::new_coinbase(address.clone(), String::new(), height, subsidy).unwrap();
+            blocks.push(miner.mine_block(vec![cbtx]).unwrap());
+        }
+        let block = blocks[0].clone();
+        let other = Transaction::new_coinbase(address.clone(), String::from("other"), 1, miner.get_block_subsidy(1)).unwrap();
+        let swapped = Block::from_parts(block.get_header().clone(), vec![other]);
+        server.inner.lock().unwrap().utxo.blockchain.add_header(block.get_header()).unwrap();
+
+        let addr_from = String::from("127.0.0.1:1");
+        let err = server
+            .handle_block(Blockmsg { addr_from: addr_from.clone(), block: swapped })
+            .unwrap_err();
+        assert!(err.downcast::<Misbehavior>().is_ok());
+        assert_eq!(server.get_best_height().unwrap(), 0);
+        {
+            // the header stays usable, so the block is fetched again and its children are accepted
+            let inner = server.inner.lock().unwrap();
+            assert_eq!(inner.utxo.blockchain.get_missing_blocks(10).unwrap(), vec![block.get_hash()]);
+            assert!(inner.utxo.blockchain.add_header(blocks[1].get_header()).unwrap());
+        }
+
+        for block in blocks.iter().cloned() {
+            server.handle_block(Blockmsg { addr_from: addr_from.clone(), block }).unwrap();
+        }
+        assert_eq!(server.get_best_height().unwrap(), 2);
+
+        // a body matching its merkle root that spends an output twice condemns the header
+        let cb = &blocks[0].get_transaction()[0];
+        let to = Wallet::new().get_address();
+        let txs = vec![
+            Transaction::new_coinbase(address, String::new(), 3, miner.get_block_subsidy(3)).unwrap(),
+            spend(&wallet, &[cb], vec![TXOutput::new(10, to.clone()).unwrap()]),
+            spend(&wallet, &[cb], vec![TXOutput::new(20, to).unwrap()]),
+        ];
+        let bits = miner.get_next_bits(blocks[1].get_header()).unwrap();
+        let double_spend = Block::new_block(txs, blocks[1].get_hash(), 3, bits).unwrap();
+        server.inner.lock().unwrap().utxo.blockchain.add_header(double_spend.get_header()).unwrap();
+        let err = server
+            .handle_block(Blockmsg { addr_from, block: double_spend.clone() })
+            .unwrap_err();
+        assert!(err.downcast::<Misbehavior>().is_ok());
+        let inner = server.inner.lock().unwrap();
+        assert!(inner.utxo.blockchain.get_header(&double_spend.get_hash()).is_err());
+        assert!(inner.utxo.blockchain.get_missing_blocks(10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_utxo_set_follows_chain() {
+        let chain = TestChain::new("server-utxo");
+        let params = &chain.params;
+        let mut miner = Blockchain::new(&params.data_path("miner"), &params.genesis).unwrap();
+        let server = Server::new(params, "", chain.utxo).unwrap();
+        let wallet = Wallet::new();
+        let subsidy = miner.get_block_subsidy(1);
+        let cbtx = Transaction::new_coinbase(wallet.get_address(), String::new(), 1, subsidy).unwrap();
+        let block = miner.mine_block(vec![cbtx.clone()]).unwrap();
+        server.handle_block(Blockmsg { addr_from: String::from("127.0.0.1:1"), block }).unwrap();
+
+        // the set loses an output the chain still has, so the next block can't be applied to it
+        let outpoint = OutPoint::new(&cbtx.id, 0);
+        sled::open(params.data_dir.join("utxos")).unwrap().remove(outpoint.to_key()).unwrap();
+        let to = Wallet::new().get_address();
+        let tx = spend(&wallet, &[&cbtx], vec![TXOutput::new(subsidy, to).unwrap()]);
+        let cbtx = Transaction::new_coinbase(wallet.get_address(), String::new(), 2, subsidy).unwrap();
+        let block = miner.mine_block(vec![cbtx, tx.clone()]).unwrap();
+        server.handle_block(Blockmsg { addr_from: String::from("127.0.0.1:1"), block }).unwrap();
+
+        assert_eq!(server.get_best_height().unwrap(), 2);
+        let inner = server.inner.lock().unwrap();
+        assert!(inner.utxo.get_entry(&OutPoint::new(&tx.id, 0)).unwrap().is_some());
+    }
+
+    /// spend signs a transaction paying `outputs` from the first output of each of `prevs`
+    fn spend(wallet: &Wallet, prevs: &[&Transaction], outputs: Vec<TXOutput>) -> Transaction {
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: prevs
+                .iter()
+                .map(|prev| TXInput {
+                    txid: prev.id.clone(),
+                    vout: 0,
+                    signature: Vec::new(),
+                    pub_key: wallet.public_key.clone(),
+                })
+                .collect(),
+            vout: outputs,
+        };
+        let prev_txs = prevs.iter().map(|prev| (prev.id.clone(), (*prev).clone())).collect();
+        tx.sign(&wallet.secret_key, prev_txs).unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_accept_transaction() {
+        let chain = TestChain::new("server-accept");
+        let mut utxo = chain.utxo;
+        let wallet = Wallet::new();
+        let subsidy = utxo.blockchain.get_block_subsidy(1);
+        let cbtx = Transaction::new_coinbase(wallet.get_address(), String::new(), 1, subsidy).unwrap();
+        let block = utxo.blockchain.mine_block(vec![cbtx.clone()]).unwrap();
+        utxo.update(&block).unwrap();
+        let server = Server::new(&chain.params, "", utxo).unwrap();
+        let reject = |tx: Transaction| {
+            let err = server.inner.lock().unwrap().accept_transaction(tx).unwrap_err();
+            err.downcast::<MempoolError>().unwrap()
+        };
+        let to = Wallet::new().get_address();
+
+        let tx = spend(&wallet, &[&cbtx], vec![TXOutput::new(i32::MAX, to.clone()).unwrap(); 2]);
+        assert_eq!(reject(tx.clone()), MempoolError::ValueOverflow(tx.id));
+
+        // spending the same output twice would count its value twice
+        let tx = spend(&wallet, &[&cbtx, &cbtx], vec![TXOutput::new(subsidy + 1, to.clone()).unwrap()]);
+        let double_spend = BlockError::DoubleSpend { txid: cbtx.id.clone(), vout: 0 };
+        assert_eq!(reject(tx), MempoolError::Invalid(double_spend));
+        // a negative output would pay for a larger one
+        let outputs = vec![
+            TXOutput::new(-1000, to.clone()).unwrap(),
+            TXOutput::new(subsidy + 999, to.clone()).unwrap(),
+        ];
+        let tx = spend(&wallet, &[&cbtx], outputs);
+        assert_eq!(reject(tx.clone()), MempoolError::Invalid(BlockError::NegativeOutput(tx.id)));
+        let mut tx = spend(&wallet, &[&cbtx], vec![TXOutput::new(subsidy - 1, to.clone()).unwrap()]);
+        tx.id = cbtx.id.clone();
+        assert_eq!(reject(tx), MempoolError::Invalid(BlockError::BadTransactionId(cbtx.id.clone())));
+        assert!(server.inner.lock().unwrap().mempool.is_empty());
+
+        let tx = spend(&wallet, &[&cbtx], vec![TXOutput::new(subsidy - 1, to).unwrap()]);
+        assert!(server.inner.lock().unwrap().accept_transaction(tx.clone()).unwrap().is_empty());
+        let (txs, fees) = server.select_transactions().unwrap();
+        assert_eq!((txs.len(), txs[0].id.clone(), fees), (1, tx.id, 1));
+    }
+
+    #[test]
+    fn test_malformed_requests() {
+        let chain = TestChain::new("server-malformed");
+        let server = Server::new(&chain.params, "", chain.utxo).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr_from = listener.local_addr().unwrap().to_string();
+
+        let inv = Invmsg {
+            addr_from: addr_from.clone(),
+            kind: String::from("tx"),
+            items: Vec::new(),
+        };
+        assert!(server.handle_inv(inv).unwrap_err().downcast::<Misbehavior>().is_ok());
+        let get_data = GetDatamsg {
+            addr_from,
+            kind: String::from("tx"),
+            id: String::from("unknown"),
+        };
+        server.handle_get_data(get_data).unwrap();
+
+        // a connection that panics still gives back its inbound slot
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        assert!(server.admit_inbound(&stream));
+        let handler = thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let _guard = ConnectionGuard {
+                        server: &server,
+                        stream: &stream,
+                        inbound: true,
+                        peer: Some(String::from("127.0.0.1:1")),
+                    };
+                    panic!("handler failed");
+                })
+                .join()
+        });
+        assert!(handler.is_err());
+        assert_eq!(server.peers.lock().unwrap().inbound, 0);
+    }
+
+    #[test]
+    fn test_inbound_connection_address() {
+        let chain = TestChain::new("server-inbound");
+        let server = Server::new(&chain.params, "", chain.utxo).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connect = || {
+            let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            assert!(server.admit_inbound(&stream));
+            let server = server.clone();
+            thread::spawn(move || server.handle_connection(stream, None));
+            client
+        };
+        let mut mallory = connect();
+        let victim = connect();
+        let mallory_addr = mallory.local_addr().unwrap().to_string();
+        let victim_addr = victim.local_addr().unwrap().to_string();
+
+        // a filter sent under another peer's address lands on the sender's connection
+        let msg = FilterLoadmsg {
+            addr_from: victim_addr.clone(),
+            pub_key_hashes: vec![vec![1; 20]],
+        };
+        let frame = encode_frame(chain.params.magic_bytes(), "filterload", &serialize(&msg).unwrap()).unwrap();
+        mallory.write_all(&frame).unwrap();
+        let filtered = |addr: &str| {
+            let peers = server.peers.lock().unwrap();
+            peers.connections.get(addr).map(|conn| conn.filter.is_some())
+        };
+        for _ in 0..100 {
+            if filtered(&mallory_addr) == Some(true) && filtered(&victim_addr).is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(filtered(&mallory_addr), Some(true));
+        assert_eq!(filtered(&victim_addr), Some(false));
+    }
+
+    #[test]
+    fn test_version_services() {
+        let chain = TestChain::new("server-services");
+        let server = Server::new(&chain.params, "", chain.utxo).unwrap();
+        // the replies to the version messages go to these listeners
+        let light = TcpListener::bind("127.0.0.1:0").unwrap();
+        let full = TcpListener::bind("127.0.0.1:0").unwrap();
+        let light_addr = light.local_addr().unwrap().to_string();
+        let full_addr = full.local_addr().unwrap().to_string();
+        let version = |addr_from: &str, services| Versionmsg {
+            addr_from: addr_from.to_string(),
+            best_height: 0,
+            version: VERSION,
+            services,
+        };
+
+        server.handle_addr(vec![light_addr.clone()]).unwrap();
+        assert!(server.get_known_nodes().contains(&light_addr));
+        server.handle_version(version(&light_addr, 0), &light_addr).unwrap();
+        server.handle_version(version(&full_addr, NODE_NETWORK), &full_addr).unwrap();
+        let known = server.get_known_nodes();
+        assert!(!known.contains(&light_addr));
+        assert!(known.contains(&full_addr));
     }
 }
\ No newline at end of file
@@ -0,0 +1,232 @@
+//! scheduling of block body downloads during headers-first sync
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/// How long a peer may take to deliver a requested block
+pub const BLOCK_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of blocks requested from one peer at a time
+pub const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 8;
+/// Number of timeouts in a row after which a peer is no longer asked for blocks
+pub const MAX_PEER_TIMEOUTS: u32 = 3;
+/// Number of times a block is requested before it is given up until the
+/// next headers announce it again
+pub const MAX_BLOCK_ATTEMPTS: u32 = 5;
+
+#[derive(Debug, Clone)]
+struct Request {
+    peer: String,
+    sent: Instant,
+}
+
+/// BlockDownload hands out block requests to the peers serving a sync
+///
+/// Blocks are requested oldest first and spread over all peers, each holding
+/// at most MAX_BLOCKS_IN_FLIGHT_PER_PEER requests. A request that is not
+/// answered within BLOCK_DOWNLOAD_TIMEOUT goes back to the front of the
+/// queue for another peer, and a peer that keeps timing out is dropped.
+#[derive(Debug, Default)]
+pub struct BlockDownload {
+    queue: VecDeque<String>,
+    in_flight: HashMap<String, Request>,
+    attempts: HashMap<String, u32>,
+    /// serving peers and their timeouts in a row
+    peers: HashMap<String, u32>,
+}
+
+impl BlockDownload {
+    pub fn new() -> BlockDownload {
+        BlockDownload::default()
+    }
+
+    /// AddPeer makes a peer available for block requests
+    pub fn add_peer(&mut self, peer: &str) {
+        self.peers.entry(peer.to_string()).or_insert(0);
+    }
+
+    /// RemovePeer stops using a peer and queues its outstanding requests again
+    pub fn remove_peer(&mut self, peer: &str) {
+        self.peers.remove(peer);
+        let lost: Vec<String> = self
+            .in_flight
+            .iter()
+            .filter(|(_, req)| req.peer == peer)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for hash in lost.into_iter().rev() {
+            self.in_flight.remove(&hash);
+            self.queue.push_front(hash);
+        }
+    }
+
+    pub fn has_peers(&self) -> bool {
+        !self.peers.is_empty()
+    }
+
+    /// Enqueue adds blocks to download, skipping those already queued or requested
+    pub fn enqueue(&mut self, hashes: Vec<String>) {
+        let queued: HashSet<String> = self.queue.iter().cloned().collect();
+        for hash in hashes {
+            if !queued.contains(&hash) && !self.in_flight.contains_key(&hash) {
+                self.queue.push_back(hash);
+            }
+        }
+    }
+
+    /// NextRequests assigns queued blocks to peers with free request slots
+    ///
+    /// Peers are filled round robin, so consecutive blocks come from
+    /// different peers. Returns the (peer, block hash) pairs to request.
+    pub fn next_requests(&mut self, now: Instant) -> Vec<(String, String)> {
+        let mut load: HashMap<String, usize> = self.peers.keys().map(|p| (p.clone(), 0)).collect();
+        for req in self.in_flight.values() {
+            if let Some(n) = load.get_mut(&req.peer) {
+                *n += 1;
+            }
+        }
+        let mut peers: Vec<String> = load.keys().cloned().collect();
+        peers.sort();
+
+        let mut requests = Vec::new();
+        'assign: while !self.queue.is_empty() {
+            let mut assigned = false;
+            for peer in &peers {
+                if load[peer] >= MAX_BLOCKS_IN_FLIGHT_PER_PEER {
+                    continue;
+                }
+                let hash = match self.queue.pop_front() {
+                    Some(hash) => hash,
+                    None => break 'assign,
+                };
+                *self.attempts.entry(hash.clone()).or_insert(0) += 1;
+                self.in_flight.insert(
+                    hash.clone(),
+                    Request {
+                        peer: peer.clone(),
+                        sent: now,
+                    },
+                );
+                *load.get_mut(peer).unwrap() += 1;
+                requests.push((peer.clone(), hash));
+                assigned = true;
+            }
+            if !assigned {
+                break;
+            }
+        }
+        requests
+    }
+
+    /// Received marks a block as delivered, returning whether it was requested
+    pub fn received(&mut self, hash: &str) -> bool {
+        self.attempts.remove(hash);
+        match self.in_flight.remove(hash) {
+            Some(req) => {
+                if let Some(timeouts) = self.peers.get_mut(&req.peer) {
+                    *timeouts = 0;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Expire requeues requests older than BLOCK_DOWNLOAD_TIMEOUT
+    ///
+    /// Returns the peers dropped for timing out too often.
+    pub fn expire(&mut self, now: Instant) -> Vec<String> {
+        let expired: Vec<(String, String)> = self
+            .in_flight
+            .iter()
+            .filter(|(_, req)| now.duration_since(req.sent) >= BLOCK_DOWNLOAD_TIMEOUT)
+            .map(|(hash, req)| (hash.clone(), req.peer.clone()))
+            .collect();
+
+        let mut dropped = Vec::new();
+        for (hash, peer) in expired {
+            self.in_flight.remove(&hash);
+            if self.attempts.get(&hash).copied().unwrap_or(0) < MAX_BLOCK_ATTEMPTS {
+                self.queue.push_front(hash);
+            } else {
+                self.attempts.remove(&hash);
+            }
+            if let Some(timeouts) = self.peers.get_mut(&peer) {
+                *timeouts += 1;
+                if *timeouts >= MAX_PEER_TIMEOUTS {
+                    dropped.push(peer);
+                }
+            }
+        }
+        dropped.sort();
+        dropped.dedup();
+        for peer in &dropped {
+            self.remove_peer(peer);
+        }
+        dropped
+    }
+
+    /// IsIdle reports whether nothing is queued or being downloaded
+    pub fn is_idle(&self) -> bool {
+        self.queue.is_empty() && self.in_flight.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashes(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("block{}", i)).collect()
+    }
+
+    #[test]
+    fn test_spread_over_peers() {
+        let mut dl = BlockDownload::new();
+        let now = Instant::now();
+        assert!(dl.next_requests(now).is_empty());
+
+        dl.add_peer("a");
+        dl.add_peer("b");
+        dl.enqueue(hashes(2 * MAX_BLOCKS_IN_FLIGHT_PER_PEER + 3));
+        dl.enqueue(hashes(1));
+        let requests = dl.next_requests(now);
+        assert_eq!(requests.len(), 2 * MAX_BLOCKS_IN_FLIGHT_PER_PEER);
+        assert_eq!(requests[0], ("a".to_string(), "block0".to_string()));
+        assert_eq!(requests[1], ("b".to_string(), "block1".to_string()));
+
+        // a delivery frees a slot for the next queued block
+        assert!(dl.received("block0"));
+        assert!(!dl.received("block0"));
+        let requests = dl.next_requests(now);
+        assert_eq!(requests, vec![("a".to_string(), format!("block{}", 2 * MAX_BLOCKS_IN_FLIGHT_PER_PEER))]);
+        assert!(!dl.is_idle());
+    }
+
+    #[test]
+    fn test_retry_after_timeout_and_disconnect() {
+        let mut dl = BlockDownload::new();
+        let start = Instant::now();
+        dl.add_peer("a");
+        dl.enqueue(hashes(1));
+        assert_eq!(dl.next_requests(start).len(), 1);
+
+        // another peer joins; once the request to a times out b gets it
+        dl.add_peer("b");
+        assert!(dl.expire(start + BLOCK_DOWNLOAD_TIMEOUT / 2).is_empty());
+        dl.remove_peer("a");
+        let later = start + BLOCK_DOWNLOAD_TIMEOUT;
+        assert_eq!(dl.next_requests(later), vec![("b".to_string(), "block0".to_string())]);
+
+        let mut now = later;
+        for _ in 0..MAX_PEER_TIMEOUTS - 1 {
+            now += BLOCK_DOWNLOAD_TIMEOUT;
+            assert!(dl.expire(now).is_empty());
+            assert_eq!(dl.next_requests(now).len(), 1);
+        }
+        now += BLOCK_DOWNLOAD_TIMEOUT;
+        assert_eq!(dl.expire(now), vec!["b".to_string()]);
+        assert!(!dl.has_peers());
+        assert!(dl.next_requests(now).is_empty());
+        assert!(!dl.is_idle());
+    }
+}
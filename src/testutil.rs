@@ -0,0 +1,55 @@
+//! setup shared by the tests of several modules
+
+use std::path::{Path, PathBuf};
+
+use crate::blockchain::Blockchain;
+use crate::config::{Network, NetworkParams};
+use crate::utxoset::UTXOSet;
+
+/// TempDir is a directory a test keeps its stores in
+///
+/// It is removed when dropped, so it is cleaned up even if the test panics.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// new returns an empty directory named after `name` and the test process
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("mini-btc-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&path).ok();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/// TestChain is a regtest chain and its UTXO set kept in a TempDir
+///
+/// Fields are dropped in order, so the stores are closed before their
+/// directory is removed. The UTXO set may be moved out, into a Server for
+/// example, as long as that is dropped first.
+pub struct TestChain {
+    pub params: NetworkParams,
+    pub utxo: UTXOSet,
+    _dir: TempDir,
+}
+
+impl TestChain {
+    /// new opens a fresh regtest chain with an indexed UTXO set
+    pub fn new(name: &str) -> TestChain {
+        let dir = TempDir::new(name);
+        let mut params = NetworkParams::for_network(Network::Regtest);
+        params.data_dir = dir.path().to_path_buf();
+        let bc = Blockchain::new(&params.data_dir, &params.genesis).unwrap();
+        let utxo = UTXOSet { blockchain: bc };
+        utxo.reindex().unwrap();
+        TestChain { params, utxo, _dir: dir }
+    }
+}
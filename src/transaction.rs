@@ -6,13 +6,59 @@ use crypto::sha2::Sha256;
 use failure::format_err;
 use log::error;
 use crate::errors::Result;
-use crate::utxoset::UTXOSet;
 use crate::wallets::{Wallet, Wallets};
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 use crate::blockchain::Blockchain;
 use crate::tx::{TXInput, TXOutput};
 
+/// SubsidySchedule describes the block reward and how it halves over time
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct SubsidySchedule {
+  /// reward of the genesis block and every block of the first epoch
+  pub initial: i32,
+  /// number of blocks after which the reward halves
+  pub halving_interval: i32,
+}
+
+/// Schedule used by chains created without an explicit one
+pub const DEFAULT_SUBSIDY: SubsidySchedule = SubsidySchedule {
+  initial: 100,
+  halving_interval: 210,
+};
+
+impl SubsidySchedule {
+  /// Subsidy returns the reward a block at `height` may create
+  pub fn subsidy(&self, height: i32) -> i32 {
+    let halvings = height / self.halving_interval;
+    if halvings >= 31 {
+      return 0;
+    }
+    self.initial >> halvings
+  }
+
+  /// SupplyAt returns the coins created by the subsidies of blocks 0..=height
+  pub fn supply_at(&self, height: i32) -> i64 {
+    let interval = self.halving_interval as i64;
+    let height = height as i64;
+    let mut supply = 0;
+    for halvings in 0..31 {
+      let start = halvings * interval;
+      if start > height {
+        break;
+      }
+      let end = (start + interval - 1).min(height);
+      supply += (self.initial >> halvings) as i64 * (end - start + 1);
+    }
+    supply
+  }
+
+  /// MaxSupply returns the total number of coins the schedule will ever create
+  pub fn max_supply(&self) -> i64 {
+    self.supply_at(i32::MAX)
+  }
+}
+
 /// Transaction represents a Bitcoin transaction
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
@@ -21,62 +67,126 @@ pub struct Transaction {
   pub vout: Vec<TXOutput>,
 }
 
+/// OutputSource is where a wallet finds the outputs it spends and the
+/// transactions that created them, the local UTXO set or a running node
+pub trait OutputSource {
+  /// FindSpendableOutputs selects outputs locked to `pub_key_hash` worth at
+  /// least `amount` if possible, returning their value and `vout` indices
+  /// per transaction id
+  fn find_spendable_outputs(&self, pub_key_hash: &[u8], amount: i32) -> Result<(i32, HashMap<String, Vec<i32>>)>;
+
+  /// SignTransaction signs every input of `tx`
+  fn sign_transaction(&self, tx: &mut Transaction, private_key: &[u8]) -> Result<()>;
+}
+
+/// Fee is the fee policy used when building a transaction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fee {
+  /// pay exactly this amount
+  Absolute(i32),
+  /// pay this amount per byte of the signed transaction
+  PerByte(i32),
+}
+
 impl Transaction {
   /// NewUTXOTransaction creates a new transaction
-  pub fn new_UTXO(wallet: &Wallet, to: &str, amount: i32, utxo: &UTXOSet) -> Result<Transaction> {
-    let mut vin = Vec::new();
-
+  ///
+  /// Whatever the selected outputs hold beyond `amount` and the fee is sent
+  /// back to the wallet as change. With a per-byte fee the inputs are
+  /// reselected until they also cover the fee of the resulting size.
+  pub fn new_UTXO(
+    wallet: &Wallet,
+    to: &str,
+    amount: i32,
+    fee: Fee,
+    utxo: &impl OutputSource,
+  ) -> Result<Transaction> {
     let mut pub_key_hash = wallet.public_key.clone();
     hash_pub_key(&mut pub_key_hash);
 
-    let acc_v = utxo.find_spendable_outputs(&pub_key_hash, amount)?;
-
-    if acc_v.0 < amount {
-      error!("Not Enough balance");
-      return Err(format_err!(
-          "Not Enough balance: current balance {}",
-          acc_v.0
-      ));
-    }
-    
-    for tx in acc_v.1 {
-        for out in tx.1 {
-            let input = TXInput {
-                txid: tx.0.clone(),
-                vout: out,
-                signature: Vec::new(),
-                pub_key: wallet.public_key.clone(),
-            };
-            vin.push(input);
-        }
+    let mut fee_amount = match fee {
+      Fee::Absolute(f) => f,
+      Fee::PerByte(_) => 0,
+    };
+    if amount <= 0 {
+      return Err(format_err!("Amount must be positive"));
+    }
+    if fee_amount < 0 {
+      return Err(format_err!("Fee must not be negative"));
     }
 
-    let mut vout = vec![TXOutput::new(
-      amount,
-      to.to_string())?];
+    loop {
+      let needed = amount
+        .checked_add(fee_amount)
+        .ok_or_else(|| format_err!("Amount plus fee overflows"))?;
+      let acc_v = utxo.find_spendable_outputs(&pub_key_hash, needed)?;
 
-    if acc_v.0 > amount {
-      vout.push(TXOutput::new(
-        acc_v.0 - amount,
-        wallet.get_address())?)
-    }
+      if acc_v.0 < needed {
+        error!("Not Enough balance");
+        return Err(format_err!(
+            "Not Enough balance: current balance {}",
+            acc_v.0
+        ));
+      }
 
-    let mut tx = Transaction {
-        id: String::new(),
-        vin,
-        vout,
-    };
-    tx.id = tx.hash()?;
-    // utxo.blockchain
-    //     .sign_transacton(&mut tx, &wallet.secret_key)?;
-  
-    Ok(tx)
+      let mut vin = Vec::new();
+      for tx in acc_v.1 {
+          for out in tx.1 {
+              let input = TXInput {
+                  txid: tx.0.clone(),
+                  vout: out,
+                  signature: Vec::new(),
+                  pub_key: wallet.public_key.clone(),
+              };
+              vin.push(input);
+          }
+      }
+
+      let mut vout = vec![TXOutput::new(
+        amount,
+        to.to_string())?];
+
+      if acc_v.0 > needed {
+        vout.push(TXOutput::new(
+          acc_v.0 - needed,
+          wallet.get_address())?)
+      }
+
+      let mut tx = Transaction {
+          id: String::new(),
+          vin,
+          vout,
+      };
+
+      if let Fee::PerByte(rate) = fee {
+        let required = i32::try_from(tx.signed_size()?)
+          .ok()
+          .and_then(|size| rate.checked_mul(size))
+          .ok_or_else(|| format_err!("Fee for the transaction size overflows"))?;
+        if required > fee_amount {
+          fee_amount = required;
+          continue;
+        }
+      }
+
+      tx.id = tx.hash()?;
+      utxo.sign_transaction(&mut tx, &wallet.secret_key)?;
+
+      return Ok(tx);
+    }
   }
 
-  pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction> {
-    if data == String::from("") {
+  /// NewCoinbaseTX creates the reward transaction of the block at `height`
+  ///
+  /// The `reward` is the block subsidy plus the fees collected from the
+  /// block's other transactions. The height is committed at the start of the
+  /// coinbase input data so that coinbases of different blocks never share an id.
+  pub fn new_coinbase(to: String, mut data: String, height: i32, reward: i32) -> Result<Transaction> {
+    if data.is_empty() {
       data += &format!("Reward to '{}'", to);
     }
+    let mut pub_key = height.to_le_bytes().to_vec();
+    pub_key.extend_from_slice(data.as_bytes());
 
     let mut tx = Transaction {
       id: String::new(),
@@ -84,9 +194,9 @@ impl Transaction {
         txid: String::new(),
         vout: -1,
         signature: Vec::new(),
-        pub_key: Vec::from(data.as_bytes())
+        pub_key,
       }],
-      vout: vec![TXOutput::new(100, to)?],
+      vout: vec![TXOutput::new(reward, to)?],
     };
     tx.id = tx.hash()?;
     Ok(tx)
@@ -97,6 +207,72 @@ impl Transaction {
     self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
   }
 
+  /// GetPubKeyHashes returns the public key hashes the transaction spends
+  /// from or pays to, each once
+  pub fn get_pub_key_hashes(&self) -> Vec<Vec<u8>> {
+    let mut hashes: Vec<Vec<u8>> = Vec::new();
+    if !self.is_coinbase() {
+      for vin in &self.vin {
+        let mut pub_key_hash = vin.pub_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        hashes.push(pub_key_hash);
+      }
+    }
+    hashes.extend(self.vout.iter().map(|out| out.pub_key_hash.clone()));
+    hashes.sort();
+    hashes.dedup();
+    hashes
+  }
+
+  /// CoinbaseHeight returns the block height committed by a coinbase transaction
+  pub fn coinbase_height(&self) -> Option<i32> {
+    if !self.is_coinbase() || self.vin[0].pub_key.len() < 4 {
+      return None;
+    }
+    let mut height = [0u8; 4];
+    height.copy_from_slice(&self.vin[0].pub_key[..4]);
+    Some(i32::from_le_bytes(height))
+  }
+
+  /// OutputValue returns the sum of the transaction's outputs, or None when
+  /// it does not fit in an amount
+  pub fn output_value(&self) -> Option<i32> {
+    self.vout.iter().try_fold(0i32, |sum, out| sum.checked_add(out.value))
+  }
+
+  /// InputValue returns the sum of the outputs the transaction spends, or
+  /// None when it does not fit in an amount
+  pub fn input_value(&self, prev_TXs: &HashMap<String, Transaction>) -> Result<Option<i32>> {
+    let mut value = Some(0i32);
+    for vin in &self.vin {
+      let out = usize::try_from(vin.vout)
+        .ok()
+        .and_then(|index| prev_TXs.get(&vin.txid)?.vout.get(index));
+      match out {
+        Some(out) => value = value.and_then(|sum| sum.checked_add(out.value)),
+        None => return Err(format_err!("Input {}:{} is not found", vin.txid, vin.vout)),
+      }
+    }
+    Ok(value)
+  }
+
+  /// Fee returns the value of the inputs less the value of the outputs, or
+  /// None when either sum or their difference does not fit in an amount
+  pub fn fee(&self, prev_txs: &HashMap<String, Transaction>) -> Result<Option<i32>> {
+    let inputs = self.input_value(prev_txs)?;
+    Ok(inputs.zip(self.output_value()).and_then(|(inputs, outputs)| inputs.checked_sub(outputs)))
+  }
+
+  /// SignedSize estimates the serialized size of the transaction once signed
+  pub fn signed_size(&self) -> Result<usize> {
+    let mut copy = self.clone();
+    copy.id = "0".repeat(64);
+    for vin in &mut copy.vin {
+      vin.signature = vec![0; 64];
+    }
+    Ok(serialize(&copy)?.len())
+  }
+
   /// Verify verifies signatures of Transaction inputs
   pub fn verify(&self, prev_TXs: HashMap<String, Transaction>) -> Result<bool> {
       if self.is_coinbase() {
@@ -113,6 +289,10 @@ impl Transaction {
 
       for in_id in 0..self.vin.len() {
           let prev_Tx = prev_TXs.get(&self.vin[in_id].txid).unwrap();
+          let prev_out = &prev_Tx.vout[self.vin[in_id].vout as usize];
+          if !self.vin[in_id].can_unlock_output_with(&prev_out.pub_key_hash) {
+              return Ok(false);
+          }
           tx_copy.vin[in_id].signature.clear();
           tx_copy.vin[in_id].pub_key = prev_Tx.vout[self.vin[in_id].vout as usize]
               .pub_key_hash
@@ -120,6 +300,9 @@ impl Transaction {
           tx_copy.id = tx_copy.hash()?;
           tx_copy.vin[in_id].pub_key = Vec::new();
 
+          if self.vin[in_id].pub_key.len() != 32 || self.vin[in_id].signature.len() != 64 {
+              return Ok(false);
+          }
           if !ed25519::verify(
               &tx_copy.id.as_bytes(),
               &self.vin[in_id].pub_key,
@@ -159,6 +342,7 @@ impl Transaction {
         let signature = ed25519::signature(tx_copy.id.as_bytes(), private_key);
         self.vin[in_id].signature = signature.to_vec();
     }
+    self.id = self.hash()?;
 
     Ok(())
   }
@@ -211,4 +395,34 @@ pub fn hash_pub_key(pubKey: &mut Vec<u8>) {
   hasher2.input(pubKey);
   pubKey.resize(20, 0);
   hasher2.result(pubKey);
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_subsidy_schedule() {
+    let schedule = SubsidySchedule {
+      initial: 50,
+      halving_interval: 10,
+    };
+    assert_eq!(schedule.subsidy(0), 50);
+    assert_eq!(schedule.subsidy(9), 50);
+    assert_eq!(schedule.subsidy(10), 25);
+    assert_eq!(schedule.subsidy(25), 12);
+    assert_eq!(schedule.supply_at(0), 50);
+    assert_eq!(schedule.supply_at(10), 525);
+    // 50 + 25 + 12 + 6 + 3 + 1 per block for ten blocks each
+    assert_eq!(schedule.max_supply(), 970);
+    assert_eq!(schedule.subsidy(60), 0);
+
+    let schedule = SubsidySchedule {
+      initial: 2,
+      halving_interval: i32::MAX,
+    };
+    // the second era starts at the last representable height
+    assert_eq!(schedule.supply_at(i32::MAX), 2 * i32::MAX as i64 + 1);
+    assert_eq!(schedule.max_supply(), 2 * i32::MAX as i64 + 1);
+  }
+}
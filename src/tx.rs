@@ -1,6 +1,5 @@
-use std::collections::HashMap;
-
 use bitcoincash_addr::Address;
+use failure::format_err;
 use log::debug;
 use serde::{Deserialize, Serialize};
 
@@ -18,16 +17,48 @@ pub struct TXInput {
 }
 
 /// TXInput represents a transaction output
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct TXOutput {
   pub value: i32,
   pub pub_key_hash: Vec<u8>,
 }
 
-// TXOutputs collects TXOutput
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct TXOutputs {
-    pub outputs: Vec<TXOutput>,
+/// OutPoint identifies a single output of a transaction
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+  pub txid: String,
+  pub vout: i32,
+}
+
+impl OutPoint {
+  pub fn new(txid: &str, vout: i32) -> Self {
+    OutPoint {
+      txid: txid.to_string(),
+      vout,
+    }
+  }
+
+  /// ToKey encodes the outpoint as the txid followed by the big-endian vout, so
+  /// all outputs of a transaction share a key prefix and sort by index
+  pub fn to_key(&self) -> Vec<u8> {
+    let mut key = self.txid.as_bytes().to_vec();
+    key.extend_from_slice(&self.vout.to_be_bytes());
+    key
+  }
+
+  /// FromKey decodes a key produced by to_key
+  pub fn from_key(key: &[u8]) -> Result<Self> {
+    if key.len() < 4 {
+      return Err(format_err!("Invalid outpoint key of length {}", key.len()));
+    }
+    let (txid, vout) = key.split_at(key.len() - 4);
+    let mut idx = [0u8; 4];
+    idx.copy_from_slice(vout);
+    Ok(OutPoint {
+      txid: String::from_utf8(txid.to_vec())?,
+      vout: i32::from_be_bytes(idx),
+    })
+  }
 }
 
 impl TXInput {
@@ -67,4 +98,18 @@ impl TXOutput {
       txo.lock(&address)?;
       Ok(txo)
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_outpoint_key() {
+    let outpoint = OutPoint::new("ab12", 258);
+    let key = outpoint.to_key();
+    assert_eq!(&key[..4], b"ab12");
+    assert_eq!(&key[4..], &[0, 0, 1, 2]);
+    assert_eq!(OutPoint::from_key(&key).unwrap(), outpoint);
+  }
+}
@@ -1,34 +1,72 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 
 use bincode::deserialize;
 use bincode::serialize;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
 
 use crate::block::*;
 use crate::blockchain::*;
-use crate::tx::TXOutputs;
+use crate::transaction::{OutputSource, Transaction};
+use crate::tx::{OutPoint, TXOutput};
 use crate::errors::Result;
 
 ///UTXOSet represents UTXO set
+///
+/// Every unspent output is stored under its own OutPoint key, so spending one
+/// output never disturbs the others of the same transaction.
 pub struct UTXOSet {
   pub blockchain: Blockchain,
 }
 
+/// UTXOEntry is an unspent output together with where it was created
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UTXOEntry {
+    pub output: TXOutput,
+    /// height of the block that created the output
+    pub height: i32,
+    pub is_coinbase: bool,
+}
+
+/// SpentOutput is an output consumed by a block, kept so the spend can be undone
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpentOutput {
+    pub outpoint: OutPoint,
+    pub entry: UTXOEntry,
+}
+
+/// BlockUndo lists the outputs a block spent, in the order it spent them
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BlockUndo {
+    pub spent: Vec<SpentOutput>,
+}
+
 impl UTXOSet {
+    /// db_path is where the set is stored, next to the blockchain
+    fn db_path(&self) -> PathBuf {
+        self.blockchain.get_data_dir().join("utxos")
+    }
+
     /// Reindex rebuilds the UTXO set
     pub fn reindex(&self) -> Result<()> {
-      std::fs::remove_dir_all("data/utxos").ok();
-      let db = sled::open("data/utxos")?;
+      std::fs::remove_dir_all(self.db_path()).ok();
+      let db = sled::open(self.db_path())?;
 
-      let utxos = self.blockchain.find_UTXO();
+      let utxos = self.blockchain.find_UTXO()?;
 
-      for (txid, outs) in utxos {
-          db.insert(txid.as_bytes(), serialize(&outs)?)?;
+      for (outpoint, entry) in utxos {
+          db.insert(outpoint.to_key(), serialize(&entry)?)?;
       }
 
       Ok(())
     }
 
   /// FindUnspentTransactions returns a list of transactions containing unspent outputs
+  ///
+  /// The returned map holds, per transaction id, the `vout` indices of the
+  /// selected outputs. Coinbase outputs that would still be immature in the
+  /// next block are never selected.
   pub fn find_spendable_outputs(
     &self,
     pub_key_hash: &[u8],
@@ -36,96 +74,187 @@ impl UTXOSet {
   ) -> Result<(i32, HashMap<String, Vec<i32>>)> {
     let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
     let mut accumulated = 0;
+    let spend_height = self.blockchain.get_best_height()? + 1;
 
-    let db = sled::open("data/utxos")?;
-    for kv in db.iter() {
-        let (k, v) = kv?;
-        let txid = String::from_utf8(k.to_vec())?;
-        let outs: TXOutputs = deserialize(&v.to_vec())?;
-
-        for out_idx in 0..outs.outputs.len() {
-            if outs.outputs[out_idx].is_locked_with_key(pub_key_hash) && accumulated < amount {
-                accumulated += outs.outputs[out_idx].value;
-                match unspent_outputs.get_mut(&txid) {
-                    Some(v) => v.push(out_idx as i32),
-                    None => {
-                        unspent_outputs.insert(txid.clone(), vec![out_idx as i32]);
-                    }
-                }
-            }
+    for (outpoint, entry) in self.find_unspent(pub_key_hash)? {
+        if accumulated >= amount {
+            break;
+        }
+        if !self.is_spendable(&entry, spend_height) {
+            continue;
         }
+        accumulated += entry.output.value;
+        unspent_outputs
+            .entry(outpoint.txid)
+            .or_default()
+            .push(outpoint.vout);
     }
     Ok((accumulated, unspent_outputs))
   }
 
-    /// FindUTXO finds UTXO for a public key hash
-    pub fn find_UTXO(&self, pub_key_hash: &[u8]) -> Result<TXOutputs> {
-        let mut utxos = TXOutputs {
-            outputs: Vec::new(),
-        };
-        let db = sled::open("data/utxos")?;
+    /// FindUnspent returns every unspent output locked to a public key hash
+    pub fn find_unspent(&self, pub_key_hash: &[u8]) -> Result<Vec<(OutPoint, UTXOEntry)>> {
+        let mut unspent = Vec::new();
+        let db = sled::open(self.db_path())?;
 
         for kv in db.iter() {
-            let (_, v) = kv?;
-            let outs: TXOutputs = deserialize(&v.to_vec())?;
+            let (k, v) = kv?;
+            let entry: UTXOEntry = deserialize(&v)?;
+            if entry.output.is_locked_with_key(pub_key_hash) {
+                unspent.push((OutPoint::from_key(&k)?, entry));
+            }
+        }
 
-            for out in outs.outputs {
-                if out.is_locked_with_key(pub_key_hash) {
-                    utxos.outputs.push(out.clone())
-                }
+        Ok(unspent)
+    }
+
+    /// GetBalance returns the mature and the immature value locked to a public key hash
+    ///
+    /// Immature value is held by coinbase outputs that cannot be spent in the
+    /// next block yet.
+    pub fn get_balance(&self, pub_key_hash: &[u8]) -> Result<(i32, i32)> {
+        let spend_height = self.blockchain.get_best_height()? + 1;
+        let mut mature = 0;
+        let mut immature = 0;
+        for (_, entry) in self.find_unspent(pub_key_hash)? {
+            if self.is_spendable(&entry, spend_height) {
+                mature += entry.output.value;
+            } else {
+                immature += entry.output.value;
             }
         }
+        Ok((mature, immature))
+    }
+
+    /// IsSpendable reports whether an output may be spent in a block at `spend_height`
+    pub fn is_spendable(&self, entry: &UTXOEntry, spend_height: i32) -> bool {
+        !entry.is_coinbase || self.blockchain.is_mature(entry.height, spend_height)
+    }
+
+    /// GetEntry looks up a single unspent output
+    pub fn get_entry(&self, outpoint: &OutPoint) -> Result<Option<UTXOEntry>> {
+        let db = sled::open(self.db_path())?;
+        match db.get(outpoint.to_key())? {
+            Some(data) => Ok(Some(deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
 
-        Ok(utxos)
+    /// TxFee returns the fee paid by a transaction whose inputs are all unspent
+    pub fn tx_fee(&self, tx: &Transaction) -> Result<i32> {
+        if tx.is_coinbase() {
+            return Ok(0);
+        }
+        let overflow = || format_err!("Amounts of transaction {} overflow", tx.id);
+        let mut input_value: i32 = 0;
+        for vin in &tx.vin {
+            match self.get_entry(&OutPoint::new(&vin.txid, vin.vout))? {
+                Some(entry) => input_value = input_value.checked_add(entry.output.value).ok_or_else(overflow)?,
+                None => {
+                    return Err(format_err!(
+                        "Output {}:{} is not in the UTXO set",
+                        vin.txid,
+                        vin.vout
+                    ))
+                }
+            }
+        }
+        tx.output_value()
+            .and_then(|outputs| input_value.checked_sub(outputs))
+            .ok_or_else(overflow)
     }
 
     /// Update updates the UTXO set with transactions from the Block
     ///
-    /// The Block is considered to be the tip of a blockchain
+    /// The Block is considered to be the tip of a blockchain. The outputs it
+    /// spends are saved as the block's undo record so rollback can restore them.
     pub fn update(&self, block: &Block) -> Result<()> {
-        let db = sled::open("data/utxos")?;
+        let db = sled::open(self.db_path())?;
+        let mut undo = BlockUndo::default();
 
         for tx in block.get_transaction() {
             if !tx.is_coinbase() {
                 for vin in &tx.vin {
-                    let mut update_outputs = TXOutputs {
-                        outputs: Vec::new(),
-                    };
-                    let outs: TXOutputs = deserialize(&db.get(&vin.txid)?.unwrap().to_vec())?;
-                    for out_idx in 0..outs.outputs.len() {
-                        if out_idx != vin.vout as usize {
-                            update_outputs.outputs.push(outs.outputs[out_idx].clone());
+                    let outpoint = OutPoint::new(&vin.txid, vin.vout);
+                    let entry: UTXOEntry = match db.remove(outpoint.to_key())? {
+                        Some(data) => deserialize(&data)?,
+                        None => {
+                            return Err(format_err!(
+                                "Output {}:{} is not in the UTXO set",
+                                vin.txid,
+                                vin.vout
+                            ))
                         }
-                    }
-
-                    if update_outputs.outputs.is_empty() {
-                        db.remove(&vin.txid)?;
-                    } else {
-                        db.insert(vin.txid.as_bytes(), serialize(&update_outputs)?)?;
-                    }
+                    };
+                    undo.spent.push(SpentOutput { outpoint, entry });
                 }
             }
 
-            let mut new_outputs = TXOutputs {
-                outputs: Vec::new(),
-            };
-            for out in &tx.vout {
-                new_outputs.outputs.push(out.clone());
+            for (vout, out) in tx.vout.iter().enumerate() {
+                let entry = UTXOEntry {
+                    output: out.clone(),
+                    height: block.get_height(),
+                    is_coinbase: tx.is_coinbase(),
+                };
+                db.insert(
+                    OutPoint::new(&tx.id, vout as i32).to_key(),
+                    serialize(&entry)?,
+                )?;
             }
+        }
+        self.blockchain.save_undo(&block.get_hash(), &undo)?;
+        Ok(())
+    }
 
-            db.insert(tx.id.as_bytes(), serialize(&new_outputs)?)?;
+    /// Rollback reverts Update for a Block that is being disconnected
+    ///
+    /// The Block must be the one whose Update was applied last; its outputs are
+    /// removed and the outputs it spent are restored from its undo record.
+    pub fn rollback(&self, block: &Block) -> Result<()> {
+        let db = sled::open(self.db_path())?;
+        let mut undo = self.blockchain.get_undo(&block.get_hash())?;
+
+        for tx in block.get_transaction().iter().rev() {
+            for vout in 0..tx.vout.len() {
+                db.remove(OutPoint::new(&tx.id, vout as i32).to_key())?;
+            }
+            if tx.is_coinbase() {
+                continue;
+            }
+            for vin in tx.vin.iter().rev() {
+                let spent = match undo.spent.pop() {
+                    Some(spent) if spent.outpoint == OutPoint::new(&vin.txid, vin.vout) => spent,
+                    _ => {
+                        return Err(format_err!(
+                            "Undo data of block {} does not match its inputs",
+                            block.get_hash()
+                        ))
+                    }
+                };
+                db.insert(spent.outpoint.to_key(), serialize(&spent.entry)?)?;
+            }
         }
         Ok(())
     }
 
   /// CountTransactions returns the number of transactions in the UTXO set
   pub fn count_transactions(&self) -> Result<i32> {
-      let mut counter = 0;
-      let db = sled::open("data/utxos")?;
+      let mut txids = HashSet::new();
+      let db = sled::open(self.db_path())?;
       for kv in db.iter() {
-          kv?;
-          counter += 1;
+          let (k, _) = kv?;
+          txids.insert(OutPoint::from_key(&k)?.txid);
       }
-      Ok(counter)
+      Ok(txids.len() as i32)
   }
-}
\ No newline at end of file
+}
+
+impl OutputSource for UTXOSet {
+    fn find_spendable_outputs(&self, pub_key_hash: &[u8], amount: i32) -> Result<(i32, HashMap<String, Vec<i32>>)> {
+        UTXOSet::find_spendable_outputs(self, pub_key_hash, amount)
+    }
+
+    fn sign_transaction(&self, tx: &mut Transaction, private_key: &[u8]) -> Result<()> {
+        self.blockchain.sign_transacton(tx, private_key)
+    }
+}
@@ -0,0 +1,590 @@
+//! consensus validation of blocks
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::SystemTime;
+
+use crate::block::{hash_meets_target, Block, BlockHeader};
+use crate::blockchain::Blockchain;
+use crate::errors::Result;
+use crate::transaction::Transaction;
+
+/// Number of previous blocks whose median timestamp a new block must exceed
+pub const MEDIAN_TIME_SPAN: usize = 11;
+/// How far ahead of the local clock a block timestamp may be, in milliseconds
+pub const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
+
+/// BlockError is the reason a block was rejected by consensus validation
+#[derive(Debug, PartialEq)]
+pub enum BlockError {
+    HashMismatch { expected: String, found: String },
+    InsufficientProofOfWork { hash: String, bits: u32 },
+    BadTarget { expected: u32, found: u32 },
+    ForeignGenesis,
+    UnknownParent(String),
+    BadHeight { expected: i32, found: i32 },
+    TimestampTooOld { timestamp: u128, median: u128 },
+    TimestampTooNew(u128),
+    NoTransactions,
+    /// the transactions do not hash to the header's merkle root
+    MerkleRootMismatch(String),
+    BadCoinbase(String),
+    BadTransactionId(String),
+    NegativeOutput(String),
+    InvalidSignature(String),
+    OutputsExceedInputs(String),
+    MissingInput { txid: String, vout: i32 },
+    DoubleSpend { txid: String, vout: i32 },
+    ImmatureCoinbase { txid: String, depth: i32 },
+    /// a sum of amounts in the transaction or block does not fit in an amount
+    ValueOverflow(String),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::HashMismatch { expected, found } => write!(
+                f,
+                "block hash {} does not match its header, expected {}",
+                found, expected
+            ),
+            BlockError::InsufficientProofOfWork { hash, bits } => write!(
+                f,
+                "block hash {} does not satisfy target {:#010x}",
+                hash, bits
+            ),
+            BlockError::BadTarget { expected, found } => write!(
+                f,
+                "block target {:#010x} differs from required {:#010x}",
+                found, expected
+            ),
+            BlockError::ForeignGenesis => write!(f, "block is a genesis block of another chain"),
+            BlockError::UnknownParent(hash) => write!(f, "parent block {} is unknown", hash),
+            BlockError::BadHeight { expected, found } => write!(
+                f,
+                "block height {} does not follow its parent, expected {}",
+                found, expected
+            ),
+            BlockError::TimestampTooOld { timestamp, median } => write!(
+                f,
+                "block timestamp {} is not after the median time {}",
+                timestamp, median
+            ),
+            BlockError::TimestampTooNew(timestamp) => {
+                write!(f, "block timestamp {} is too far in the future", timestamp)
+            }
+            BlockError::NoTransactions => write!(f, "block has no transactions"),
+            BlockError::MerkleRootMismatch(hash) => {
+                write!(f, "transactions of block {} do not match its merkle root", hash)
+            }
+            BlockError::BadCoinbase(reason) => write!(f, "bad coinbase: {}", reason),
+            BlockError::BadTransactionId(txid) => {
+                write!(f, "transaction id {} does not match its contents", txid)
+            }
+            BlockError::NegativeOutput(txid) => {
+                write!(f, "transaction {} has a negative output", txid)
+            }
+            BlockError::OutputsExceedInputs(txid) => {
+                write!(f, "transaction {} spends more than its inputs", txid)
+            }
+            BlockError::InvalidSignature(txid) => {
+                write!(f, "transaction {} has an invalid signature", txid)
+            }
+            BlockError::MissingInput { txid, vout } => {
+                write!(f, "output {}:{} does not exist", txid, vout)
+            }
+            BlockError::DoubleSpend { txid, vout } => {
+                write!(f, "output {}:{} is already spent", txid, vout)
+            }
+            BlockError::ImmatureCoinbase { txid, depth } => write!(
+                f,
+                "coinbase {} is spent at depth {}, before it matured",
+                txid, depth
+            ),
+            BlockError::ValueOverflow(hash) => write!(f, "amounts of {} overflow", hash),
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+impl BlockError {
+    /// proves_invalid_header reports whether the error shows that the block
+    /// the header commits to breaks the consensus rules
+    ///
+    /// Only these errors may mark the block hash invalid for good. Once the
+    /// transactions match the merkle root, the body is the one the header
+    /// commits to, so any later failure condemns the header. A body that
+    /// does not match its header says nothing about the header, and neither
+    /// do transaction ids, which the merkle root does not cover. A missing
+    /// parent may still arrive, and a timestamp too far ahead may become
+    /// acceptable later.
+    pub fn proves_invalid_header(&self) -> bool {
+        !matches!(
+            self,
+            BlockError::HashMismatch { .. }
+                | BlockError::UnknownParent(_)
+                | BlockError::TimestampTooNew(_)
+                | BlockError::NoTransactions
+                | BlockError::MerkleRootMismatch(_)
+                | BlockError::BadTransactionId(_)
+        )
+    }
+}
+
+/// ValidateBlock runs every consensus check on a block that is about to be stored
+///
+/// Inputs are resolved against the block's own ancestry, so blocks on side
+/// branches are checked against the state they would build on. Consensus
+/// failures are returned as a BlockError, which callers can recover with
+/// `downcast`.
+pub fn validate_block(bc: &Blockchain, block: &Block) -> Result<()> {
+    check_header(bc, block.get_header())?;
+    if !bc.has_block(&block.get_prev_hash())? {
+        return Err(BlockError::UnknownParent(block.get_prev_hash()).into());
+    }
+    check_transactions(block)?;
+    check_spends(bc, block)?;
+    Ok(())
+}
+
+/// CheckHeader validates the proof of work and the header's place in the chain
+///
+/// The parent only needs to be known as a header, so a header chain can be
+/// checked before the blocks it describes are downloaded.
+pub fn check_header(bc: &Blockchain, header: &BlockHeader) -> Result<()> {
+    let hash = header.calculate_hash()?;
+    if hash != header.hash {
+        return Err(BlockError::HashMismatch {
+            expected: hash,
+            found: header.hash.clone(),
+        }
+        .into());
+    }
+    if !hash_meets_target(&hash, header.bits)? {
+        return Err(BlockError::InsufficientProofOfWork {
+            hash,
+            bits: header.bits,
+        }
+        .into());
+    }
+
+    if header.prev_block_hash.is_empty() {
+        return Err(BlockError::ForeignGenesis.into());
+    }
+    let parent = match bc.get_header(&header.prev_block_hash) {
+        Ok(parent) => parent,
+        Err(_) => return Err(BlockError::UnknownParent(header.prev_block_hash.clone()).into()),
+    };
+    if header.height != parent.height + 1 {
+        return Err(BlockError::BadHeight {
+            expected: parent.height + 1,
+            found: header.height,
+        }
+        .into());
+    }
+    let expected_bits = bc.get_next_bits(&parent)?;
+    if header.bits != expected_bits {
+        return Err(BlockError::BadTarget {
+            expected: expected_bits,
+            found: header.bits,
+        }
+        .into());
+    }
+
+    let median = median_time_past(bc, &parent)?;
+    if header.timestamp <= median {
+        return Err(BlockError::TimestampTooOld {
+            timestamp: header.timestamp,
+            median,
+        }
+        .into());
+    }
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_millis();
+    if header.timestamp > now + MAX_FUTURE_BLOCK_TIME {
+        return Err(BlockError::TimestampTooNew(header.timestamp).into());
+    }
+    Ok(())
+}
+
+/// CheckTransactions validates the transaction list without looking at the chain
+pub fn check_transactions(block: &Block) -> Result<()> {
+    let txs = block.get_transaction();
+    if txs.is_empty() {
+        return Err(BlockError::NoTransactions.into());
+    }
+    if block.get_header().merkle_root != block.hash_transactions()? {
+        return Err(BlockError::MerkleRootMismatch(block.get_hash()).into());
+    }
+    if !txs[0].is_coinbase() {
+        return Err(BlockError::BadCoinbase(String::from("first transaction is not a coinbase")).into());
+    }
+    if txs.iter().skip(1).any(|tx| tx.is_coinbase()) {
+        return Err(BlockError::BadCoinbase(String::from("more than one coinbase")).into());
+    }
+    if txs[0].coinbase_height() != Some(block.get_height()) {
+        return Err(BlockError::BadCoinbase(String::from("coinbase does not commit to the block height")).into());
+    }
+    for tx in txs {
+        check_transaction(tx)?;
+    }
+    Ok(())
+}
+
+/// CheckTransaction validates a transaction on its own: its id must match
+/// its contents, no output may be negative and no input may repeat another
+pub fn check_transaction(tx: &Transaction) -> Result<()> {
+    if tx.id != tx.hash()? {
+        return Err(BlockError::BadTransactionId(tx.id.clone()).into());
+    }
+    if tx.vout.iter().any(|out| out.value < 0) {
+        return Err(BlockError::NegativeOutput(tx.id.clone()).into());
+    }
+    let mut spent = HashSet::new();
+    for vin in &tx.vin {
+        if !spent.insert((&vin.txid, vin.vout)) {
+            return Err(BlockError::DoubleSpend {
+                txid: vin.txid.clone(),
+                vout: vin.vout,
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// CheckSpends makes sure every input spends an existing, unspent output and is
+/// correctly signed, that no transaction creates value, and that the coinbase
+/// claims no more than the subsidy plus the fees of the block
+///
+/// Outputs created earlier in the same block may be spent by later transactions.
+pub fn check_spends(bc: &Blockchain, block: &Block) -> Result<()> {
+    let mut wanted: HashSet<(String, i32)> = HashSet::new();
+    let mut in_block: HashMap<String, Transaction> = HashMap::new();
+    let mut from_chain: HashSet<(String, i32)> = HashSet::new();
+
+    for tx in block.get_transaction() {
+        if !tx.is_coinbase() {
+            for vin in &tx.vin {
+                let outpoint = (vin.txid.clone(), vin.vout);
+                if !wanted.insert(outpoint) {
+                    return Err(BlockError::DoubleSpend {
+                        txid: vin.txid.clone(),
+                        vout: vin.vout,
+                    }
+                    .into());
+                }
+                match in_block.get(&vin.txid) {
+                    Some(prev) if vin.vout >= 0 && (vin.vout as usize) < prev.vout.len() => {}
+                    Some(_) => {
+                        return Err(BlockError::MissingInput {
+                            txid: vin.txid.clone(),
+                            vout: vin.vout,
+                        }
+                        .into())
+                    }
+                    None => {
+                        from_chain.insert((vin.txid.clone(), vin.vout));
+                    }
+                }
+            }
+        }
+        in_block.insert(tx.id.clone(), tx.clone());
+    }
+
+    // walk the ancestry once, newest first, looking for the spent outputs
+    let mut prev_txs: HashMap<String, Transaction> = HashMap::new();
+    let mut found: HashSet<(String, i32)> = HashSet::new();
+    let mut hash = block.get_prev_hash();
+    while !hash.is_empty() && found.len() < from_chain.len() {
+        let b = bc.get_block(&hash)?;
+        for tx in b.get_transaction() {
+            if !tx.is_coinbase() {
+                for vin in &tx.vin {
+                    if from_chain.contains(&(vin.txid.clone(), vin.vout)) {
+                        return Err(BlockError::DoubleSpend {
+                            txid: vin.txid.clone(),
+                            vout: vin.vout,
+                        }
+                        .into());
+                    }
+                }
+            }
+            for vout in 0..tx.vout.len() as i32 {
+                let outpoint = (tx.id.clone(), vout);
+                if from_chain.contains(&outpoint) {
+                    found.insert(outpoint);
+                    prev_txs.insert(tx.id.clone(), tx.clone());
+                }
+            }
+        }
+        hash = b.get_prev_hash();
+    }
+    if let Some((txid, vout)) = from_chain.difference(&found).next() {
+        return Err(BlockError::MissingInput {
+            txid: txid.clone(),
+            vout: *vout,
+        }
+        .into());
+    }
+
+    prev_txs.extend(in_block);
+    let overflow = || BlockError::ValueOverflow(block.get_hash());
+    let mut fees: i32 = 0;
+    for tx in block.get_transaction().iter().skip(1) {
+        check_maturity(bc, tx, &prev_txs, block.get_height())?;
+        if !tx.verify(prev_txs.clone())? {
+            return Err(BlockError::InvalidSignature(tx.id.clone()).into());
+        }
+        let fee = tx
+            .fee(&prev_txs)?
+            .ok_or_else(|| BlockError::ValueOverflow(tx.id.clone()))?;
+        if fee < 0 {
+            return Err(BlockError::OutputsExceedInputs(tx.id.clone()).into());
+        }
+        fees = fees.checked_add(fee).ok_or_else(overflow)?;
+    }
+
+    let coinbase = &block.get_transaction()[0];
+    let reward = coinbase
+        .output_value()
+        .ok_or_else(|| BlockError::ValueOverflow(coinbase.id.clone()))?;
+    let subsidy = bc.get_block_subsidy(block.get_height());
+    if reward > subsidy.checked_add(fees).ok_or_else(overflow)? {
+        return Err(BlockError::BadCoinbase(format!(
+            "coinbase pays {}, more than the subsidy {} plus fees {}",
+            reward, subsidy, fees
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// CheckMaturity rejects a transaction that spends a coinbase which is not
+/// yet mature in a block at `spend_height`
+pub fn check_maturity(
+    bc: &Blockchain,
+    tx: &Transaction,
+    prev_txs: &HashMap<String, Transaction>,
+    spend_height: i32,
+) -> Result<()> {
+    for vin in &tx.vin {
+        let height = match prev_txs.get(&vin.txid).and_then(|prev| prev.coinbase_height()) {
+            Some(height) => height,
+            None => continue,
+        };
+        if !bc.is_mature(height, spend_height) {
+            return Err(BlockError::ImmatureCoinbase {
+                txid: vin.txid.clone(),
+                depth: spend_height - height,
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// MedianTimePast returns the median timestamp of `tip` and its ancestors
+pub fn median_time_past(bc: &Blockchain, tip: &BlockHeader) -> Result<u128> {
+    let mut times = vec![tip.timestamp];
+    let mut hash = tip.prev_block_hash.clone();
+    while times.len() < MEDIAN_TIME_SPAN && !hash.is_empty() {
+        let h = bc.get_header(&hash)?;
+        times.push(h.timestamp);
+        hash = h.prev_block_hash;
+    }
+    times.sort_unstable();
+    Ok(times[times.len() / 2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::POW_LIMIT_BITS;
+    use crate::transaction::DEFAULT_SUBSIDY;
+    use crate::tx::{TXInput, TXOutput};
+    use crate::wallets::Wallet;
+
+    fn setup() -> (Blockchain, Block, Wallet) {
+        let wallet = Wallet::new();
+        let cbtx = Transaction::new_coinbase(wallet.get_address(), String::from("genesis"), 0, DEFAULT_SUBSIDY.initial).unwrap();
+        let genesis = Block::new_block(vec![cbtx], String::new(), 0, POW_LIMIT_BITS).unwrap();
+        (Blockchain::open_temporary(&genesis).unwrap(), genesis, wallet)
+    }
+
+    fn spend(wallet: &Wallet, prev: &Transaction, to: &str) -> Transaction {
+        spend_value(wallet, prev, to, prev.vout[0].value)
+    }
+
+    fn spend_value(wallet: &Wallet, prev: &Transaction, to: &str, value: i32) -> Transaction {
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: prev.id.clone(),
+                vout: 0,
+                signature: Vec::new(),
+                pub_key: wallet.public_key.clone(),
+            }],
+            vout: vec![TXOutput::new(value, to.to_string()).unwrap()],
+        };
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(prev.id.clone(), prev.clone());
+        tx.sign(&wallet.secret_key, prev_txs).unwrap();
+        tx
+    }
+
+    fn reason(bc: &Blockchain, block: &Block) -> BlockError {
+        validate_block(bc, block).unwrap_err().downcast::<BlockError>().unwrap()
+    }
+
+    fn next_block(bc: &Blockchain, parent: &Block, txs: Vec<Transaction>, miner: &str) -> Block {
+        next_block_with_fees(bc, parent, txs, miner, 0)
+    }
+
+    fn next_block_with_fees(
+        bc: &Blockchain,
+        parent: &Block,
+        mut txs: Vec<Transaction>,
+        miner: &str,
+        fees: i32,
+    ) -> Block {
+        let height = parent.get_height() + 1;
+        let reward = bc.get_block_subsidy(height) + fees;
+        txs.insert(0, Transaction::new_coinbase(miner.to_string(), String::new(), height, reward).unwrap());
+        Block::new_block(txs, parent.get_hash(), height, bc.get_next_bits(parent.get_header()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_valid_spend() {
+        let (bc, genesis, wallet) = setup();
+        let to = Wallet::new().get_address();
+        let tx = spend(&wallet, &genesis.get_transaction()[0], &to);
+        let block = next_block(&bc, &genesis, vec![tx], &to);
+        validate_block(&bc, &block).unwrap();
+    }
+
+    #[test]
+    fn test_reject_swapped_body() {
+        let (bc, genesis, wallet) = setup();
+        let to = Wallet::new().get_address();
+        let block = next_block(&bc, &genesis, Vec::new(), &to);
+        let other = next_block(&bc, &genesis, vec![spend(&wallet, &genesis.get_transaction()[0], &to)], &to);
+        let swapped = Block::from_parts(block.get_header().clone(), other.get_transaction().clone());
+        assert_eq!(reason(&bc, &swapped), BlockError::MerkleRootMismatch(block.get_hash()));
+    }
+
+    #[test]
+    fn test_reject_bad_target() {
+        let (bc, genesis, wallet) = setup();
+        let cbtx = Transaction::new_coinbase(wallet.get_address(), String::new(), 1, DEFAULT_SUBSIDY.initial).unwrap();
+        let block = Block::new_block(vec![cbtx], genesis.get_hash(), 1, 0x1e7fff80).unwrap();
+        assert_eq!(
+            reason(&bc, &block),
+            BlockError::BadTarget {
+                expected: genesis.get_header().bits,
+                found: 0x1e7fff80
+            }
+        );
+    }
+
+    #[test]
+    fn test_reject_double_spend() {
+        let (bc, genesis, wallet) = setup();
+        let to1 = Wallet::new().get_address();
+        let to2 = Wallet::new().get_address();
+        let cb = &genesis.get_transaction()[0];
+        let txs = vec![spend(&wallet, cb, &to1), spend(&wallet, cb, &to2)];
+        let block = next_block(&bc, &genesis, txs, &to1);
+        assert_eq!(
+            reason(&bc, &block),
+            BlockError::DoubleSpend {
+                txid: cb.id.clone(),
+                vout: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_reject_bad_signature() {
+        let (bc, genesis, _) = setup();
+        let thief = Wallet::new();
+        let tx = spend(&thief, &genesis.get_transaction()[0], &thief.get_address());
+        let block = next_block(&bc, &genesis, vec![tx.clone()], &thief.get_address());
+        assert_eq!(reason(&bc, &block), BlockError::InvalidSignature(tx.id));
+    }
+
+    #[test]
+    fn test_reject_overpaying_coinbase() {
+        let (bc, genesis, wallet) = setup();
+        let reward = bc.get_block_subsidy(1) + 1;
+        let cbtx = Transaction::new_coinbase(wallet.get_address(), String::new(), 1, reward).unwrap();
+        let block = Block::new_block(vec![cbtx], genesis.get_hash(), 1, genesis.get_header().bits).unwrap();
+        assert!(matches!(reason(&bc, &block), BlockError::BadCoinbase(_)));
+    }
+
+    #[test]
+    fn test_coinbase_claims_fees() {
+        let (bc, genesis, wallet) = setup();
+        let to = Wallet::new().get_address();
+        let tx = spend_value(&wallet, &genesis.get_transaction()[0], &to, 90);
+
+        let block = next_block_with_fees(&bc, &genesis, vec![tx.clone()], &to, 10);
+        validate_block(&bc, &block).unwrap();
+
+        let block = next_block_with_fees(&bc, &genesis, vec![tx], &to, 11);
+        assert!(matches!(reason(&bc, &block), BlockError::BadCoinbase(_)));
+    }
+
+    #[test]
+    fn test_reject_value_creation() {
+        let (bc, genesis, wallet) = setup();
+        let to = Wallet::new().get_address();
+        let tx = spend_value(&wallet, &genesis.get_transaction()[0], &to, DEFAULT_SUBSIDY.initial + 1);
+        let block = next_block(&bc, &genesis, vec![tx.clone()], &to);
+        assert_eq!(reason(&bc, &block), BlockError::OutputsExceedInputs(tx.id));
+    }
+
+    #[test]
+    fn test_reject_overflowing_outputs() {
+        let (bc, genesis, wallet) = setup();
+        let to = Wallet::new().get_address();
+        let mut tx = spend(&wallet, &genesis.get_transaction()[0], &to);
+        tx.vout = vec![TXOutput::new(i32::MAX, to.clone()).unwrap(); 2];
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(genesis.get_transaction()[0].id.clone(), genesis.get_transaction()[0].clone());
+        tx.sign(&wallet.secret_key, prev_txs).unwrap();
+        let block = next_block(&bc, &genesis, vec![tx.clone()], &to);
+        assert_eq!(reason(&bc, &block), BlockError::ValueOverflow(tx.id));
+
+        // a coinbase paying more than an amount holds can't slip under the limit
+        let mut cbtx = Transaction::new_coinbase(to.clone(), String::new(), 1, i32::MAX).unwrap();
+        cbtx.vout.push(cbtx.vout[0].clone());
+        cbtx.id = cbtx.hash().unwrap();
+        let block = Block::new_block(vec![cbtx.clone()], genesis.get_hash(), 1, genesis.get_header().bits).unwrap();
+        assert_eq!(reason(&bc, &block), BlockError::ValueOverflow(cbtx.id));
+    }
+
+    #[test]
+    fn test_reject_immature_coinbase() {
+        let (mut bc, genesis, wallet) = setup();
+        bc.set_coinbase_maturity(2);
+        let to = Wallet::new().get_address();
+        let cb = &genesis.get_transaction()[0];
+
+        let block = next_block(&bc, &genesis, vec![spend(&wallet, cb, &to)], &to);
+        assert_eq!(
+            reason(&bc, &block),
+            BlockError::ImmatureCoinbase {
+                txid: cb.id.clone(),
+                depth: 1
+            }
+        );
+
+        let empty = next_block(&bc, &genesis, Vec::new(), &to);
+        bc.add_block(empty.clone()).unwrap();
+        let block = next_block(&bc, &empty, vec![spend(&wallet, cb, &to)], &to);
+        validate_block(&bc, &block).unwrap();
+    }
+}
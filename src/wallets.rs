@@ -1,6 +1,6 @@
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
-use bitcoincash_addr::{Address, HashType, Scheme};
 use crypto::ripemd160::Ripemd160;
 use crypto::{digest::Digest, sha2::Sha256};
 use crypto::ed25519;
@@ -9,6 +9,8 @@ use rand::RngCore;
 use rand::rngs::OsRng;
 use serde::{Serialize, Deserialize};
 
+use crate::config::Network;
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Wallet {
   pub secret_key: Vec<u8>,
@@ -29,16 +31,16 @@ impl Wallet {
   }
 
   pub fn get_address(&self) -> String {
+    self.get_network_address(Network::Main)
+  }
+
+  /// GetNetworkAddress returns the address of the wallet on `network`
+  pub fn get_network_address(&self, network: Network) -> String {
     let mut pub_hash = self.public_key.clone();
     hash_pub_key(&mut pub_hash);
-    let address = Address {
-      body: pub_hash,
-      scheme: Scheme::Base58,
-      hash_type: HashType::Script,
-      ..Default::default()
-    };
-    // 0 0 1 I
-    address.encode().unwrap()
+    network
+      .encode_address(pub_hash)
+      .expect("a 20 byte public key hash has an address on every network")
   }
 }
 
@@ -55,16 +57,19 @@ pub fn hash_pub_key(pubKey: &mut Vec<u8>) {
 
 pub struct Wallets {
   wallets: HashMap<String, Wallet>,
+  path: PathBuf,
 }
 
 use crate::errors::Result;
 impl Wallets {
-  pub fn new() -> Result<Wallets> {
+  /// NewWallets loads the wallets kept in `data_dir`
+  pub fn new(data_dir: &Path) -> Result<Wallets> {
     let mut wlt = Wallets {
       wallets: HashMap::<String, Wallet>::new(),
+      path: data_dir.join("wallets"),
     };
 
-    let db = sled::open("data/wallets")?;
+    let db = sled::open(&wlt.path)?;
     for item in db.into_iter() {
       let i = item?;
       let address = String::from_utf8(i.0.to_vec())?;
@@ -75,9 +80,10 @@ impl Wallets {
     Ok(wlt)
   }
 
-  pub fn create_wallet(&mut self) -> String {
+  /// CreateWallet adds a new wallet, returning its address on `network`
+  pub fn create_wallet(&mut self, network: Network) -> String {
     let wallet = Wallet::new();
-    let address = wallet.get_address();
+    let address = wallet.get_network_address(network);
     self.wallets.insert(address.clone(), wallet);
     info!("Create wallet: {}", address);
     address
@@ -96,7 +102,7 @@ impl Wallets {
   }
 
   pub fn save_all(&self) -> Result<()> {
-    let db = sled::open("data/wallets")?;
+    let db = sled::open(&self.path)?;
 
     for (address, wallet) in &self.wallets {
       let data = bincode::serialize(wallet)?;